@@ -0,0 +1,351 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, BufReader, Read},
+    path::{Component, Path},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+};
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use flate2::read::GzDecoder;
+use tokio::time::Instant;
+
+use mass_heif_convert::{
+    heif_to_formats,
+    options::{OnConflict, Options, Rule},
+    output::{check_writable, Storage},
+};
+
+use crate::{free_name, is_heif, output_extension, unique_name, Stats};
+
+/// The extensions `is_archive` accepts, longest first so `.tar.gz` isn't taken for `.gz`.
+const EXTENSIONS: &[&str] = &[".tar.gz", ".tgz", ".zip", ".tar"];
+
+/// Whether `path` is named as an archive to convert from rather than a directory.
+pub fn is_archive(path: &Utf8Path) -> bool {
+    let name = path.as_str().to_lowercase();
+    EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+/// Names the output directory of `archive` for `--per-input-subdir`, which is its file name
+/// without the archive extension.
+fn archive_name(archive: &Utf8Path) -> String {
+    let name = archive.file_name().unwrap_or("archive");
+    let lower = name.to_lowercase();
+    EXTENSIONS
+        .iter()
+        .find(|ext| lower.ends_with(*ext))
+        .map_or(name, |ext| &name[..name.len() - ext.len()])
+        .to_string()
+}
+
+/// A HEIC entry read out of an archive, waiting for a decode job.
+struct Pending {
+    /// The entry as `archive/path/in/archive`, for errors.
+    source: Utf8PathBuf,
+    dest: Utf8PathBuf,
+    data: Vec<u8>,
+}
+
+/// Converts the HEIC files in each archive input into the output directory, mirroring the paths
+/// they have in the archive, and extracts everything else as-is. Archives are read front to back
+/// once, with HEIC entries handed to `--decode-jobs` threads as they come, so tarballs don't
+/// have to be seekable. A file that fails doesn't stop the run, and shows up in the returned
+/// totals like it would in a directory run.
+pub fn run(storage: &dyn Storage, opts: &Options) -> Result<Stats> {
+    let stats = Mutex::new(Stats {
+        started: Instant::now(),
+        completed: 0,
+        converted: 0,
+        bytes: 0,
+        errors: 0,
+        differences: 0,
+        paused: false,
+        failures: Vec::new(),
+    });
+    let fail = |source: Utf8PathBuf, err: anyhow::Error| {
+        if !opts.quiet {
+            println!("{}: {:#}", source, err);
+        }
+        let mut stats = stats.lock().unwrap();
+        stats.errors += 1;
+        stats.failures.push((source, format!("{:#}", err)));
+    };
+    let done = |bytes: u64, converted: bool| {
+        let mut stats = stats.lock().unwrap();
+        stats.completed += 1;
+        stats.converted += converted as usize;
+        stats.bytes += bytes;
+    };
+
+    let mut archive_names = HashSet::new();
+    for archive in &opts.inputs {
+        let output = opts.format_output(opts.formats[0]);
+        let output = if opts.per_input_subdir {
+            output.join(unique_name(&mut archive_names, archive_name(archive)))
+        } else {
+            output
+        };
+        // only enough entries are read ahead to keep every job busy
+        let (tx, rx) = mpsc::sync_channel::<Pending>(opts.decode_jobs.get());
+        let rx = Mutex::new(rx);
+        let mut counts = Counts::default();
+        let converted = AtomicUsize::new(0);
+        thread::scope(|scope| -> Result<()> {
+            for _ in 0..opts.decode_jobs.get() {
+                scope.spawn(|| loop {
+                    let Ok(pending) = rx.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let size = pending.data.len() as u64;
+                    let progress = |_, _| {};
+                    let converted_to = heif_to_formats(
+                        &pending.source,
+                        Some(&pending.data),
+                        &pending.dest,
+                        storage,
+                        &progress,
+                        opts,
+                    );
+                    let synced = converted_to.and_then(|(_, written)| {
+                        written
+                            .iter()
+                            .filter(|_| opts.fsync)
+                            .try_for_each(|path| sync_file(path))
+                    });
+                    match synced {
+                        Ok(()) => {
+                            converted.fetch_add(1, Ordering::Relaxed);
+                            done(size, true)
+                        }
+                        Err(err) => fail(pending.source, err),
+                    }
+                });
+            }
+
+            let mut extractor = Extractor {
+                archive,
+                output: &output,
+                written: HashSet::new(),
+                counts: &mut counts,
+                tx,
+            };
+            let mut extract = |path: &Path, kind: Kind, reader: &mut dyn Read| match extractor
+                .extract(path, kind, reader, opts)
+            {
+                Ok(Some(bytes)) => done(bytes, false),
+                Ok(None) => {}
+                Err(err) => fail(archive.join(path.to_string_lossy().as_ref()), err),
+            };
+            let file =
+                File::open(archive).with_context(|| format!("failed to open '{}'", archive))?;
+            let name = archive.as_str().to_lowercase();
+            if name.ends_with(".zip") {
+                let mut zip = zip::ZipArchive::new(BufReader::new(file))
+                    .with_context(|| format!("failed to read '{}'", archive))?;
+                for i in 0..zip.len() {
+                    let mut entry = zip.by_index(i)?;
+                    let kind = if entry.is_dir() {
+                        Kind::Dir
+                    } else {
+                        Kind::File
+                    };
+                    let path = Path::new(entry.name()).to_owned();
+                    extract(&path, kind, &mut entry);
+                }
+            } else {
+                let reader: Box<dyn Read> = if name.ends_with(".tar") {
+                    Box::new(BufReader::new(file))
+                } else {
+                    Box::new(GzDecoder::new(BufReader::new(file)))
+                };
+                let mut tar = tar::Archive::new(reader);
+                for entry in tar
+                    .entries()
+                    .with_context(|| format!("failed to read '{}'", archive))?
+                {
+                    let mut entry =
+                        entry.with_context(|| format!("failed to read '{}'", archive))?;
+                    let path = entry.path()?.into_owned();
+                    let kind = match entry.header().entry_type() {
+                        kind if kind.is_dir() => Kind::Dir,
+                        kind if kind.is_file() => Kind::File,
+                        _ => Kind::Other,
+                    };
+                    extract(&path, kind, &mut entry);
+                }
+            }
+            // lets the jobs finish what's queued and stop
+            drop(extractor);
+            Ok(())
+        })?;
+
+        if !opts.quiet {
+            println!(
+                "{}: {} converted, {} extracted, {} skipped",
+                archive,
+                converted.into_inner(),
+                counts.extracted,
+                counts.skipped
+            );
+        }
+    }
+    Ok(stats.into_inner().unwrap())
+}
+
+/// What an archive entry is, as far as extracting it goes.
+#[derive(Clone, Copy)]
+enum Kind {
+    File,
+    Dir,
+    /// Links and devices, which aren't recreated.
+    Other,
+}
+
+/// What became of the entries of one archive other than HEIC files, for its summary line.
+#[derive(Default)]
+struct Counts {
+    extracted: usize,
+    skipped: usize,
+}
+
+/// Where the entries of one archive go.
+struct Extractor<'a> {
+    archive: &'a Utf8Path,
+    /// Where the archive's paths are mirrored to.
+    output: &'a Utf8Path,
+    /// Every output path so far, which later entries mustn't take.
+    written: HashSet<Utf8PathBuf>,
+    counts: &'a mut Counts,
+    tx: mpsc::SyncSender<Pending>,
+}
+
+impl Extractor<'_> {
+    /// Writes one entry into the output directory, or queues it for conversion if it's a HEIC
+    /// file. Returns the size of a file that was extracted, which is done by the time this
+    /// returns, unlike conversions.
+    fn extract(
+        &mut self,
+        path: &Path,
+        kind: Kind,
+        reader: &mut dyn Read,
+        opts: &Options,
+    ) -> Result<Option<u64>> {
+        let relative = Utf8Path::from_path(path).context("the path in the archive isn't UTF-8")?;
+        // names like `../x` or `/x` would land outside the output
+        if !path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        {
+            bail!("the path points outside the archive, so it was left out");
+        }
+        // dotfiles like `.DS_Store` are left out as they are from directories
+        let hidden = relative
+            .components()
+            .any(|component| component.as_str().starts_with('.') && component.as_str() != ".");
+        if hidden && !opts.include_hidden {
+            return Ok(None);
+        }
+        let dest = self.output.join(relative);
+        match kind {
+            // with `--images-only`, only directories with images in them are made, for those
+            Kind::Dir if opts.images_only => return Ok(None),
+            Kind::Dir => {
+                std::fs::create_dir_all(&dest)
+                    .with_context(|| format!("failed to create '{}'", dest))?;
+                return Ok(None);
+            }
+            Kind::Other => {
+                self.counts.skipped += 1;
+                return Ok(None);
+            }
+            Kind::File => {}
+        }
+
+        let convert = match opts.rule(relative) {
+            Some(Rule::Skip) => {
+                self.counts.skipped += 1;
+                return Ok(None);
+            }
+            Some(Rule::Copy) => false,
+            Some(Rule::Convert { .. }) => true,
+            None => is_heif(relative),
+        };
+        if !convert && opts.images_only {
+            self.counts.skipped += 1;
+            return Ok(None);
+        }
+        let mut dest = match relative.extension() {
+            Some(ext) if convert => dest.with_extension(output_extension(
+                ext,
+                Some(opts.formats_for(relative)[0].extension()),
+                opts.extension_case,
+            )),
+            _ => dest,
+        };
+        // also catches an archive holding the same path twice
+        if self.written.contains(&dest) || dest.symlink_metadata().is_ok() {
+            match opts.on_conflict {
+                OnConflict::Overwrite => {}
+                OnConflict::Ask if opts.yes => {}
+                OnConflict::Skip => {
+                    self.counts.skipped += 1;
+                    return Ok(None);
+                }
+                OnConflict::Rename => dest = free_name(&dest, &self.written),
+                // refused before archives are opened
+                OnConflict::Smaller => unreachable!(),
+                OnConflict::Ask => bail!(
+                    "'{}' already exists, run with --yes to overwrite it, or --on-conflict",
+                    dest
+                ),
+            }
+        }
+        self.written.insert(dest.clone());
+        if let Some(parent) = dest.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create '{}'", parent))?;
+        }
+
+        if convert {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            let source = self.archive.join(relative);
+            self.tx
+                .send(Pending { source, dest, data })
+                .context("the decode jobs stopped")?;
+            return Ok(None);
+        }
+        check_writable(&dest)?;
+        let mut file =
+            File::create(&dest).with_context(|| format!("failed to create '{}'", dest))?;
+        let bytes =
+            io::copy(reader, &mut file).with_context(|| format!("failed to write '{}'", dest))?;
+        if opts.fsync {
+            drop(file);
+            sync_file(&dest)?;
+        }
+        self.counts.extracted += 1;
+        Ok(Some(bytes))
+    }
+}
+
+/// Flushes the file at `path` and its directory entry to disk for `--fsync`, like a directory
+/// run does.
+fn sync_file(path: &Utf8Path) -> Result<()> {
+    File::open(path)
+        .and_then(|file| file.sync_all())
+        .with_context(|| format!("failed to flush '{}' to disk", path))?;
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        File::open(parent)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("failed to flush directory '{}' to disk", parent))?;
+    }
+    Ok(())
+}
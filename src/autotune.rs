@@ -0,0 +1,80 @@
+use std::{
+    io,
+    num::NonZeroUsize,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+
+use mass_heif_convert::{heif_to_png, options::Options};
+
+use crate::{Action, Group};
+
+/// Most files read into memory to benchmark with, taken from the start of the run.
+const SAMPLE_FILES: usize = 32;
+
+/// Conversions timed at each level for every job, so that each job gets a few files in.
+const FILES_PER_JOB: usize = 2;
+
+/// Fewest conversions timed at a level, so the low ones aren't over too quickly to measure.
+const MIN_FILES: usize = 8;
+
+/// Levels within this fraction of the best throughput count as just as good, so that the fewest
+/// jobs are picked rather than more that are only faster by noise.
+const KNEE: f64 = 0.95;
+
+/// Times converting a sample of the files in `groups` with 1, 2, 4 and so on decode jobs up to
+/// twice the number of cores, printing the throughput of each, and returns the fewest jobs that
+/// come within 5% of the fastest. Files are decoded from memory and encoded with the run's
+/// options, but nothing is written.
+pub fn run(groups: &[Group], opts: &Options) -> Result<NonZeroUsize> {
+    let sample = groups
+        .iter()
+        .flat_map(|group| &group.jobs)
+        .filter(|job| job.action == Action::Convert)
+        .take(SAMPLE_FILES)
+        .map(|job| {
+            std::fs::read(&job.source).with_context(|| format!("failed to read '{}'", job.source))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if sample.is_empty() {
+        bail!("there are no files to convert to benchmark with");
+    }
+
+    let cores = thread::available_parallelism().map_or(1, NonZeroUsize::get);
+    let levels = (0..)
+        .map(|n| 1 << n)
+        .take_while(|&jobs| jobs <= (cores * 2).max(2));
+    let mut results = Vec::new();
+    for jobs in levels {
+        let files = (jobs * FILES_PER_JOB).max(MIN_FILES);
+        let next = AtomicUsize::new(0);
+        let started = Instant::now();
+        thread::scope(|scope| {
+            for _ in 0..jobs {
+                scope.spawn(|| loop {
+                    let n = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(data) = (n < files).then(|| &sample[n % sample.len()]) else {
+                        break;
+                    };
+                    // a file that fails still took the time, and the run will report it
+                    heif_to_png(Utf8Path::new(""), Some(data), io::sink(), &|_, _| {}, opts).ok();
+                });
+            }
+        });
+        let rate = files as f64 / started.elapsed().as_secs_f64();
+        eprintln!("autotune: {:>2} decode jobs, {:.1} files/s", jobs, rate);
+        results.push((jobs, rate));
+    }
+
+    let best = results.iter().map(|&(_, rate)| rate).fold(0.0, f64::max);
+    let (jobs, _) = results
+        .into_iter()
+        .find(|&(_, rate)| rate >= best * KNEE)
+        .unwrap();
+    eprintln!("autotune: using --decode-jobs {}", jobs);
+    Ok(NonZeroUsize::new(jobs).unwrap())
+}
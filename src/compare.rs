@@ -0,0 +1,92 @@
+use std::{fs::File, io::BufReader};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+/// The first frame of a PNG, with palettes and low bit depths expanded to whole bytes.
+struct Pixels {
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    data: Vec<u8>,
+}
+
+fn read(path: &Utf8Path) -> Result<Pixels> {
+    let file = File::open(path).with_context(|| format!("failed to open '{}'", path))?;
+    let mut decoder = png::Decoder::new(BufReader::new(file));
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder
+        .read_info()
+        .with_context(|| format!("failed to read '{}'", path))?;
+
+    let mut data = vec![0; reader.output_buffer_size()];
+    let frame = reader
+        .next_frame(&mut data)
+        .with_context(|| format!("failed to decode '{}'", path))?;
+    data.truncate(frame.buffer_size());
+    Ok(Pixels {
+        width: frame.width,
+        height: frame.height,
+        color: frame.color_type,
+        depth: frame.bit_depth,
+        data,
+    })
+}
+
+/// Compares the PNG at `output` with the one at `reference`, describing how they differ when the
+/// size or format changed, or any channel of any pixel is more than `tolerance` apart.
+pub fn compare(output: &Utf8Path, reference: &Utf8Path, tolerance: u16) -> Result<Option<String>> {
+    if !reference.exists() {
+        return Ok(Some("missing from the reference".to_string()));
+    }
+    let (new, old) = (read(output)?, read(reference)?);
+
+    if (new.width, new.height) != (old.width, old.height) {
+        return Ok(Some(format!(
+            "is {}x{}, the reference is {}x{}",
+            new.width, new.height, old.width, old.height
+        )));
+    }
+    if (new.color, new.depth) != (old.color, old.depth) {
+        return Ok(Some(format!(
+            "is {:?} at {} bits, the reference is {:?} at {} bits",
+            new.color, new.depth as u8, old.color, old.depth as u8
+        )));
+    }
+
+    // 16-bit samples are stored big-endian
+    let samples = |data: &[u8]| -> Vec<u16> {
+        match new.depth {
+            png::BitDepth::Sixteen => data
+                .chunks_exact(2)
+                .map(|sample| u16::from_be_bytes([sample[0], sample[1]]))
+                .collect(),
+            _ => data.iter().map(|&sample| sample as u16).collect(),
+        }
+    };
+    let channels = new.color.samples();
+    let (new, old) = (samples(&new.data), samples(&old.data));
+
+    let mut max = 0;
+    let mut pixels = 0;
+    for (new, old) in new.chunks(channels).zip(old.chunks(channels)) {
+        let delta = new
+            .iter()
+            .zip(old)
+            .map(|(new, old)| new.abs_diff(*old))
+            .max()
+            .unwrap_or(0);
+        if delta > tolerance {
+            pixels += 1;
+        }
+        max = max.max(delta);
+    }
+
+    Ok((pixels > 0).then(|| {
+        format!(
+            "{} pixels differ from the reference, by up to {}",
+            pixels, max
+        )
+    }))
+}
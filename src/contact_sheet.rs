@@ -0,0 +1,106 @@
+use std::io::BufWriter;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+/// Longest side of each image on a contact sheet.
+const THUMBNAIL_SIZE: u32 = 160;
+const COLUMNS: u32 = 8;
+/// Space around each thumbnail, in pixels.
+const PADDING: u32 = 4;
+const BACKGROUND: [u8; 3] = [32, 32, 32];
+
+/// A downscaled RGB copy of a converted image.
+#[derive(Clone, Debug)]
+pub struct Thumbnail {
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+impl Thumbnail {
+    /// Shrinks an interleaved RGB image with rows `stride` bytes apart, and `channels` bytes to a
+    /// pixel of which any past the third are ignored, averaging each block of pixels that becomes
+    /// one thumbnail pixel.
+    pub fn new(data: &[u8], width: u32, height: u32, stride: usize, channels: usize) -> Self {
+        let scale = width.max(height).div_ceil(THUMBNAIL_SIZE).max(1);
+        let thumb_width = (width / scale).max(1);
+        let thumb_height = (height / scale).max(1);
+
+        let mut thumb = Vec::with_capacity((thumb_width * thumb_height * 3) as usize);
+        for ty in 0..thumb_height {
+            for tx in 0..thumb_width {
+                let mut sum = [0u32; 3];
+                let mut count = 0;
+                for y in ty * scale..((ty + 1) * scale).min(height) {
+                    let row = &data[y as usize * stride..];
+                    for x in tx * scale..((tx + 1) * scale).min(width) {
+                        let pixel = &row[x as usize * channels..x as usize * channels + 3];
+                        for (sum, &value) in sum.iter_mut().zip(pixel) {
+                            *sum += value as u32;
+                        }
+                        count += 1;
+                    }
+                }
+                thumb.extend(sum.map(|sum| (sum / count) as u8));
+            }
+        }
+
+        Self {
+            width: thumb_width,
+            height: thumb_height,
+            data: thumb,
+        }
+    }
+
+    /// The thumbnail as its width and height, as little-endian `u32`s, followed by its pixels,
+    /// for handing it from one process to another.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.data.len());
+        bytes.extend(self.width.to_le_bytes());
+        bytes.extend(self.height.to_le_bytes());
+        bytes.extend(&self.data);
+        bytes
+    }
+
+    /// Reads a thumbnail back from [`Thumbnail::to_bytes`].
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let width = u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?);
+        let height = u32::from_le_bytes(bytes.get(4..8)?.try_into().ok()?);
+        let data = bytes.get(8..)?.to_vec();
+        (data.len() == width as usize * height as usize * 3).then_some(Self {
+            width,
+            height,
+            data,
+        })
+    }
+}
+
+/// Tiles `thumbnails` into a grid, in order, and writes it to `path` as a PNG.
+pub fn write(path: &Utf8Path, thumbnails: &[Thumbnail]) -> Result<()> {
+    let cell = THUMBNAIL_SIZE + PADDING * 2;
+    let columns = COLUMNS.min(thumbnails.len() as u32);
+    let rows = (thumbnails.len() as u32).div_ceil(COLUMNS);
+    let (width, height) = (columns * cell, rows * cell);
+
+    let mut sheet = BACKGROUND.repeat((width * height) as usize);
+    for (i, thumb) in thumbnails.iter().enumerate() {
+        // centered in its cell
+        let left = (i as u32 % COLUMNS) * cell + (cell - thumb.width) / 2;
+        let top = (i as u32 / COLUMNS) * cell + (cell - thumb.height) / 2;
+        let row_size = thumb.width as usize * 3;
+        for (y, row) in thumb.data.chunks(row_size).enumerate() {
+            let start = ((top as usize + y) * width as usize + left as usize) * 3;
+            sheet[start..start + row_size].copy_from_slice(row);
+        }
+    }
+
+    let file =
+        std::fs::File::create(path).with_context(|| format!("failed to create '{}'", path))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&sheet)?;
+    writer.finish()?;
+    Ok(())
+}
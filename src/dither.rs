@@ -0,0 +1,44 @@
+/// The 4x4 Bayer matrix, whose thresholds spread each pixel's rounding error evenly over every
+/// block of 16 pixels.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Ordered dithering of 8-bit samples down to fewer levels, still spread over the full 0 to 255
+/// range so images keep their true-color layout. Unlike error diffusion, each pixel only depends
+/// on its position, so rows can be dithered one at a time as they're encoded, and the repeating
+/// pattern compresses well.
+pub struct Dither {
+    /// The dithered sample for each threshold of `BAYER` and each input sample.
+    table: Vec<[u8; 256]>,
+}
+
+impl Dither {
+    /// Makes a dither to `bits` bits per channel, from 1 to 7.
+    pub fn new(bits: u8) -> Self {
+        let levels = (1u32 << bits) - 1;
+        let table = (0..16u32)
+            .map(|threshold| {
+                let mut samples = [0; 256];
+                for (value, sample) in samples.iter_mut().enumerate() {
+                    // the level below the value plus a threshold from 1/32 to 31/32, so values
+                    // between two levels round to either in proportion to how close they are
+                    let level =
+                        (32 * value as u32 * levels + 255 * (2 * threshold + 1)) / (255 * 32);
+                    *sample = ((level * 255 + levels / 2) / levels) as u8;
+                }
+                samples
+            })
+            .collect();
+        Self { table }
+    }
+
+    /// Dithers `row`, the `y`th of an image with `channels` samples per pixel, into `reduced`.
+    /// Alpha, as a fourth channel, is left as it is.
+    pub fn row(&self, row: &[u8], y: usize, channels: usize, reduced: &mut Vec<u8>) {
+        reduced.clear();
+        for (x, pixel) in row.chunks_exact(channels).enumerate() {
+            let samples = &self.table[BAYER[y % 4][x % 4] as usize];
+            reduced.extend(pixel.iter().take(3).map(|&sample| samples[sample as usize]));
+            reduced.extend(pixel.get(3));
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use camino::Utf8Path;
+use libheif_rs::CompressionFormat;
+
+use mass_heif_convert::{options::Options, HEIF, MAX_CODECS};
+
+use crate::version;
+
+/// Checks that everything a run needs is in place and prints what was found, returning false if
+/// anything is missing.
+pub fn run(opts: &Options) -> bool {
+    print!("{}", version());
+    println!();
+
+    let mut ok = true;
+    let mut check = |what: &str, result: Result<String, String>| match result {
+        Ok(detail) => println!("ok       {}: {}", what, detail),
+        Err(detail) => {
+            println!("problem  {}: {}", what, detail);
+            ok = false;
+        }
+    };
+
+    let hevc = HEIF.decoder_descriptors(MAX_CODECS, Some(CompressionFormat::Hevc));
+    check(
+        "HEVC decoder",
+        match hevc.first() {
+            Some(decoder) => Ok(decoder.name()),
+            None => Err("none available, so HEIC files can't be decoded".to_string()),
+        },
+    );
+
+    for input in &opts.inputs {
+        check(
+            &format!("input '{}'", input),
+            match input.read_dir_utf8() {
+                Ok(_) => Ok("readable".to_string()),
+                Err(err) => Err(err.to_string()),
+            },
+        );
+    }
+
+    if !opts.output.as_str().is_empty() {
+        check(&format!("output '{}'", opts.output), writable(&opts.output));
+
+        #[cfg(unix)]
+        check(
+            "free space",
+            existing_ancestor(&opts.output)
+                .ok_or_else(|| "no existing parent directory".to_string())
+                .and_then(|dir| crate::available_space(dir).map_err(|err| format!("{:#}", err)))
+                .map(|bytes| format!("{} MB", bytes / 1_000_000)),
+        );
+    }
+
+    check(
+        "terminal",
+        if opts.quiet {
+            Ok("not needed with --quiet".to_string())
+        } else if termion::is_tty(&std::io::stdout()) {
+            match termion::terminal_size() {
+                Ok((width, height)) => Ok(format!("{}x{}", width, height)),
+                Err(err) => Err(format!("can't get the size: {}", err)),
+            }
+        } else {
+            Err("stdout isn't a terminal, use --quiet for non-interactive runs".to_string())
+        },
+    );
+
+    ok
+}
+
+/// Checks that files can be created in `dir`, or in the closest parent that exists if `dir` will
+/// be created by the run.
+fn writable(dir: &Utf8Path) -> Result<String, String> {
+    let existing =
+        existing_ancestor(dir).ok_or_else(|| "no existing parent directory".to_string())?;
+    let probe = existing.join(".mass-heif-doctor");
+    std::fs::write(&probe, b"").map_err(|err| format!("not writable: {}", err))?;
+    std::fs::remove_file(&probe).ok();
+
+    Ok(if existing == dir {
+        "writable".to_string()
+    } else {
+        format!("doesn't exist yet, '{}' is writable", existing)
+    })
+}
+
+fn existing_ancestor(path: &Utf8Path) -> Option<&Utf8Path> {
+    path.ancestors()
+        .find(|dir| dir.as_str().is_empty() || dir.is_dir())
+        .map(|dir| {
+            if dir.as_str().is_empty() {
+                Utf8Path::new(".")
+            } else {
+                dir
+            }
+        })
+}
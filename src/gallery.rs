@@ -0,0 +1,163 @@
+use std::fmt::Write;
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+/// The page written into each directory of the output with `--gallery`.
+const INDEX_FILE: &str = "index.html";
+
+/// Extensions of the files shown as images, in lowercase.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "gif"];
+
+/// How the `--sidecar-thumbnails` JPEGs end, in lowercase. They're shown in place of the images
+/// they're beside rather than next to them.
+const THUMBNAIL_SUFFIX: &str = ".thumb.jpg";
+
+/// Shared by every page, so each one stands on its own.
+const STYLE: &str = "body { font-family: sans-serif; margin: 1em; }
+ul { list-style: none; padding: 0; }
+.images { display: flex; flex-wrap: wrap; gap: 1em; }
+.images li { width: 200px; text-align: center; font-size: small; overflow-wrap: anywhere; }
+.images img { width: 200px; height: 200px; object-fit: contain; background: #eee; }";
+
+/// Writes an `index.html` into `output` and each directory below it that has images somewhere
+/// in it, showing the images as thumbnails that link to them and linking to the pages of the
+/// directories inside. Returns how many pages were written.
+pub fn write(output: &Utf8Path) -> Result<usize> {
+    let mut pages = 0;
+    write_dir(output, output, &mut pages)?;
+    Ok(pages)
+}
+
+/// Writes the page of `dir` after those of the directories in it, and returns whether there was
+/// anything to show, since its parent only links to it then.
+fn write_dir(output: &Utf8Path, dir: &Utf8Path, pages: &mut usize) -> Result<bool> {
+    let mut entries = dir
+        .read_dir_utf8()
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("failed to list '{}'", dir))?;
+    entries.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+
+    let mut dirs = Vec::new();
+    let mut images = Vec::new();
+    for entry in &entries {
+        let name = entry.file_name();
+        // the lock file and what `--on-conflict smaller` leaves behind
+        if name.starts_with('.') {
+            continue;
+        }
+        // links aren't followed, so a loop of them can't go on forever
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if write_dir(output, entry.path(), pages)? {
+                dirs.push(name);
+            }
+        } else if file_type.is_file() && is_image(name) && !is_thumbnail(name) {
+            images.push(name);
+        }
+    }
+    if dirs.is_empty() && images.is_empty() {
+        return Ok(false);
+    }
+
+    let title = match dir.strip_prefix(output) {
+        Ok(relative) if !relative.as_str().is_empty() => relative.as_str(),
+        _ => dir.file_name().unwrap_or(dir.as_str()),
+    };
+    let mut html = String::new();
+    writeln!(
+        html,
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+         <title>{0}</title>\n<style>\n{1}\n</style>\n</head>\n<body>\n<h1>{0}</h1>",
+        escape(title),
+        STYLE
+    )?;
+    if dir != output {
+        writeln!(html, "<p><a href=\"../{}\">up</a></p>", INDEX_FILE)?;
+    }
+    if !dirs.is_empty() {
+        writeln!(html, "<ul class=\"dirs\">")?;
+        for name in dirs {
+            writeln!(
+                html,
+                "<li><a href=\"{}/{}\">{}/</a></li>",
+                href(name),
+                INDEX_FILE,
+                escape(name)
+            )?;
+        }
+        writeln!(html, "</ul>")?;
+    }
+    if !images.is_empty() {
+        writeln!(html, "<ul class=\"images\">")?;
+        for name in images {
+            let thumbnail = thumbnail_of(name, &entries).unwrap_or(name);
+            writeln!(
+                html,
+                "<li><a href=\"{0}\"><img src=\"{1}\" alt=\"\" loading=\"lazy\"><br>{2}</a></li>",
+                href(name),
+                href(thumbnail),
+                escape(name)
+            )?;
+        }
+        writeln!(html, "</ul>")?;
+    }
+    writeln!(html, "</body>\n</html>")?;
+
+    let path = dir.join(INDEX_FILE);
+    std::fs::write(&path, html).with_context(|| format!("failed to write '{}'", path))?;
+    *pages += 1;
+    Ok(true)
+}
+
+fn is_image(name: &str) -> bool {
+    Utf8Path::new(name)
+        .extension()
+        .is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+fn is_thumbnail(name: &str) -> bool {
+    name.to_lowercase().ends_with(THUMBNAIL_SUFFIX)
+}
+
+/// The `--sidecar-thumbnails` JPEG beside the image `name` among `entries`, in whatever case.
+fn thumbnail_of<'a>(name: &str, entries: &'a [camino::Utf8DirEntry]) -> Option<&'a str> {
+    let stem = Utf8Path::new(name).file_stem()?;
+    let wanted = format!("{}{}", stem, THUMBNAIL_SUFFIX).to_lowercase();
+    entries
+        .iter()
+        .map(|entry| entry.file_name())
+        .find(|other| other.to_lowercase() == wanted)
+}
+
+/// Makes `text` safe to put in a page, in an element or a quoted attribute.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Percent-encodes a file name for a link relative to the page, so that names with `#`, `?` or
+/// spaces in them still lead to the file.
+fn href(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => write!(encoded, "%{:02X}", byte).unwrap(),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,116 @@
+use std::collections::HashSet;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use mass_heif_convert::{options::Options, read_heif};
+
+use crate::{find_groups, is_heif, is_hidden, modified_in_range, MISC_DIR};
+
+/// Prints a table of what each HEIC file a run would convert holds, opening the files without
+/// decoding them. Returns false if any of them couldn't be opened.
+pub fn run(opts: &Options) -> Result<bool> {
+    let mut files = Vec::new();
+    let mut visited = HashSet::new();
+    for input in &opts.inputs {
+        visited.insert(input.canonicalize()?);
+        let mut groups = Vec::new();
+        find_groups(input, opts.depth.get(), opts, &mut visited, &mut groups)?;
+        groups.sort();
+        for group in groups
+            .into_iter()
+            .filter(|dir| dir.file_name() != Some(MISC_DIR))
+        {
+            let mut sources: Vec<Utf8PathBuf> = group
+                .read_dir_utf8()?
+                .map(|entry| entry.map(|entry| entry.into_path()))
+                .collect::<std::io::Result<_>>()?;
+            sources.retain(|source| {
+                is_heif(source)
+                    && (opts.include_hidden || !is_hidden(source))
+                    && modified_in_range(source, opts)
+            });
+            sources.sort();
+            files.extend(sources);
+        }
+    }
+
+    let width = files
+        .iter()
+        .map(|file| file.as_str().chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("file".len());
+    println!(
+        "{:<width$}  {:>11}  {:>4}  {:<5}  codec",
+        "file", "size", "bits", "alpha"
+    );
+
+    let mut ok = true;
+    let mut pixels = 0;
+    for file in &files {
+        match describe(file) {
+            Ok(Described {
+                width: image_width,
+                height,
+                bits,
+                alpha,
+                codec,
+            }) => {
+                pixels += image_width as u64 * height as u64;
+                println!(
+                    "{:<width$}  {:>11}  {:>4}  {:<5}  {}",
+                    file,
+                    format!("{}x{}", image_width, height),
+                    bits,
+                    if alpha { "yes" } else { "no" },
+                    codec
+                );
+            }
+            Err(err) => {
+                println!("{:<width$}  error: {:#}", file, err);
+                ok = false;
+            }
+        }
+    }
+    println!();
+    println!(
+        "{} files, {:.1} megapixels in all",
+        files.len(),
+        pixels as f64 / 1e6
+    );
+    Ok(ok)
+}
+
+struct Described {
+    width: u32,
+    height: u32,
+    bits: u8,
+    alpha: bool,
+    codec: &'static str,
+}
+
+fn describe(file: &Utf8Path) -> Result<Described> {
+    let data = std::fs::read(file)?;
+    let ctx = read_heif(file, Some(&data))?;
+    let handle = ctx.primary_image_handle()?;
+    Ok(Described {
+        width: handle.width(),
+        height: handle.height(),
+        bits: handle.luma_bits_per_pixel(),
+        alpha: handle.has_alpha_channel(),
+        codec: codec(&data),
+    })
+}
+
+/// Names the codec of a HEIF file by the decoder configuration property its images carry.
+fn codec(data: &[u8]) -> &'static str {
+    let has_box = |name: &[u8; 4]| data.windows(4).any(|window| window == name);
+    if has_box(b"hvcC") {
+        "HEVC"
+    } else if has_box(b"av1C") {
+        "AV1"
+    } else {
+        "unknown"
+    }
+}
@@ -0,0 +1,115 @@
+use std::{env, process};
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+
+use mass_heif_convert::{
+    contact_sheet::Thumbnail, explain_grid, heif_to_formats, options::Options, output,
+};
+
+/// The hidden first argument that makes the command convert a single file for
+/// `--isolate-decodes`, as `--decode-one SOURCE DEST` followed by the run's own arguments.
+pub const DECODE_ONE: &str = "--decode-one";
+
+/// Converts one file as a child of a run with `--isolate-decodes`, and exits. Progress and the
+/// outputs written are reported to the run as lines on stdout, and a failure as stderr and a
+/// status of 1. A crash in libheif only ends this process, which the run sees as a signal.
+pub fn decode_one(args: Vec<String>) -> ! {
+    let [_, source, dest, args @ ..] = &args[..] else {
+        eprintln!("{} needs a source and a destination", DECODE_ONE);
+        process::exit(2);
+    };
+    let (source, dest) = (Utf8Path::new(source), Utf8Path::new(dest));
+    let converted = Options::parse(args.to_vec()).and_then(|opts| {
+        let storage = output::storage(&opts.output)?;
+        let progress = |done, total| println!("encoded {} {}", done, total);
+        heif_to_formats(source, None, dest, &*storage, &progress, &opts)
+            .map_err(|err| explain_grid(err, source, None))
+    });
+    match converted {
+        Ok((thumbnail, written)) => {
+            for path in written {
+                println!("wrote {}", path);
+            }
+            if let Some(thumbnail) = thumbnail {
+                let hex: String = thumbnail
+                    .to_bytes()
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                println!("thumbnail {}", hex);
+            }
+            process::exit(0);
+        }
+        Err(err) => {
+            eprintln!("{:#}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Converts `source` to `dest` in a child process running [`decode_one`] with this run's
+/// arguments, passing on its encoding progress as it comes.
+pub async fn convert(
+    source: &Utf8Path,
+    dest: &Utf8Path,
+    progress: &(dyn Fn(u64, u64) + Send + Sync),
+) -> Result<(Option<Thumbnail>, Vec<Utf8PathBuf>)> {
+    let exe = env::current_exe().context("failed to find this program to run decodes with")?;
+    let mut child = tokio::process::Command::new(exe)
+        .arg(DECODE_ONE)
+        .arg(source)
+        .arg(dest)
+        .args(env::args().skip(1))
+        .stdin(process::Stdio::null())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        // a quit doesn't wait for decodes in flight
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start a decode process")?;
+
+    let mut stderr = child.stderr.take().unwrap();
+    let errors = tokio::spawn(async move {
+        let mut text = String::new();
+        stderr.read_to_string(&mut text).await.ok();
+        text
+    });
+
+    let mut thumbnail = None;
+    let mut written = Vec::new();
+    let mut lines = BufReader::new(child.stdout.take().unwrap()).lines();
+    while let Some(line) = lines.next_line().await? {
+        match line.split_once(' ') {
+            Some(("encoded", numbers)) => {
+                if let Some((done, total)) = numbers.split_once(' ') {
+                    progress(done.parse().unwrap_or(0), total.parse().unwrap_or(0));
+                }
+            }
+            Some(("wrote", path)) => written.push(path.into()),
+            Some(("thumbnail", hex)) => {
+                let bytes: Option<Vec<u8>> = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                    .collect();
+                thumbnail = bytes.as_deref().and_then(Thumbnail::from_bytes);
+            }
+            _ => {}
+        }
+    }
+
+    let status = child.wait().await?;
+    let errors = errors.await.unwrap_or_default();
+    if status.success() {
+        return Ok((thumbnail, written));
+    }
+    #[cfg(unix)]
+    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(&status) {
+        bail!("the decoder crashed with signal {}", signal);
+    }
+    match errors.trim() {
+        "" => bail!("the decode process failed with {}", status),
+        errors => bail!("{}", errors),
+    }
+}
@@ -0,0 +1,1835 @@
+//! HEIF to PNG conversion, as done by the `mass-heif-convert` command for each file.
+//!
+//! [`convert_heif_to_png`] converts a single file with the default options, while
+//! [`heif_to_png`] and [`heif_bytes_to_png`] take the same [`Options`] as the command and write
+//! to any writer.
+
+pub mod contact_sheet;
+mod dither;
+pub mod options;
+pub mod output;
+mod quantize;
+
+use std::{
+    borrow::Cow,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use flate2::write::ZlibEncoder;
+use once_cell::sync::Lazy;
+
+use heif::{DecodingOptions, HeifContext, LibHeif};
+use libheif_rs as heif;
+use tracing::{debug, info, instrument, warn};
+
+use contact_sheet::Thumbnail;
+use dither::Dither;
+use options::{ColorChunk, Fit, Format, JpegEncoder, Options, PngFilter};
+use output::Storage;
+
+/// How long each frame of an animated PNG is shown, in seconds as a fraction, since image
+/// collections don't carry any timing.
+const FRAME_DELAY: (u16, u16) = (1, 10);
+
+pub static HEIF: Lazy<LibHeif> = Lazy::new(LibHeif::new);
+
+/// Rough memory needed per pixel while converting: the decoded RGB image, plus the YCbCr planes
+/// libheif decodes into and the PNG encoder's row buffers.
+pub const DECODE_BYTES_PER_PIXEL: u64 = 6;
+
+/// The most decoder or encoder plugins listed by `--version`.
+pub const MAX_CODECS: usize = 64;
+
+/// Most pixels a WebP can be either way.
+const WEBP_MAX_DIMENSION: u32 = 16383;
+
+/// H.273 code points of the BT.709 primaries and sRGB transfer function that make up sRGB.
+const CICP_SRGB_PRIMARIES: u8 = 1;
+const CICP_SRGB_TRANSFER: u8 = 13;
+
+/// The H.273 code point of the Display P3 primaries that iPhones shoot in.
+const CICP_P3_PRIMARIES: u8 = 12;
+
+/// Extension of the `--sidecar-thumbnails` JPEGs, which goes in place of the output's own.
+const SIDECAR_EXTENSION: &str = "thumb.jpg";
+
+/// Display P3 to sRGB, for linear light, with both white points at D65.
+const P3_TO_SRGB: [[f32; 3]; 3] = [
+    [1.224_940_1, -0.224_940_4, 0.0],
+    [-0.042_056_9, 1.042_057_1, 0.0],
+    [-0.019_637_6, -0.078_636_1, 1.098_273_5],
+];
+
+/// Encoding progress is reported each time about this many more bytes of pixels are encoded.
+const PROGRESS_BYTES: usize = 4 * 1024 * 1024;
+
+/// Rows sampled from each image by `--adaptive-png`, spread evenly from top to bottom.
+const ADAPTIVE_SAMPLE_ROWS: usize = 64;
+
+/// With `--adaptive-png`, images where more than one pixel in this many repeats the one to its
+/// left are taken for flat content. Sensor noise makes that rare in photos even in smooth skies.
+const FLAT_REPEATS_DIVISOR: usize = 3;
+
+/// Converts the HEIF file at `input` to a PNG at `output` with the default options.
+pub fn convert_heif_to_png(input: &Path, output: &Path) -> Result<()> {
+    let data =
+        std::fs::read(input).with_context(|| format!("failed to read '{}'", input.display()))?;
+    let file =
+        File::create(output).with_context(|| format!("failed to create '{}'", output.display()))?;
+    heif_bytes_to_png(&data, file, &Options::default())?;
+    Ok(())
+}
+
+/// Converts a HEIF file that's already in memory to a PNG, like [`heif_to_png`].
+pub fn heif_bytes_to_png<W: Write>(
+    data: &[u8],
+    writer: W,
+    opts: &Options,
+) -> Result<Option<Thumbnail>> {
+    // the path is only read from when there's no data
+    let source = Utf8Path::new("");
+    heif_to_png(source, Some(data), writer, &|_, _| {}, opts)
+        .map_err(|err| explain_grid(err, source, Some(data)))
+}
+
+/// Converts `source` to a PNG, decoding `data` instead of reading the file if it's already been
+/// loaded. Returns a thumbnail of the image with `--contact-sheet`.
+///
+/// `progress` is called with the bytes of pixels encoded so far and in all as encoding goes,
+/// except for animated PNGs.
+#[instrument(skip_all)]
+pub fn heif_to_png<W: Write>(
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+    writer: W,
+    progress: &dyn Fn(u64, u64),
+    opts: &Options,
+) -> Result<Option<Thumbnail>> {
+    let ctx = read_heif(source, data)?;
+    if opts.animated && ctx.number_of_top_level_images() > 1 {
+        return encode_apng(&ctx.top_level_image_handles(), source, data, writer, opts);
+    }
+
+    let handle = ctx.primary_image_handle()?;
+    let exif = png_exif(&handle, source, data, opts.exif_orient, opts)?;
+    let image = decode_primary(&ctx, source, data, opts)?;
+    drop((handle, ctx));
+    encode_png(&image, exif.as_deref(), source, writer, progress, opts)
+}
+
+/// Converts `source` to each of `--format` from a single decode, writing the first format to
+/// `dest` and the others beside it under their own extensions, in the same case, or at the same
+/// place in their own trees with `--format-subdirs`. With
+/// `--png-max-size`, a PNG that would be bigger is written as a JPEG instead, unless there's one
+/// already, and `--sidecar-thumbnails` adds a small JPEG beside the first. A quality from
+/// `source`'s rule in `--rules` stands in for `--jpeg-quality` and `--webp-quality`. Returns
+/// the thumbnail along with the paths that were written.
+///
+/// Animated PNGs are written whatever their size, while the other formats only get the primary
+/// image, and progress is only reported for PNGs.
+///
+/// The decoded primary image is dropped as soon as the last format that needs it is written,
+/// rather than being held through the rest of the formats and the sidecar.
+pub fn heif_to_formats(
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+    dest: &Utf8Path,
+    storage: &dyn Storage,
+    progress: &dyn Fn(u64, u64),
+    opts: &Options,
+) -> Result<(Option<Thumbnail>, Vec<Utf8PathBuf>)> {
+    let opts = &*opts.for_source(source);
+    let read_only;
+    let (ctx, data) = match read_heif(source, data) {
+        Err(err) if data.is_none() && libheif_cant_open(&err) => {
+            debug!("retrying with a read-only open: {:#}", err);
+            read_only = read_source(source)?;
+            (read_heif(source, Some(&read_only))?, Some(&read_only[..]))
+        }
+        ctx => (ctx?, data),
+    };
+    if opts.strict {
+        check_strict(&ctx.primary_image_handle()?, opts)?;
+    }
+    let animated = opts.animated && ctx.number_of_top_level_images() > 1;
+    // decoded when the first format needing it comes up, and only once
+    let mut image = None;
+    let mut thumbnail = None;
+    let mut written = Vec::new();
+
+    let formats = opts.formats_for(source);
+    let exif = if formats.contains(&Format::Png) && !animated {
+        png_exif(
+            &ctx.primary_image_handle()?,
+            source,
+            data,
+            opts.exif_orient,
+            opts,
+        )?
+    } else {
+        None
+    };
+    // animations are encoded frame by frame, without the primary image
+    let decoded = |format: Format| !(animated && matches!(format, Format::Png | Format::Webp));
+    // scaled from the primary image as soon as it's decoded, so it doesn't have to be kept for it
+    let mut sidecar = None;
+    for (n, &format) in formats.iter().enumerate() {
+        // the decoded pixels go as soon as no format still to come needs them
+        if !formats[n..].iter().any(|&format| decoded(format)) {
+            image = None;
+        }
+        let path = if n == 0 {
+            dest.to_owned()
+        } else {
+            format_path(dest, format, opts)?
+        };
+        if format == Format::Png && animated {
+            let handles = ctx.top_level_image_handles();
+            thumbnail = encode_apng(&handles, source, data, storage.create(&path)?, opts)?;
+            written.push(path);
+            continue;
+        }
+        if format == Format::Webp && animated {
+            let handles = ctx.top_level_image_handles();
+            let (frame, webp) = encode_animated_webp(&handles, opts)?;
+            if thumbnail.is_none() {
+                thumbnail = frame;
+            }
+            storage.create(&path)?.write_all(&webp)?;
+            written.push(path);
+            continue;
+        }
+
+        if image.is_none() {
+            let primary = decode_primary(&ctx, source, data, opts)?;
+            if let Some(size) = opts.sidecar_thumbnails {
+                sidecar = Some(sidecar_thumbnail(&primary, size.get(), opts)?);
+            }
+            image = Some(primary);
+        }
+        let image = image.as_ref().unwrap();
+        match format {
+            Format::Png => {
+                let Some(max_size) = opts.png_max_size else {
+                    let writer = storage.create(&path)?;
+                    thumbnail = encode_png(image, exif.as_deref(), source, writer, progress, opts)?;
+                    written.push(path);
+                    continue;
+                };
+                let mut png = Vec::new();
+                thumbnail = encode_png(image, exif.as_deref(), source, &mut png, progress, opts)?;
+                if png.len() as u64 <= max_size {
+                    storage.create(&path)?.write_all(&png)?;
+                    written.push(path);
+                    continue;
+                }
+                debug!("PNG would be {} bytes, writing a JPEG instead", png.len());
+                drop(png);
+                if !formats.contains(&Format::Jpeg) {
+                    let path = beside(&path, Format::Jpeg.extension());
+                    encode_jpeg(image, storage.create(&path)?, opts)?;
+                    written.push(path);
+                }
+            }
+            Format::Jpeg => {
+                if thumbnail.is_none() {
+                    thumbnail = make_thumbnail(image, opts);
+                }
+                encode_jpeg(image, storage.create(&path)?, opts)?;
+                written.push(path);
+            }
+            Format::Webp => {
+                if thumbnail.is_none() {
+                    thumbnail = make_thumbnail(image, opts);
+                }
+                storage
+                    .create(&path)?
+                    .write_all(&encode_webp(image, opts)?)?;
+                written.push(path);
+            }
+        }
+    }
+
+    drop(image);
+
+    if let Some(size) = opts.sidecar_thumbnails {
+        let sidecar = match sidecar {
+            Some(sidecar) => sidecar,
+            // animations don't decode the primary image for themselves
+            None => {
+                sidecar_thumbnail(&decode_primary(&ctx, source, data, opts)?, size.get(), opts)?
+            }
+        };
+        let path = beside(dest, SIDECAR_EXTENSION);
+        encode_jpeg(&sidecar, storage.create(&path)?, opts)?;
+        written.push(path);
+    }
+    Ok((thumbnail, written))
+}
+
+/// Scales `image` down to fit in `size` pixels either way for `--sidecar-thumbnails`, and brings
+/// Display P3 colors into sRGB, which is what a JPEG without a profile is taken to be in. Other
+/// color spaces are left as they were decoded, or fail the file with `--strict`.
+fn sidecar_thumbnail(image: &heif::Image, size: u32, opts: &Options) -> Result<heif::Image> {
+    let (width, height) = fit_within(image.width(), image.height(), size);
+    let mut thumbnail = image.scale(width, height, None)?;
+
+    match cicp(image.color_profile_nclx()) {
+        None => {}
+        Some([CICP_SRGB_PRIMARIES, ..]) => {}
+        Some([CICP_P3_PRIMARIES, CICP_SRGB_TRANSFER, ..]) => {
+            debug!("converting the thumbnail from Display P3 to sRGB");
+            p3_to_srgb(&mut thumbnail);
+        }
+        Some([primaries, transfer, ..]) => {
+            let problem = format!(
+                "the thumbnail can't be brought into sRGB from primaries {} and transfer {}",
+                primaries, transfer
+            );
+            if opts.strict {
+                bail!("{} (--strict)", problem);
+            }
+            debug!("{}, so it's left as it is", problem);
+        }
+    }
+    Ok(thumbnail)
+}
+
+/// Converts the colors of an 8-bit RGB or RGBA image from Display P3 to sRGB in place, clipping
+/// those outside sRGB. Both share the sRGB transfer function.
+fn p3_to_srgb(image: &mut heif::Image) {
+    let to_linear: Vec<f32> = (0..=u8::MAX)
+        .map(|value| {
+            let value = value as f32 / 255.0;
+            if value <= 0.040_45 {
+                value / 12.92
+            } else {
+                ((value + 0.055) / 1.055).powf(2.4)
+            }
+        })
+        .collect();
+    let from_linear = |value: f32| {
+        let value = value.clamp(0.0, 1.0);
+        let value = if value <= 0.003_130_8 {
+            value * 12.92
+        } else {
+            1.055 * value.powf(1.0 / 2.4) - 0.055
+        };
+        (value * 255.0).round() as u8
+    };
+
+    let mut planes = image.planes_mut();
+    let plane = planes.interleaved.as_mut().unwrap();
+    let channels = plane.storage_bits_per_pixel as usize / 8;
+    let row_size = plane.width as usize * channels;
+    for row in plane.data.chunks_mut(plane.stride) {
+        for pixel in row[..row_size].chunks_exact_mut(channels) {
+            let linear = [0, 1, 2].map(|i| to_linear[pixel[i] as usize]);
+            for (value, coefficients) in pixel.iter_mut().zip(P3_TO_SRGB) {
+                let mixed = coefficients.iter().zip(linear).map(|(c, v)| c * v).sum();
+                *value = from_linear(mixed);
+            }
+        }
+    }
+}
+
+/// Where the `format` output for `dest`, the path of the first format's output, is written.
+fn format_path(dest: &Utf8Path, format: Format, opts: &Options) -> Result<Utf8PathBuf> {
+    let path = beside(dest, format.extension());
+    let relative = match path.strip_prefix(opts.format_output(opts.formats[0])) {
+        Ok(relative) if opts.format_subdirs => relative,
+        _ => return Ok(path),
+    };
+    let path = opts.format_output(format).join(relative);
+    // the first format's directories are made as the run plans them, but not these
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create '{}'", parent))?;
+    }
+    Ok(path)
+}
+
+/// Gives `path` the extension `ext`, in the case of the one it has.
+fn beside(path: &Utf8Path, ext: &str) -> Utf8PathBuf {
+    match path.extension() {
+        Some(old) if !old.chars().any(char::is_lowercase) => {
+            path.with_extension(ext.to_uppercase())
+        }
+        _ => path.with_extension(ext),
+    }
+}
+
+/// Decodes the primary image of `ctx`, oriented by its EXIF tag with `--exif-orient`, then
+/// brought to the `--resize` dimensions. The file's nclx and ICC color profiles go along with
+/// it, for the PNG to be tagged with. Images with an alpha channel, which HEIF always stores as a
+/// separate auxiliary image, come out as RGBA with it merged in, and as RGB otherwise.
+fn decode_primary(
+    ctx: &HeifContext,
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+    opts: &Options,
+) -> Result<heif::Image> {
+    let handle = ctx.primary_image_handle()?;
+    let mut image = decode_heif(&handle, handle.has_alpha_channel(), opts)?;
+    if image.is_premultiplied_alpha() {
+        unpremultiply(&mut image);
+    }
+    if opts.exif_orient {
+        image = apply_exif_orientation(&handle, image, source, data)?;
+    }
+    let mut image = resize(image, opts)?;
+    // images made from scratch by the transforms above don't have them
+    if let Some(nclx) = handle.color_profile_nclx() {
+        image.set_color_profile_nclx(&nclx)?;
+    }
+    if let Some(icc) = handle.color_profile_raw() {
+        image.set_color_profile_raw(&icc)?;
+    }
+    Ok(image)
+}
+
+/// Rotates and flips `image` as the EXIF Orientation tag of `handle` says. Files with `irot` or
+/// `imir` properties are left as they are, as libheif has already applied those, and the EXIF
+/// tag in such files usually describes the same rotation.
+fn apply_exif_orientation(
+    handle: &heif::ImageHandle,
+    image: heif::Image,
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+) -> Result<heif::Image> {
+    let orientation = match exif_orientation(handle) {
+        Some(orientation @ 2..=8) => orientation,
+        _ => return Ok(image),
+    };
+    let transformed = match data {
+        Some(data) => has_transform(data),
+        None => has_transform(&read_meta(source)?),
+    };
+    if transformed {
+        debug!(
+            "ignoring EXIF orientation {}, the container has transforms",
+            orientation
+        );
+        return Ok(image);
+    }
+
+    debug!("applying EXIF orientation {}", orientation);
+    orient(&image, orientation)
+}
+
+/// Reads the Orientation tag from the first IFD of the image's EXIF block.
+fn exif_orientation(handle: &heif::ImageHandle) -> Option<u16> {
+    let exif = exif_block(handle)?;
+    let tiff = Tiff::new(&exif)?;
+    // a SHORT value sits in the first 2 bytes of the entry's value
+    let entry = tiff.entry(tiff.u32_at(4)? as usize, 0x0112)?;
+    tiff.u16_at(entry + 8)
+}
+
+/// Returns the TIFF structure of the image's EXIF block for a PNG's `eXIf` chunk, or `None`
+/// with `--strip-metadata`. Its Orientation tag is set to 1 if the pixels have already been
+/// turned the way it says, by `oriented` with `--exif-orient` or by libheif for files with
+/// transforms, so that viewers going by it don't turn them again, and its GPS tags are removed
+/// with `--scrub-gps`.
+fn png_exif(
+    handle: &heif::ImageHandle,
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+    oriented: bool,
+    opts: &Options,
+) -> Result<Option<Vec<u8>>> {
+    if opts.strip_metadata {
+        return Ok(None);
+    }
+    let Some(exif) = exif_block(handle) else {
+        return Ok(None);
+    };
+    let Some(tiff) = Tiff::new(&exif) else {
+        debug!("leaving out an EXIF block without a TIFF header");
+        return Ok(None);
+    };
+    let mut payload = tiff.data.to_vec();
+    let entry = tiff
+        .u32_at(4)
+        .and_then(|ifd| tiff.entry(ifd as usize, 0x0112));
+    if let Some(entry) = entry.filter(|&entry| matches!(tiff.u16_at(entry + 8), Some(2..=8))) {
+        let turned = oriented
+            || match data {
+                Some(data) => has_transform(data),
+                None => has_transform(&read_meta(source)?),
+            };
+        if turned {
+            debug!("resetting the EXIF orientation, the pixels are already turned");
+            let one = if tiff.little_endian {
+                1u16.to_le_bytes()
+            } else {
+                1u16.to_be_bytes()
+            };
+            payload[entry + 8..entry + 10].copy_from_slice(&one);
+        }
+    }
+    if opts.scrub_gps && scrub_gps(&mut payload, tiff.little_endian) {
+        debug!("removed the GPS tags from the EXIF block");
+    }
+    Ok(Some(payload))
+}
+
+/// Removes the GPS IFD from `data`, the TIFF structure of an EXIF block, returning whether there
+/// was one. Its entry in the first IFD is dropped and the IFD itself is zeroed along with the
+/// values it points to, so that the coordinates aren't left anywhere in the bytes, while
+/// everything else stays at the offset it was at.
+///
+/// This is done in place rather than by parsing the block with an EXIF crate and writing it out
+/// again, which would move every value and so break maker notes, whose own offsets nothing but
+/// the camera maker knows how to fix up. Damaged blocks are left as they are where the first IFD
+/// can't be read, and a GPS pointer into the header or the first IFD doesn't get them zeroed.
+fn scrub_gps(data: &mut [u8], little_endian: bool) -> bool {
+    let mut scrubbed = false;
+    // a damaged block can point to more than one
+    while remove_gps_ifd(data, little_endian) {
+        scrubbed = true;
+    }
+    scrubbed
+}
+
+/// Removes the first GPS IFD pointer of the first IFD, for [`scrub_gps`].
+fn remove_gps_ifd(data: &mut [u8], little_endian: bool) -> bool {
+    let tiff = Tiff {
+        data,
+        little_endian,
+    };
+    let Some(ifd) = tiff.u32_at(4).map(|ifd| ifd as usize) else {
+        return false;
+    };
+    let (Some(entries), Some(entry)) = (tiff.u16_at(ifd), tiff.entry(ifd, 0x8825)) else {
+        return false;
+    };
+    // the entries are followed by the offset of the next IFD
+    let end = ifd + 2 + entries as usize * 12 + 4;
+    if end > data.len() {
+        return false;
+    }
+    let mut zeroed = Vec::new();
+    if let Some(gps) = tiff.u32_at(entry + 8).map(|gps| gps as usize) {
+        let count = tiff.u16_at(gps).unwrap_or(0) as usize;
+        for entry in (0..count).map(|i| gps + 2 + i * 12) {
+            let (Some(kind), Some(values)) = (tiff.u16_at(entry + 2), tiff.u32_at(entry + 4))
+            else {
+                break;
+            };
+            // values of more than 4 bytes are stored elsewhere, at the offset the entry holds
+            let size = match kind {
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => 1,
+            } * values as usize;
+            if let Some(at) = tiff.u32_at(entry + 8).filter(|_| size > 4) {
+                zeroed.push(at as usize..(at as usize).saturating_add(size));
+            }
+        }
+        zeroed.push(gps..gps + 2 + count * 12 + 4);
+    }
+
+    data.copy_within(entry + 12..end, entry);
+    data[end - 12..end].fill(0);
+    let entries = if little_endian {
+        (entries - 1).to_le_bytes()
+    } else {
+        (entries - 1).to_be_bytes()
+    };
+    data[ifd..ifd + 2].copy_from_slice(&entries);
+    let kept = [0..8, ifd..end];
+    for range in zeroed {
+        let range = range.start.min(data.len())..range.end.min(data.len());
+        if kept
+            .iter()
+            .any(|kept| range.start < kept.end && kept.start < range.end)
+        {
+            continue;
+        }
+        data[range].fill(0);
+    }
+    true
+}
+
+/// Reads the month the HEIF file at `source` was taken as `YYYY-MM`, from the DateTimeOriginal
+/// tag of its EXIF block or else the DateTime one. The image isn't decoded.
+pub fn exif_month(source: &Utf8Path) -> Option<String> {
+    let ctx = read_heif(source, None).ok()?;
+    let exif = exif_block(&ctx.primary_image_handle().ok()?)?;
+    let tiff = Tiff::new(&exif)?;
+    let ifd = tiff.u32_at(4)? as usize;
+    let original = tiff
+        .entry(ifd, 0x8769)
+        .and_then(|entry| tiff.u32_at(entry + 8))
+        .and_then(|exif_ifd| tiff.entry(exif_ifd as usize, 0x9003));
+
+    // dates are ASCII `YYYY:MM:DD HH:MM:SS`, too long to sit in the entry so it holds an offset,
+    // and cameras that don't know the date fill them with zeros or spaces
+    let entry = original.or_else(|| tiff.entry(ifd, 0x0132))?;
+    let at = tiff.u32_at(entry + 8)? as usize;
+    let date = std::str::from_utf8(tiff.data.get(at..at + 7)?).ok()?;
+    let (year, month) = date.split_once(':')?;
+    match (year.parse::<u32>(), month.parse::<u32>()) {
+        (Ok(1..), Ok(1..=12)) => Some(date.replace(':', "-")),
+        _ => None,
+    }
+}
+
+/// Reads the burst identifier Apple cameras put in the maker note of each photo of a burst, from
+/// the HEIF file at `source`. The image isn't decoded.
+pub fn exif_burst(source: &Utf8Path) -> Option<String> {
+    let ctx = read_heif(source, None).ok()?;
+    let exif = exif_block(&ctx.primary_image_handle().ok()?)?;
+    let tiff = Tiff::new(&exif)?;
+    let exif_ifd = tiff.entry(tiff.u32_at(4)? as usize, 0x8769)?;
+    let exif_ifd = tiff.u32_at(exif_ifd + 8)? as usize;
+    let maker_note = tiff.entry(exif_ifd, 0x927c)?;
+    let (count, at) = (tiff.u32_at(maker_note + 4)?, tiff.u32_at(maker_note + 8)?);
+    let maker_note = tiff
+        .data
+        .get(at as usize..(at as usize).checked_add(count as usize)?)?;
+
+    // Apple's starts with `Apple iOS\0`, a 2-byte version and its byte order, before an IFD whose
+    // offsets count from the start of the note, in which BurstUUID is an ASCII value
+    if !maker_note.starts_with(b"Apple iOS\0") {
+        return None;
+    }
+    let apple = Tiff {
+        data: maker_note,
+        little_endian: maker_note.get(12..14)? == b"II",
+    };
+    let entry = apple.entry(14, 0x000b)?;
+    let (count, at) = (
+        apple.u32_at(entry + 4)? as usize,
+        apple.u32_at(entry + 8)? as usize,
+    );
+    let uuid = std::str::from_utf8(apple.data.get(at..at + count)?).ok()?;
+    let uuid = uuid.trim_end_matches('\0');
+    (!uuid.is_empty()).then(|| uuid.to_string())
+}
+
+/// Returns the image's EXIF block, if it has one.
+fn exif_block(handle: &heif::ImageHandle) -> Option<Vec<u8>> {
+    let mut ids = [0];
+    if handle.metadata_block_ids(&mut ids, b"Exif") == 0 {
+        return None;
+    }
+    handle.metadata(ids[0]).ok()
+}
+
+/// The TIFF structure inside an EXIF block, with offsets from the start of its header.
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+impl<'a> Tiff<'a> {
+    fn new(exif: &'a [u8]) -> Option<Self> {
+        // the block starts with the offset of the TIFF header after these 4 bytes
+        let offset = u32::from_be_bytes(exif.get(..4)?.try_into().ok()?) as usize;
+        let data = exif.get(4 + offset..)?;
+        let little_endian = match data.get(..2)? {
+            b"II" => true,
+            b"MM" => false,
+            _ => return None,
+        };
+        Some(Self {
+            data,
+            little_endian,
+        })
+    }
+
+    fn u16_at(&self, at: usize) -> Option<u16> {
+        let bytes = self.data.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u16::from_le_bytes(bytes)
+        } else {
+            u16::from_be_bytes(bytes)
+        })
+    }
+
+    fn u32_at(&self, at: usize) -> Option<u32> {
+        let bytes = self.data.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little_endian {
+            u32::from_le_bytes(bytes)
+        } else {
+            u32::from_be_bytes(bytes)
+        })
+    }
+
+    /// Finds the entry for `tag` in the IFD at `ifd`. Each entry is a 2-byte tag, 2-byte type,
+    /// 4-byte count and a 4-byte value or offset to it.
+    fn entry(&self, ifd: usize, tag: u16) -> Option<usize> {
+        (0..self.u16_at(ifd)? as usize)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|&entry| self.u16_at(entry) == Some(tag))
+    }
+}
+
+/// Looks for `irot` or `imir` properties in a HEIF file. Both boxes are 9 bytes long, which the
+/// size before the type is checked against to rule out the same bytes in image data.
+fn has_transform(data: &[u8]) -> bool {
+    data.windows(8).any(|window| {
+        matches!(
+            window,
+            [0, 0, 0, 9, b'i', b'r', b'o', b't'] | [0, 0, 0, 9, b'i', b'm', b'i', b'r']
+        )
+    })
+}
+
+/// Copies `image` into a new one, reoriented for EXIF orientations 2 to 8.
+fn orient(image: &heif::Image, orientation: u16) -> Result<heif::Image> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+    let (width, height) = (plane.width as usize, plane.height as usize);
+    let channels = channels(&plane);
+    // 5 to 8 swap the axes
+    let (out_width, out_height) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    let mut oriented = heif::Image::new(
+        out_width as u32,
+        out_height as u32,
+        heif::ColorSpace::Rgb(chroma(channels)),
+    )?;
+    oriented.create_plane(
+        heif::Channel::Interleaved,
+        out_width as u32,
+        out_height as u32,
+        channels as u8 * 8,
+    )?;
+    {
+        let mut out_planes = oriented.planes_mut();
+        let out = out_planes.interleaved.as_mut().unwrap();
+
+        for y in 0..out_height {
+            for x in 0..out_width {
+                let (sx, sy) = match orientation {
+                    2 => (width - 1 - x, y),
+                    3 => (width - 1 - x, height - 1 - y),
+                    4 => (x, height - 1 - y),
+                    5 => (y, x),
+                    6 => (y, height - 1 - x),
+                    7 => (width - 1 - y, height - 1 - x),
+                    _ => (width - 1 - y, x),
+                };
+                let from = sy * plane.stride + sx * channels;
+                let to = y * out.stride + x * channels;
+                out.data[to..to + channels].copy_from_slice(&plane.data[from..from + channels]);
+            }
+        }
+    }
+    Ok(oriented)
+}
+
+/// Scales `width` and `height` down so that the longer is at most `size`, keeping the aspect ratio
+/// and never scaling up.
+fn fit_within(width: u32, height: u32, size: u32) -> (u32, u32) {
+    let longest = width.max(height);
+    let scaled = |side: u32| match longest {
+        longest if longest <= size => side,
+        longest => {
+            ((side as u64 * size as u64 + longest as u64 / 2) / longest as u64).max(1) as u32
+        }
+    };
+    (scaled(width), scaled(height))
+}
+
+/// Scales `image` to the `--resize` dimensions, padding or cropping it as `--fit` says when its
+/// aspect ratio is different, or else shrinks it to `--max-dimension`.
+fn resize(image: heif::Image, opts: &Options) -> Result<heif::Image> {
+    let Some((width, height)) = opts.resize else {
+        let Some(size) = opts.max_dimension else {
+            return Ok(image);
+        };
+        let (width, height) = fit_within(image.width(), image.height(), size.get());
+        if (width, height) == (image.width(), image.height()) {
+            return Ok(image);
+        }
+        debug!(
+            "shrinking from {}x{} to {}x{}",
+            image.width(),
+            image.height(),
+            width,
+            height
+        );
+        return Ok(image.scale(width, height, None)?);
+    };
+    let (from_width, from_height) = (image.width() as u64, image.height() as u64);
+    if (from_width, from_height) == (width as u64, height as u64) {
+        return Ok(image);
+    }
+
+    // whether the image is wider than the target, relative to its height
+    let wider = from_width * height as u64 > from_height * width as u64;
+    // the side that isn't matched exactly is rounded to the nearest pixel
+    let scaled = |from: u64, to: u32, other_from: u64| {
+        ((from * to as u64 + other_from / 2) / other_from).max(1) as u32
+    };
+    let (scaled_width, scaled_height) = match opts.fit {
+        Fit::Stretch => (width, height),
+        Fit::Contain if wider => (width, scaled(from_height, width, from_width).min(height)),
+        Fit::Contain => (scaled(from_width, height, from_height).min(width), height),
+        Fit::Cover if wider => (scaled(from_width, height, from_height).max(width), height),
+        Fit::Cover => (width, scaled(from_height, width, from_width).max(height)),
+    };
+    debug!(
+        "resizing from {}x{} to {}x{}, then to {}x{}",
+        from_width, from_height, scaled_width, scaled_height, width, height
+    );
+    let image = image.scale(scaled_width, scaled_height, None)?;
+    if (scaled_width, scaled_height) == (width, height) {
+        return Ok(image);
+    }
+    place(&image, width, height, opts.pad_color)
+}
+
+/// Copies `image` into the middle of a new `width` by `height` one, cropping off whatever doesn't
+/// fit and filling the borders around a smaller image with `color`, which is opaque in images
+/// with alpha.
+fn place(image: &heif::Image, width: u32, height: u32, color: [u8; 3]) -> Result<heif::Image> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+    let channels = channels(&plane);
+    let [r, g, b] = color;
+    let color = &[r, g, b, u8::MAX][..channels];
+
+    let mut placed = heif::Image::new(width, height, heif::ColorSpace::Rgb(chroma(channels)))?;
+    placed.create_plane(
+        heif::Channel::Interleaved,
+        width,
+        height,
+        channels as u8 * 8,
+    )?;
+    {
+        let mut out_planes = placed.planes_mut();
+        let out = out_planes.interleaved.as_mut().unwrap();
+
+        // how far the image starts to the right of and below the top left corner, negative when
+        // it's cropped
+        let left = (width as i64 - plane.width as i64) / 2;
+        let top = (height as i64 - plane.height as i64) / 2;
+        let (x_start, x_end) = (left.max(0), (left + plane.width as i64).min(width as i64));
+        for y in 0..height as i64 {
+            let row = &mut out.data[y as usize * out.stride..][..width as usize * channels];
+            for pixel in row.chunks_exact_mut(channels) {
+                pixel.copy_from_slice(color);
+            }
+            let from_y = y - top;
+            if from_y < 0 || from_y >= plane.height as i64 {
+                continue;
+            }
+            let from = from_y as usize * plane.stride + (x_start - left) as usize * channels;
+            let len = (x_end - x_start) as usize * channels;
+            row[x_start as usize * channels..][..len]
+                .copy_from_slice(&plane.data[from..from + len]);
+        }
+    }
+    Ok(placed)
+}
+
+/// Bytes per pixel of an interleaved 8-bit plane, which is 4 with alpha and 3 without.
+fn channels(plane: &heif::Plane<&[u8]>) -> usize {
+    plane.storage_bits_per_pixel as usize / 8
+}
+
+fn chroma(channels: usize) -> heif::RgbChroma {
+    match channels {
+        4 => heif::RgbChroma::Rgba,
+        _ => heif::RgbChroma::Rgb,
+    }
+}
+
+/// Divides the color of each pixel of an RGBA image by its alpha, for files whose alpha is
+/// premultiplied, since PNG's never is.
+fn unpremultiply(image: &mut heif::Image) {
+    {
+        let mut planes = image.planes_mut();
+        let plane = planes.interleaved.as_mut().unwrap();
+        let row_size = plane.width as usize * 4;
+        for row in plane.data.chunks_mut(plane.stride) {
+            for pixel in row[..row_size].chunks_exact_mut(4) {
+                let alpha = pixel[3] as u32;
+                for value in &mut pixel[..3] {
+                    *value = match alpha {
+                        0 => 0,
+                        _ => ((*value as u32 * 255 + alpha / 2) / alpha).min(255) as u8,
+                    };
+                }
+            }
+        }
+    }
+    image.set_premultiplied_alpha(false);
+}
+
+/// Points out when libheif failed on a tiled image, which some builds can't assemble, since its
+/// own error doesn't say so.
+pub fn explain_grid(err: anyhow::Error, source: &Utf8Path, data: Option<&[u8]>) -> anyhow::Error {
+    if !err.chain().any(|cause| cause.is::<heif::HeifError>()) {
+        return err;
+    }
+    let grid = match data {
+        Some(data) => has_grid_item(data),
+        None => read_meta(source).is_ok_and(|meta| has_grid_item(&meta)),
+    };
+    if grid {
+        err.context(
+            "this is a tiled (grid) image whose tiles libheif couldn't assemble, either because \
+             the file is incomplete or because the installed libheif is too old for it",
+        )
+    } else {
+        err
+    }
+}
+
+/// Looks for an item of type `grid` among the item info entries of a HEIF file.
+fn has_grid_item(data: &[u8]) -> bool {
+    // an `infe` box is followed by its version and flags, then the item id, which is 4 bytes
+    // from version 3 and 2 bytes before, a 2-byte protection index and the item type
+    data.windows(4)
+        .enumerate()
+        .filter(|(_, window)| *window == b"infe")
+        .any(|(i, _)| {
+            let version = data.get(i + 4).copied().unwrap_or(0);
+            let item_type = i + 8 + if version >= 3 { 4 } else { 2 } + 2;
+            version >= 2 && data.get(item_type..item_type + 4) == Some(b"grid")
+        })
+}
+
+/// Opens `source` with libheif, or `data` if the file has already been read.
+pub fn read_heif<'a>(source: &Utf8Path, data: Option<&'a [u8]>) -> Result<HeifContext<'a>> {
+    Ok(match data {
+        Some(data) => HeifContext::read_from_bytes(data)?,
+        None => HeifContext::read_from_file(source.as_str())?,
+    })
+}
+
+/// Whether `err` is libheif failing to open a file, rather than to read it as HEIF. Its own open
+/// is refused on some network shares with odd ACLs where a plain read-only one isn't.
+pub fn libheif_cant_open(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<heif::HeifError>()
+        .is_some_and(|err| matches!(err.code, heif::HeifErrorCode::InputDoesNotExist))
+}
+
+/// Reads the top-level `meta` box of the HEIF file at `source` through [`open_read_only`],
+/// skipping over the image data around it, for the properties and item info libheif doesn't
+/// expose. Files without one, or too damaged to find it in, give an empty box.
+fn read_meta(source: &Utf8Path) -> Result<Vec<u8>> {
+    let mut file = io::BufReader::new(open_read_only(source)?);
+    let failed = || format!("failed to read '{}'", source);
+    loop {
+        // a 4-byte size including the header, then the type
+        let mut header = [0; 8];
+        match file.read_exact(&mut header) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(Vec::new()),
+            read => read.with_context(failed)?,
+        }
+        let (size, header_size) = match u32::from_be_bytes(header[..4].try_into().unwrap()) {
+            // the box runs to the end of the file
+            0 => (u64::MAX, 8),
+            // a 64-bit size follows the type
+            1 => {
+                let mut size = [0; 8];
+                file.read_exact(&mut size).with_context(failed)?;
+                (u64::from_be_bytes(size), 16)
+            }
+            size => (size as u64, 8),
+        };
+        let Some(body) = size.checked_sub(header_size) else {
+            return Ok(Vec::new());
+        };
+        if &header[4..] == b"meta" {
+            let mut meta = Vec::new();
+            file.take(body)
+                .read_to_end(&mut meta)
+                .with_context(failed)?;
+            return Ok(meta);
+        }
+        if size == u64::MAX {
+            return Ok(Vec::new());
+        }
+        file.seek_relative(body.try_into().unwrap_or(i64::MAX))
+            .with_context(failed)?;
+    }
+}
+
+/// Reads all of `source` through [`open_read_only`].
+pub fn read_source(source: &Utf8Path) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open_read_only(source)?
+        .read_to_end(&mut data)
+        .with_context(|| format!("failed to read '{}'", source))?;
+    Ok(data)
+}
+
+/// Opens `source` for reading and nothing else. A file of the process's own that's only missing
+/// its owner read bit is given it for as long as it takes to open, and a permission error that's
+/// left is told apart from the file being damaged.
+pub fn open_read_only(source: &Utf8Path) -> Result<File> {
+    let open = || File::options().read(true).open(source);
+    let denied = match open() {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => err,
+        opened => return opened.with_context(|| format!("failed to open '{}'", source)),
+    };
+
+    #[cfg(unix)]
+    if let Ok(meta) = std::fs::metadata(source) {
+        use std::{fs::Permissions, os::unix::fs::PermissionsExt};
+        let mode = meta.permissions().mode();
+        // fails for files of other users, which are left alone
+        if mode & 0o400 == 0
+            && std::fs::set_permissions(source, Permissions::from_mode(mode | 0o400)).is_ok()
+        {
+            let opened = open();
+            if let Err(err) = std::fs::set_permissions(source, meta.permissions()) {
+                warn!("couldn't put back the permissions of '{}': {}", source, err);
+            }
+            if let Ok(file) = opened {
+                info!("opened after giving it owner read permission for a moment");
+                return Ok(file);
+            }
+        }
+    }
+    Err(denied).with_context(|| {
+        format!(
+            "permission denied opening '{}' even read-only, so it's its permissions or ACLs that \
+             need fixing rather than the file that's damaged",
+            source
+        )
+    })
+}
+
+fn decode_heif(handle: &heif::ImageHandle, alpha: bool, opts: &Options) -> Result<heif::Image> {
+    let pixels = handle.width() as u64 * handle.height() as u64;
+    // the full image is never decoded then, so it isn't held to `--max-pixels` either
+    let thumbnail = opts
+        .thumbnail_above
+        .filter(|&megapixels| pixels as f64 > megapixels * 1e6)
+        .and_then(|_| largest_thumbnail(handle));
+    let handle = thumbnail.as_ref().unwrap_or(handle);
+    if thumbnail.is_some() {
+        debug!(
+            "decoding the {}x{} embedded thumbnail",
+            handle.width(),
+            handle.height()
+        );
+    } else if opts.max_pixels > 0 && pixels > opts.max_pixels {
+        bail!(
+            "image is {}x{}, larger than the {} pixel limit (see --max-pixels)",
+            handle.width(),
+            handle.height(),
+            opts.max_pixels
+        );
+    }
+    debug!("decoding {}", describe_image(handle));
+
+    let options = match &opts.decoder {
+        Some(decoder) => {
+            let mut options =
+                DecodingOptions::new().with_context(|| "failed to allocate decoding options")?;
+            options.set_decoder_id(Some(decoder))?;
+            Some(options)
+        }
+        None => None,
+    };
+    // libheif finds the auxiliary alpha image itself, and leaves it out of RGB
+    let color_space = heif::ColorSpace::Rgb(chroma(if alpha { 4 } else { 3 }));
+    match opts.decode_scale {
+        Some(scale) if thumbnail.is_none() => {
+            decode_scaled(handle, scale.get(), color_space, options)
+        }
+        _ => Ok(HEIF.decode(handle, color_space, options)?),
+    }
+}
+
+/// Returns the biggest of the thumbnails embedded alongside `handle`, for `--thumbnail-above`.
+fn largest_thumbnail(handle: &heif::ImageHandle) -> Option<heif::ImageHandle> {
+    let mut ids = vec![0; handle.number_of_thumbnails()];
+    handle.thumbnail_ids(&mut ids);
+    ids.into_iter()
+        .filter_map(|id| handle.thumbnail(id).ok())
+        .max_by_key(|thumb| thumb.width() as u64 * thumb.height() as u64)
+}
+
+/// Decodes `handle` at 1/`scale` of its size. libheif can't decode at a reduced resolution
+/// itself, so where the file embeds a thumbnail at least that big, that's decoded instead of the
+/// full image. Otherwise the full image is decoded and scaled down.
+fn decode_scaled(
+    handle: &heif::ImageHandle,
+    scale: u32,
+    color_space: heif::ColorSpace,
+    options: Option<DecodingOptions>,
+) -> Result<heif::Image> {
+    let width = (handle.width() / scale).max(1);
+    let height = (handle.height() / scale).max(1);
+
+    let mut ids = vec![0; handle.number_of_thumbnails()];
+    handle.thumbnail_ids(&mut ids);
+    let thumbnail = ids
+        .into_iter()
+        .filter_map(|id| handle.thumbnail(id).ok())
+        .filter(|thumb| thumb.width() >= width && thumb.height() >= height)
+        // a thumbnail of a different shape would come out stretched
+        .filter(|thumb| {
+            let (w, h) = (thumb.width() as u64, thumb.height() as u64);
+            let skew = (w * handle.height() as u64).abs_diff(h * handle.width() as u64);
+            skew * 100 <= w * handle.height() as u64
+        })
+        .min_by_key(|thumb| thumb.width());
+    debug!(
+        "decoding at {}x{} from {}",
+        width,
+        height,
+        if thumbnail.is_some() {
+            "the embedded thumbnail"
+        } else {
+            "the full image"
+        }
+    );
+
+    let image = HEIF.decode(thumbnail.as_ref().unwrap_or(handle), color_space, options)?;
+    if (image.width(), image.height()) == (width, height) {
+        Ok(image)
+    } else {
+        Ok(image.scale(width, height, None)?)
+    }
+}
+
+/// Fails the file for `--strict` over what a conversion would otherwise quietly work around: no
+/// color profile to say what the colors are, a bit depth that's cut down to 8, or no EXIF block
+/// for `--exif-orient` to go by.
+fn check_strict(handle: &heif::ImageHandle, opts: &Options) -> Result<()> {
+    if handle.color_profile_nclx().is_none() && handle.color_profile_raw().is_none() {
+        bail!("the image has no color profile, so it would be taken for sRGB (--strict)");
+    }
+    let (luma, chroma) = (handle.luma_bits_per_pixel(), handle.chroma_bits_per_pixel());
+    if (luma, chroma) != (8, 8) {
+        bail!(
+            "the image is {}-bit luma and {}-bit chroma, which would be cut down to 8 bits \
+             (--strict)",
+            luma,
+            chroma
+        );
+    }
+    if opts.exif_orient && exif_block(handle).is_none() {
+        bail!("the image has no EXIF block for --exif-orient to go by (--strict)");
+    }
+    Ok(())
+}
+
+/// Summarizes the properties of a source image that most often explain odd-looking output.
+fn describe_image(handle: &heif::ImageHandle) -> String {
+    let alpha = match (handle.has_alpha_channel(), handle.is_premultiplied_alpha()) {
+        (true, true) => "premultiplied alpha",
+        (true, false) => "alpha",
+        (false, _) => "no alpha",
+    };
+    let color = match handle.color_profile_nclx() {
+        Some(nclx) => format!(
+            "nclx {:?}/{:?}/{:?}{}",
+            nclx.color_primaries(),
+            nclx.transfer_characteristics(),
+            nclx.matrix_coefficients(),
+            if nclx.full_range_flag() != 0 {
+                " full range"
+            } else {
+                ""
+            }
+        ),
+        None => match handle.color_profile_raw() {
+            Some(icc) => format!("ICC profile of {} bytes", icc.data.len()),
+            None => "no color profile".to_string(),
+        },
+    };
+    format!(
+        "{}x{}, {}-bit luma, {}-bit chroma, {}, {}",
+        handle.width(),
+        handle.height(),
+        handle.luma_bits_per_pixel(),
+        handle.chroma_bits_per_pixel(),
+        alpha,
+        color
+    )
+}
+
+/// Encodes `image` as a PNG with `exif` from [`png_exif`]. The pixels are only borrowed, so it's
+/// up to the caller to drop them once nothing else is encoded from them, as [`heif_to_formats`]
+/// does after the last format that needs them.
+fn encode_png<W: Write>(
+    image: &heif::Image,
+    exif: Option<&[u8]>,
+    source: &Utf8Path,
+    writer: W,
+    progress: &dyn Fn(u64, u64),
+    opts: &Options,
+) -> Result<Option<Thumbnail>> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+
+    let thumbnail = make_thumbnail(image, opts);
+
+    let channels = channels(&plane);
+    let target_size = plane.width as usize * plane.height as usize * channels;
+    let actual_size = plane.data.len();
+
+    let tags = PngTags::new(
+        image.color_profile_nclx(),
+        image.color_profile_raw(),
+        exif,
+        opts,
+    );
+    let total = target_size as u64;
+
+    // a palette has no room for alpha, short of a tRNS chunk
+    let indexed = opts.quantize.filter(|_| channels == 3).and_then(|mode| {
+        quantize::quantize(plane.data, plane.width, plane.height, plane.stride, mode)
+    });
+    if let Some(indexed) = indexed {
+        debug!(
+            "encoding with a palette of {} colors",
+            indexed.palette.len() / 3
+        );
+        // the png crate writes the palette along with the header, which cICP and iCCP chunks
+        // have to come before, so they're put in as the header goes by
+        let writer = AfterHeader::new(writer, &color_chunks(&tags)?);
+        let mut encoder = png_encoder(writer, &plane, source, &tags, opts)?;
+        encoder.set_color(png::ColorType::Indexed);
+        encoder.set_palette(indexed.palette);
+        let rest = PngTags {
+            cicp: None,
+            icc: None,
+            ..tags
+        };
+        let mut writer = start_png(encoder, &rest)?;
+        writer.write_image_data(&indexed.indices)?;
+        writer.finish()?;
+        progress(total, total);
+        info!("converted");
+        return Ok(thumbnail);
+    }
+
+    let encoder = png_encoder(writer, &plane, source, &tags, opts)?;
+    let dither = opts.bit_reduce.map(Dither::new);
+    let mut writer = start_png(encoder, &tags)?;
+    if target_size == actual_size && dither.is_none() {
+        debug!("encoding as a single stream");
+        let mut stream = writer.stream_writer()?;
+        let mut encoded = 0;
+        for chunk in plane.data.chunks(PROGRESS_BYTES) {
+            stream.write_all(chunk)?;
+            encoded += chunk.len() as u64;
+            progress(encoded, total);
+        }
+        stream.finish()?;
+    } else {
+        match opts.bit_reduce {
+            Some(bits) => debug!("encoding row by row, dithered to {} bits", bits),
+            None => debug!("encoding row by row, trimming the padding"),
+        }
+        // rows are padded out to the stride, which must be trimmed off before encoding
+        let row_size = plane.width as usize * channels;
+        let rows_per_report = (PROGRESS_BYTES / row_size).max(1);
+        let mut stream = writer.stream_writer_with_size(row_size)?;
+        let mut reduced = Vec::with_capacity(row_size);
+        for (n, row) in plane.data.chunks(plane.stride).enumerate() {
+            match &dither {
+                Some(dither) => {
+                    dither.row(&row[..row_size], n, channels, &mut reduced);
+                    stream.write_all(&reduced)?;
+                }
+                None => stream.write_all(&row[..row_size])?,
+            }
+            if (n + 1) % rows_per_report == 0 || n + 1 == plane.height as usize {
+                progress(((n + 1) * row_size) as u64, total);
+            }
+        }
+        stream.finish()?;
+    }
+    writer.finish()?;
+
+    info!("converted");
+    Ok(thumbnail)
+}
+
+/// Makes a thumbnail of `image` for the contact sheet, with `--contact-sheet`.
+fn make_thumbnail(image: &heif::Image, opts: &Options) -> Option<Thumbnail> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+    let channels = channels(&plane);
+    opts.contact_sheet.then(|| {
+        Thumbnail::new(
+            plane.data,
+            plane.width,
+            plane.height,
+            plane.stride,
+            channels,
+        )
+    })
+}
+
+fn encode_jpeg<W: Write>(image: &heif::Image, writer: W, opts: &Options) -> Result<()> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+    let (Ok(width), Ok(height)) = (u16::try_from(plane.width), u16::try_from(plane.height)) else {
+        bail!(
+            "{}x{} is too big for a JPEG, which is at most 65535 pixels either way",
+            plane.width,
+            plane.height
+        );
+    };
+
+    // the encoder takes rows without the padding out to the stride, and JPEGs have no alpha
+    let channels = channels(&plane);
+    let row_size = plane.width as usize * channels;
+    let data: Cow<[u8]> = if plane.stride == row_size && channels == 3 {
+        Cow::Borrowed(&plane.data[..row_size * plane.height as usize])
+    } else {
+        Cow::Owned(
+            plane
+                .data
+                .chunks(plane.stride)
+                .flat_map(|row| row[..row_size].chunks_exact(channels))
+                .flat_map(|pixel| &pixel[..3])
+                .copied()
+                .collect(),
+        )
+    };
+    match opts.jpeg_encoder {
+        JpegEncoder::Baseline => {
+            let mut encoder = jpeg_encoder::Encoder::new(writer, opts.jpeg_quality);
+            encoder.set_progressive(opts.progressive);
+            encoder.encode(&data, width, height, jpeg_encoder::ColorType::Rgb)?;
+        }
+        #[cfg(feature = "mozjpeg")]
+        JpegEncoder::Mozjpeg => {
+            let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+            compress.set_size(width as usize, height as usize);
+            compress.set_quality(opts.jpeg_quality as f32);
+            if opts.progressive {
+                compress.set_progressive_mode();
+            }
+            let mut compress = compress.start_compress(writer)?;
+            compress.write_scanlines(&data)?;
+            compress.finish()?;
+        }
+        // refused when the options are parsed, but library callers can still ask for it
+        #[cfg(not(feature = "mozjpeg"))]
+        JpegEncoder::Mozjpeg => {
+            bail!("this build has no mozjpeg, rebuild with '--features mozjpeg' for it")
+        }
+    }
+
+    info!("converted to JPEG");
+    Ok(())
+}
+
+/// Encodes each of `handles` as a frame of an animated PNG, decoding them one at a time. The
+/// thumbnail, with `--contact-sheet`, is of the first frame, and the EXIF block and ICC profile
+/// are the first frame's too.
+fn encode_apng<W: Write>(
+    handles: &[heif::ImageHandle],
+    source: &Utf8Path,
+    data: Option<&[u8]>,
+    out: W,
+    opts: &Options,
+) -> Result<Option<Thumbnail>> {
+    let mut out = Some(out);
+    // started once the first frame's size is known
+    let mut encoding = None;
+    let mut thumbnail = None;
+    for (n, handle) in handles.iter().enumerate() {
+        // every frame has to have the same color type, so they're all decoded without alpha
+        let image = resize(decode_heif(handle, false, opts)?, opts)?;
+        let planes = image.planes();
+        let plane = planes.interleaved.unwrap();
+
+        if encoding.is_none() {
+            thumbnail = opts
+                .contact_sheet
+                .then(|| Thumbnail::new(plane.data, plane.width, plane.height, plane.stride, 3));
+
+            // frames aren't turned by `--exif-orient`
+            let exif = png_exif(handle, source, data, false, opts)?;
+            let tags = PngTags::new(
+                handle.color_profile_nclx(),
+                handle.color_profile_raw(),
+                exif.as_deref(),
+                opts,
+            );
+            let (width, height) = (plane.width, plane.height);
+            let mut encoder = png_encoder(out.take().unwrap(), &plane, source, &tags, opts)?;
+            encoder.set_animated(handles.len() as u32, 0)?;
+            encoder.set_frame_delay(FRAME_DELAY.0, FRAME_DELAY.1)?;
+            encoding = Some((start_png(encoder, &tags)?, width, height));
+        }
+        let (writer, width, height) = encoding.as_mut().unwrap();
+        if (plane.width, plane.height) != (*width, *height) {
+            bail!(
+                "image {} is {}x{}, but an animation's frames must all be {}x{} like the first",
+                n + 1,
+                plane.width,
+                plane.height,
+                width,
+                height
+            );
+        }
+
+        // rows are padded out to the stride, which must be trimmed off before encoding
+        let row_size = plane.width as usize * 3;
+        let frame: Vec<u8> = plane
+            .data
+            .chunks(plane.stride)
+            .flat_map(|row| &row[..row_size])
+            .copied()
+            .collect();
+        writer.write_image_data(&frame)?;
+    }
+
+    encoding.unwrap().0.finish()?;
+    info!("converted {} images into an animation", handles.len());
+    Ok(thumbnail)
+}
+
+/// Encodes `image` as a WebP, lossy unless `--webp-lossless`, keeping its alpha if it has any.
+fn encode_webp(image: &heif::Image, opts: &Options) -> Result<webp::WebPMemory> {
+    let planes = image.planes();
+    let plane = planes.interleaved.unwrap();
+    check_webp_size(plane.width, plane.height)?;
+    let channels = channels(&plane);
+    let pixels = packed_rows(&plane, channels);
+    let encoder = match channels {
+        4 => webp::Encoder::from_rgba(&pixels, plane.width, plane.height),
+        _ => webp::Encoder::from_rgb(&pixels, plane.width, plane.height),
+    };
+    let webp = encoder
+        .encode_simple(opts.webp_lossless, opts.webp_quality as f32)
+        .map_err(|err| anyhow!("failed to encode the WebP: {:?}", err))?;
+    info!("converted");
+    Ok(webp)
+}
+
+/// Encodes each of `handles` as a frame of an animated WebP, shown for as long as the frames of
+/// an APNG, and returns it along with the thumbnail of the first frame. The frames are all held
+/// until the end, since libwebp only encodes the animation once it has every one.
+fn encode_animated_webp(
+    handles: &[heif::ImageHandle],
+    opts: &Options,
+) -> Result<(Option<Thumbnail>, webp::WebPMemory)> {
+    let mut frames = Vec::with_capacity(handles.len());
+    let mut thumbnail = None;
+    let mut size = None;
+    for (n, handle) in handles.iter().enumerate() {
+        // like an APNG's, every frame is decoded without alpha
+        let image = resize(decode_heif(handle, false, opts)?, opts)?;
+        let planes = image.planes();
+        let plane = planes.interleaved.unwrap();
+        let (width, height) = *size.get_or_insert((plane.width, plane.height));
+        if n == 0 {
+            check_webp_size(width, height)?;
+            thumbnail = opts
+                .contact_sheet
+                .then(|| Thumbnail::new(plane.data, plane.width, plane.height, plane.stride, 3));
+        } else if (plane.width, plane.height) != (width, height) {
+            bail!(
+                "image {} is {}x{}, but an animation's frames must all be {}x{} like the first",
+                n + 1,
+                plane.width,
+                plane.height,
+                width,
+                height
+            );
+        }
+        frames.push(packed_rows(&plane, 3).into_owned());
+    }
+
+    let (width, height) = size.unwrap();
+    let mut config =
+        webp::WebPConfig::new().map_err(|()| anyhow!("failed to set up the WebP encoder"))?;
+    config.lossless = i32::from(opts.webp_lossless);
+    config.quality = opts.webp_quality as f32;
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+    let delay_ms = 1000 * FRAME_DELAY.0 as i32 / FRAME_DELAY.1 as i32;
+    for (n, frame) in frames.iter().enumerate() {
+        encoder.add_frame(webp::AnimFrame::from_rgb(
+            frame,
+            width,
+            height,
+            n as i32 * delay_ms,
+        ));
+    }
+    let webp = encoder
+        .try_encode()
+        .map_err(|err| anyhow!("failed to encode the animation: {:?}", err))?;
+    info!("converted {} images into an animation", handles.len());
+    Ok((thumbnail, webp))
+}
+
+fn check_webp_size(width: u32, height: u32) -> Result<()> {
+    if width > WEBP_MAX_DIMENSION || height > WEBP_MAX_DIMENSION {
+        bail!(
+            "{}x{} is too big for a WebP, which is at most {} pixels either way",
+            width,
+            height,
+            WEBP_MAX_DIMENSION
+        );
+    }
+    Ok(())
+}
+
+/// The pixels of `plane` with the padding out to the stride trimmed off the end of each row.
+fn packed_rows<'a>(plane: &heif::Plane<&'a [u8]>, channels: usize) -> Cow<'a, [u8]> {
+    let row_size = plane.width as usize * channels;
+    if plane.stride == row_size {
+        return Cow::Borrowed(&plane.data[..row_size * plane.height as usize]);
+    }
+    Cow::Owned(
+        plane
+            .data
+            .chunks(plane.stride)
+            .take(plane.height as usize)
+            .flat_map(|row| &row[..row_size])
+            .copied()
+            .collect(),
+    )
+}
+
+/// Sets up a PNG encoder for an image the size and color type of `plane`.
+fn png_encoder<W: Write>(
+    writer: W,
+    plane: &heif::Plane<&[u8]>,
+    source: &Utf8Path,
+    tags: &PngTags,
+    opts: &Options,
+) -> Result<png::Encoder<'static, W>> {
+    let mut encoder = png::Encoder::new(writer, plane.width, plane.height);
+    encoder.set_color(match channels(plane) {
+        4 => png::ColorType::Rgba,
+        _ => png::ColorType::Rgb,
+    });
+    if opts.adaptive_png {
+        // flat areas compress well enough to be worth the effort, while a photo's noise barely
+        // does, so it's better off encoded quickly
+        let (compression, filter) = if is_flat(plane) {
+            (png::Compression::Best, png::FilterType::NoFilter)
+        } else {
+            (png::Compression::Fast, png::FilterType::Paeth)
+        };
+        debug!(
+            "encoding with {:?} compression and the {:?} filter",
+            compression, filter
+        );
+        encoder.set_compression(compression);
+        encoder.set_filter(filter);
+    } else {
+        encoder.set_compression(opts.compression);
+        match opts.png_filter {
+            Some(PngFilter::Fixed(filter)) => encoder.set_filter(filter),
+            Some(PngFilter::Adaptive) => {
+                encoder.set_adaptive_filter(png::AdaptiveFilterType::Adaptive)
+            }
+            None => {}
+        }
+    }
+    // either would contradict a cICP chunk for any other color space, or an ICC profile
+    let srgb = tags.icc.is_none()
+        && tags.cicp.is_none_or(|[primaries, transfer, ..]| {
+            (primaries, transfer) == (CICP_SRGB_PRIMARIES, CICP_SRGB_TRANSFER)
+        });
+    match opts.color_chunk.filter(|_| srgb) {
+        // decoded images are sRGB unless the file says otherwise, which libheif doesn't convert
+        Some(ColorChunk::Srgb) => encoder.set_srgb(png::SrgbRenderingIntent::Perceptual),
+        // 1/2.2 as PNG stores it, which rounding an f32 can miss by one
+        Some(ColorChunk::Gamma) => encoder.set_source_gamma(png::ScaledFloat::from_scaled(45455)),
+        None => {}
+    }
+    if opts.embed_source_name {
+        embed_source_name(&mut encoder, source)?;
+    }
+    Ok(encoder)
+}
+
+/// Whether `plane` looks like a screenshot or graphic rather than a photo, from how many of the
+/// pixels in a sample of its rows repeat the one to their left.
+fn is_flat(plane: &heif::Plane<&[u8]>) -> bool {
+    let channels = channels(plane);
+    let row_size = plane.width as usize * channels;
+    let step = (plane.height as usize / ADAPTIVE_SAMPLE_ROWS).max(1);
+    let (mut repeats, mut pixels) = (0, 0);
+    for row in plane
+        .data
+        .chunks(plane.stride)
+        .take(plane.height as usize)
+        .step_by(step)
+    {
+        let row = &row[..row_size];
+        for (left, pixel) in row
+            .chunks_exact(channels)
+            .zip(row.chunks_exact(channels).skip(1))
+        {
+            pixels += 1;
+            repeats += usize::from(left == pixel);
+        }
+    }
+    repeats > pixels / FLAT_REPEATS_DIVISOR
+}
+
+/// What a PNG carries over from its source besides the pixels, in the chunks that go before the
+/// image data.
+struct PngTags<'a> {
+    /// The contents of a `cICP` chunk, from [`cicp`].
+    cicp: Option<[u8; 4]>,
+    /// The ICC profile for an `iCCP` chunk.
+    icc: Option<Vec<u8>>,
+    /// The TIFF structure of the EXIF block for an `eXIf` chunk.
+    exif: Option<&'a [u8]>,
+}
+
+impl<'a> PngTags<'a> {
+    /// Collects the tags for an image with these color profiles, leaving out the ICC profile with
+    /// `--strip-metadata`.
+    fn new(
+        nclx: Option<heif::ColorProfileNCLX>,
+        icc: Option<heif::ColorProfileRaw>,
+        exif: Option<&'a [u8]>,
+        opts: &Options,
+    ) -> Self {
+        Self {
+            cicp: cicp(nclx),
+            icc: icc.filter(|_| !opts.strip_metadata).map(|icc| icc.data),
+            exif,
+        }
+    }
+}
+
+/// Writes the header and the chunks that go before the image data, which are the ones in `tags`
+/// as the png crate doesn't write them itself.
+fn start_png<W: Write>(
+    encoder: png::Encoder<'static, W>,
+    tags: &PngTags,
+) -> Result<png::Writer<W>> {
+    let mut writer = encoder.write_header()?;
+    for (kind, data) in color_chunks(tags)? {
+        writer.write_chunk(kind, &data)?;
+    }
+    if let Some(exif) = tags.exif {
+        debug!("copying an EXIF block of {} bytes", exif.len());
+        writer.write_chunk(png::chunk::ChunkType(*b"eXIf"), exif)?;
+    }
+    Ok(writer)
+}
+
+/// Builds the `cICP` and `iCCP` chunks for `tags`, which have to come before any palette.
+fn color_chunks(tags: &PngTags) -> Result<Vec<(png::chunk::ChunkType, Vec<u8>)>> {
+    let mut chunks = Vec::new();
+    if let Some(cicp) = tags.cicp {
+        debug!("tagging as cICP {:?}", cicp);
+        chunks.push((png::chunk::ChunkType(*b"cICP"), cicp.to_vec()));
+    }
+    if let Some(icc) = &tags.icc {
+        debug!("embedding an ICC profile of {} bytes", icc.len());
+        // a profile name and compression method 0, then the profile as a zlib stream
+        let mut zlib = ZlibEncoder::new(b"ICC Profile\0\0".to_vec(), flate2::Compression::best());
+        zlib.write_all(icc)?;
+        chunks.push((png::chunk::iCCP, zlib.finish()?));
+    }
+    Ok(chunks)
+}
+
+/// Signature and IHDR chunk, which every PNG starts with.
+const PNG_HEADER_SIZE: usize = 8 + 12 + 13;
+
+/// Passes a PNG through to the writer it wraps, putting chunks in straight after the IHDR. The
+/// png crate writes a palette as part of the header, leaving no room before it otherwise.
+struct AfterHeader<W> {
+    inner: W,
+    /// The chunks, ready to be written as they are.
+    chunks: Vec<u8>,
+    /// How much of the header is still to go by.
+    left: usize,
+}
+
+impl<W: Write> AfterHeader<W> {
+    fn new(inner: W, chunks: &[(png::chunk::ChunkType, Vec<u8>)]) -> Self {
+        let mut bytes = Vec::new();
+        for (kind, data) in chunks {
+            let mut crc = flate2::Crc::new();
+            crc.update(&kind.0);
+            crc.update(data);
+            bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&kind.0);
+            bytes.extend_from_slice(data);
+            bytes.extend_from_slice(&crc.sum().to_be_bytes());
+        }
+        Self {
+            inner,
+            chunks: bytes,
+            left: PNG_HEADER_SIZE,
+        }
+    }
+}
+
+impl<W: Write> Write for AfterHeader<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            return self.inner.write(buf);
+        }
+        let n = buf.len().min(self.left);
+        self.inner.write_all(&buf[..n])?;
+        self.left -= n;
+        if self.left == 0 {
+            self.inner.write_all(&std::mem::take(&mut self.chunks))?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Translates an nclx color profile into the contents of a PNG `cICP` chunk. Both use the code
+/// points of ITU-T H.273 for the primaries and transfer function, which libheif's enums hold as
+/// they are, while the matrix is the identity and the range full for RGB output. Profiles that
+/// leave either of the first two unspecified don't say enough to tag the output with.
+fn cicp(nclx: Option<heif::ColorProfileNCLX>) -> Option<[u8; 4]> {
+    let nclx = nclx?;
+    let primaries = nclx.color_primaries();
+    let transfer = nclx.transfer_characteristics();
+    if matches!(
+        primaries,
+        heif::ColorPrimaries::Unspecified | heif::ColorPrimaries::Unknown
+    ) || matches!(
+        transfer,
+        heif::TransferCharacteristics::Unspecified | heif::TransferCharacteristics::Unknown
+    ) {
+        return None;
+    }
+    Some([primaries as u8, transfer as u8, 0, 1])
+}
+
+/// Adds the name of `source` and of the directory it's in as text chunks, as Latin-1 `tEXt`
+/// chunks where they're ASCII and UTF-8 `iTXt` chunks otherwise. There's nothing to add for
+/// images converted from memory, which have no path.
+fn embed_source_name<W: Write>(encoder: &mut png::Encoder<W>, source: &Utf8Path) -> Result<()> {
+    let dir = source.parent().and_then(Utf8Path::file_name);
+    for (keyword, name) in [("Source", source.file_name()), ("Source Directory", dir)] {
+        let Some(name) = name else {
+            continue;
+        };
+        if name.is_ascii() {
+            encoder.add_text_chunk(keyword.to_string(), name.to_string())?;
+        } else {
+            encoder.add_itxt_chunk(keyword.to_string(), name.to_string())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A big-endian TIFF structure whose first IFD has a Make of `Abc` and a pointer to a GPS IFD
+    /// at 38, which has a LatitudeRef of `N` and a Latitude stored after it at 68.
+    fn located(gps: u32) -> Vec<u8> {
+        let mut data = b"MM\0\x2a\0\0\0\x08".to_vec();
+        data.extend([0, 2]);
+        data.extend([0x01, 0x0f, 0, 2, 0, 0, 0, 4, b'A', b'b', b'c', 0]);
+        data.extend([0x88, 0x25, 0, 4, 0, 0, 0, 1]);
+        data.extend(gps.to_be_bytes());
+        data.extend([0; 4]);
+        data.extend([0, 2]);
+        data.extend([0, 0x01, 0, 2, 0, 0, 0, 2, b'N', 0, 0, 0]);
+        data.extend([0, 0x02, 0, 5, 0, 0, 0, 3, 0, 0, 0, 68]);
+        data.extend([0; 4]);
+        data.extend((1..=24).collect::<Vec<u8>>());
+        assert_eq!(data.len(), 92);
+        data
+    }
+
+    fn gps_pointer(data: &[u8]) -> Option<usize> {
+        let tiff = Tiff {
+            data,
+            little_endian: false,
+        };
+        tiff.entry(8, 0x8825)
+    }
+
+    #[test]
+    fn scrubs_the_gps_ifd_and_its_values() {
+        let mut data = located(38);
+        assert!(scrub_gps(&mut data, false));
+        assert_eq!(gps_pointer(&data), None);
+        // the Make entry is still there, followed by an empty one and the next IFD offset
+        assert_eq!(&data[8..10], [0, 1]);
+        assert_eq!(&data[18..22], b"Abc\0");
+        assert!(data[22..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn leaves_blocks_without_gps_alone() {
+        let mut data = located(38);
+        assert!(scrub_gps(&mut data, false));
+        let scrubbed = data.clone();
+        assert!(!scrub_gps(&mut data, false));
+        assert_eq!(data, scrubbed);
+    }
+
+    #[test]
+    fn removes_every_gps_pointer() {
+        let mut data = located(38);
+        // the Make entry turned into a second pointer, to the same IFD
+        data[10..22].copy_from_slice(&[0x88, 0x25, 0, 4, 0, 0, 0, 1, 0, 0, 0, 38]);
+        assert!(scrub_gps(&mut data, false));
+        assert_eq!(&data[8..10], [0, 0]);
+        assert_eq!(gps_pointer(&data), None);
+    }
+
+    #[test]
+    fn keeps_the_header_and_first_ifd_from_a_stray_pointer() {
+        for gps in [0, 4, 8, 20] {
+            let mut data = located(gps);
+            assert!(scrub_gps(&mut data, false));
+            assert_eq!(&data[..8], b"MM\0\x2a\0\0\0\x08");
+            assert_eq!(&data[18..22], b"Abc\0");
+        }
+    }
+
+    #[test]
+    fn survives_truncated_and_damaged_blocks() {
+        let whole = located(38);
+        for len in 0..whole.len() {
+            scrub_gps(&mut whole[..len].to_vec(), false);
+        }
+        // every byte set to values that make counts and offsets huge or zero
+        for at in 0..whole.len() {
+            for value in [0, 1, 0x7f, 0xff] {
+                let mut data = whole.clone();
+                data[at] = value;
+                scrub_gps(&mut data, false);
+                scrub_gps(&mut data, true);
+                // nothing is written before the first IFD
+                assert_eq!(
+                    data[..8],
+                    {
+                        let mut header = whole[..8].to_vec();
+                        if at < 8 {
+                            header[at] = value;
+                        }
+                        header
+                    }[..]
+                );
+            }
+        }
+    }
+}
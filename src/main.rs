@@ -1,321 +1,3248 @@
+mod archive;
+mod autotune;
+mod compare;
+mod doctor;
+mod gallery;
+mod info;
+mod isolate;
+mod metrics;
+mod pick;
+mod pressure;
+mod render;
+
 use indexmap::IndexMap;
-use once_cell::sync::Lazy;
 use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
     env,
-    io::{self, BufWriter, Read, Write},
+    fs::FileType,
+    io::{self, Read, Write},
+    path::PathBuf,
     process,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
 };
 use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    runtime,
     sync::{
-        mpsc::{self, UnboundedReceiver, UnboundedSender},
-        Semaphore,
+        mpsc::{self, error::TrySendError, Receiver, Sender},
+        watch, Notify, Semaphore, SemaphorePermit,
     },
     task,
-    time::{self, Duration},
+    time::{self, Duration, Instant, MissedTickBehavior},
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 
-use termion::{event::Key, raw::IntoRawMode};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+use unicode_normalization::UnicodeNormalization;
+
+use sha2::{Digest, Sha256};
+use tracing::{error, info, instrument, warn, Span};
+use tracing_subscriber::EnvFilter;
+
+use mass_heif_convert::{
+    contact_sheet::{self, Thumbnail},
+    exif_burst, exif_month, explain_grid, heif_to_formats, libheif_cant_open, open_read_only,
+    options::{
+        self, ExtensionCase, Format, NormalizationForm, OnConflict, Options, Rule, Ui, Verify,
+        HEIF_EXTENSIONS,
+    },
+    output::{self, Storage},
+    read_heif, read_source, DECODE_BYTES_PER_PIXEL, HEIF, MAX_CODECS,
+};
+use metrics::METRICS;
+use render::{Bars, Compact, Plain, Quiet, Renderer, Tui};
+
+/// Finished files wait for the display to catch up once this many events are queued.
+const EVENT_QUEUE_SIZE: usize = 256;
+
+/// How often the display is redrawn between events, for anything it animates.
+const RENDER_TICK: Duration = Duration::from_millis(100);
+
+/// Manifest written to the output directory with `--checksum`.
+const CHECKSUMS_FILE: &str = "checksums.txt";
+
+/// Lists the sources finished so far, one per line, so that `--resume` can pick up an
+/// interrupted run. It's removed once a run completes.
+const CHECKPOINT_FILE: &str = ".mass-heif-progress";
+
+/// Locked for as long as a run is writing to the output directory, and holds its process id.
+const LOCK_FILE: &str = ".mass-heif-lock";
+
+/// Put in front of the name of a file converted with `--on-conflict smaller`, while it waits to
+/// be compared with the existing output. Being a dotfile, it's never taken for a source.
+const PARTIAL_PREFIX: &str = ".partial-";
+
+/// Directories of extra data in Apple Photos exports, skipped unless `--keep-misc` is given.
+const MISC_DIR: &str = ".MISC";
+
+/// Rough ratio of PNG to HEIC file size for photos, used to estimate the space a run needs.
+const PNG_SIZE_FACTOR: u64 = 5;
+
+/// The same for JPEGs, at the quality they're written at.
+const JPEG_SIZE_FACTOR: u64 = 2;
 
-use heif::{HeifContext, LibHeif};
-use libheif_rs as heif;
+/// The same for WebPs, which come out a little smaller than JPEGs.
+const WEBP_SIZE_FACTOR: u64 = 2;
 
-const MAX_FILE_HANDLES: usize = 10;
+const MIB: u64 = 1024 * 1024;
 
-static HEIF: Lazy<LibHeif> = Lazy::new(LibHeif::new);
+/// Files copied as-is that are at least this big are copied in chunks, reporting progress as
+/// they go, rather than in one opaque call.
+const STREAM_COPY_THRESHOLD: u64 = 64 * MIB;
+const COPY_CHUNK_SIZE: usize = 4 * MIB as usize;
+
+/// Extensions of the files copied into `images` with `--sort-by-type`, in lowercase, besides the
+/// converted ones.
+const IMAGE_EXTENSIONS: &[&str] = &[
+    "heic", "heics", "heif", "hif", "avif", "jpg", "jpeg", "png", "gif", "tif", "tiff", "webp",
+    "dng",
+];
+/// Extensions of the videos given a poster with `--video-poster`, and sorted into `videos` with
+/// `--sort-by-type`, in lowercase.
+const VIDEO_EXTENSIONS: &[&str] = &["mov", "mp4", "m4v", "avi", "mkv", "3gp"];
+/// Set once ffmpeg turns out to be missing, so that's only warned about once.
+static NO_FFMPEG: AtomicBool = AtomicBool::new(false);
+
+/// Called as a file moves along, returning false if the work should be abandoned.
+type ProgressFn<'a> = &'a (dyn Fn(FileProgress) -> bool + Send + Sync);
+
+/// Steps within a single file, reported while it's being processed.
+#[derive(Clone, Copy, Debug)]
+enum FileProgress {
+    /// The file's turn to be decoded has come, after waiting for the decode limits.
+    Decoding,
+    /// Bytes copied so far out of the total size, while a large file is copied.
+    Copied(u64, u64),
+    /// Bytes of pixels encoded so far out of all of them, while an image is encoded.
+    Encoded(u64, u64),
+}
 
 #[derive(Clone, Debug)]
 enum Event {
-    Progress { id: usize, file: Utf8PathBuf },
-    Err { id: usize, err: String },
+    Progress {
+        id: usize,
+        file: Utf8PathBuf,
+        bytes: u64,
+        processed: Processed,
+        elapsed: Duration,
+    },
+    Err {
+        id: usize,
+        file: Utf8PathBuf,
+        err: String,
+        /// The output can't be written to at all, so there's no point carrying on.
+        fatal: bool,
+        /// What was being done with the file, or `None` if it failed before it was planned.
+        action: Option<Action>,
+        /// Size of the file, as far as it could be read.
+        bytes: u64,
+        elapsed: Duration,
+    },
+    /// A file wasn't processed, as its directory was aborted with `--stop-directory-on-error` or
+    /// its hash is in `--skip-hashes`.
+    Skipped {
+        id: usize,
+        reason: Skip,
+    },
+    /// Stop starting new files until the matching `Resume`.
+    Pause,
+    Resume,
+    /// A file has started decoding.
+    Started {
+        id: usize,
+        file: Utf8PathBuf,
+    },
+    /// Part of a decoded image has been encoded.
+    Encoding {
+        id: usize,
+        file: Utf8PathBuf,
+        encoded: u64,
+        total: u64,
+    },
+    /// Part of a large file has been copied.
+    Copying {
+        id: usize,
+        file: Utf8PathBuf,
+        copied: u64,
+        size: u64,
+    },
+    /// More files were listed in a directory with `--stream`, besides those in `finished` that
+    /// need nothing done.
+    Found {
+        id: usize,
+        files: usize,
+        finished: BTreeMap<Skip, usize>,
+    },
+    /// A directory has been listed in full with `--stream`.
+    Listed {
+        id: usize,
+    },
+    /// Scroll the directory list by this many lines.
+    Scroll(isize),
+    /// Switch between showing every directory and only those with errors.
+    ToggleErrorsOnly,
     Quit,
 }
 
 #[derive(Clone, Debug)]
 struct Entry {
     name: String,
+    source: Utf8PathBuf,
+    /// Path of `source` relative to the input it was found in.
+    relative: Utf8PathBuf,
+    output: Utf8PathBuf,
     last_file: Option<Utf8PathBuf>,
-    last_err: Option<String>,
+    /// Bytes copied so far and the size of `last_file`, while it's a large file being copied.
+    copying: Option<(u64, u64)>,
+    /// The file that most recently started decoding, until it's done.
+    decoding: Option<Utf8PathBuf>,
+    /// Bytes of pixels encoded so far and in all, once `decoding` is being encoded.
+    encoding: Option<(u64, u64)>,
 
     total: usize,
     completed: usize,
+    /// Files decoded and written as PNGs, out of `completed`.
+    converted: usize,
+    /// Files copied or linked as-is, out of `completed`.
+    copied: usize,
+    /// Files that couldn't be processed with their errors, in the order they failed. They don't
+    /// count towards `completed`.
+    errors: Vec<(Utf8PathBuf, String)>,
+    /// Files left unprocessed after one failed with `--stop-directory-on-error` or for having a
+    /// hash in `--skip-hashes`.
+    skipped: usize,
+    /// Files left alone, by why. Those left alone while planning count towards `completed` and
+    /// the rest towards `skipped`.
+    skips: BTreeMap<Skip, usize>,
+    /// Converted files that don't match their counterparts under `--compare`, and how.
+    differences: Vec<(Utf8PathBuf, String)>,
+    /// Thumbnails of the converted files so far, with `--contact-sheet`.
+    thumbnails: Vec<Thumbnail>,
+    /// Directories made for `output` by this run, which may include its parents.
+    created_dirs: Vec<Utf8PathBuf>,
+    /// Set while the directory is still being listed with `--stream`, so `total` may grow.
+    listing: bool,
+}
+
+impl Entry {
+    /// Whether every file has been accounted for, which empty directories are from the start.
+    fn finished(&self) -> bool {
+        !self.listing && self.completed + self.errors.len() + self.skipped == self.total
+    }
+}
+
+/// The outcome of processing a single file.
+#[derive(Clone, Debug)]
+struct Processed {
+    /// What was actually done with the file, which is `Copy` for conversions that fell back to
+    /// copying.
+    action: Action,
+    /// SHA-256 of the source file as hex, with `--checksum`.
+    checksum: Option<String>,
+    /// A small copy of the converted image, with `--contact-sheet`.
+    thumbnail: Option<Thumbnail>,
+    /// How the converted image differs from its counterpart under `--compare`, if it does.
+    difference: Option<String>,
+    /// Size of everything written for the file.
+    output_bytes: u64,
+}
+
+/// What to do with a file found in one of the input directories.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Action {
+    /// Decode the image and write it out as a PNG.
+    Convert,
+    Copy,
+    /// Create a symlink in the output with the same target, which is left unchanged even if it's
+    /// a relative path.
+    Symlink,
+}
+
+/// Why a file was left alone, in the order they're summed up at the end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Skip {
+    /// Its output exists, with `--on-conflict skip`.
+    Exists,
+    /// Its output is newer than it, with `--update`.
+    UpToDate,
+    /// The run being resumed finished it, with `--resume`.
+    Resumed,
+    /// Its hash is in `--skip-hashes`.
+    KnownHash,
+    /// It isn't converted, with `--images-only`.
+    NotImage,
+    /// Its extension's rule is to skip it, with `--rules`.
+    Ruled,
+    /// Another file in its directory failed, with `--stop-directory-on-error`.
+    Aborted,
+}
+
+impl Skip {
+    fn reason(self) -> &'static str {
+        match self {
+            Skip::Exists => "already exists",
+            Skip::UpToDate => "up to date",
+            Skip::Resumed => "done by the resumed run",
+            Skip::KnownHash => "known hash",
+            Skip::NotImage => "not an image",
+            Skip::Ruled => "left out by --rules",
+            Skip::Aborted => "directory stopped",
+        }
+    }
+}
+
+/// Bounds on the work in progress across all directories.
+struct Limits {
+    /// Decodes are bounded separately from open files, since every one in flight holds a
+    /// full-resolution image in memory.
+    decodes: Semaphore,
+    /// Estimated memory held by decodes in flight, in MiB, with `--max-memory`.
+    memory: Option<Semaphore>,
+    /// Whether measured memory use is over `--memory-high-water`, which holds off new decodes.
+    high_water: Option<watch::Receiver<bool>>,
+}
+
+impl Limits {
+    /// Waits for a turn to decode, which is held for as long as the returned one is.
+    async fn admit_decode(&self, opts: &Options) -> Result<DecodeTurn<'_>> {
+        let permit = self.decodes.acquire().await?;
+        METRICS.decoding.fetch_add(1, Ordering::Relaxed);
+        let turn = DecodeTurn { _permit: permit };
+        // memory that's in use with no other decodes going can't be waited out, so one always goes
+        if let Some(high_water) = &self.high_water {
+            while *high_water.borrow()
+                && self.decodes.available_permits() + 1 < opts.decode_jobs.get()
+            {
+                time::sleep(pressure::SAMPLE_INTERVAL).await;
+            }
+        }
+        Ok(turn)
+    }
+}
+
+/// One of the `--decode-jobs` turns, given back when it's dropped.
+struct DecodeTurn<'a> {
+    _permit: SemaphorePermit<'a>,
+}
+
+impl Drop for DecodeTurn<'_> {
+    fn drop(&mut self) {
+        METRICS.decoding.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Totals across all entries.
+#[derive(Clone, Debug)]
+struct Stats {
+    started: Instant,
+    completed: usize,
+    /// Completed files that were converted rather than copied.
+    converted: usize,
+    /// Size of the completed source files.
+    bytes: u64,
+    errors: usize,
+    /// Files that came out different from `--compare`.
+    differences: usize,
+    paused: bool,
+    /// Every file that failed and why, by directory, gathered once the run is over.
+    failures: Vec<(Utf8PathBuf, String)>,
+}
+
+fn main() -> Result<()> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some(isolate::DECODE_ONE) {
+        isolate::decode_one(args);
+    }
+    if args.is_empty() {
+        eprintln!("{}\nrun with --help for a list of options", options::USAGE);
+        process::exit(2);
+    }
+
+    let opts = match Options::parse(args) {
+        Ok(opts) => Arc::new(opts),
+        Err(err) => {
+            eprintln!("error: {:#}\n{}", err, options::USAGE);
+            process::exit(2);
+        }
+    };
+    if opts.help {
+        print!("{}", options::help());
+        process::exit(0);
+    } else if opts.version {
+        print!("{}", version());
+        process::exit(0);
+    }
+
+    // built by hand rather than with `#[tokio::main]`, which can't be told how many workers
+    let mut runtime = runtime::Builder::new_multi_thread();
+    if let Some(threads) = opts.runtime_threads {
+        runtime.worker_threads(threads.get());
+    }
+    runtime
+        .enable_all()
+        .build()
+        .context("failed to start the runtime")?
+        .block_on(run(opts))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut args = env::args().map(Utf8PathBuf::from).skip(1);
-    let input = args
-        .next()
-        .with_context(|| "missing input directory argument")?;
-    let output = args
-        .next()
-        .with_context(|| "missing output directory argument")?;
-
-    if !output.exists() {
-        std::fs::create_dir(&output)?;
-    } else if output.read_dir()?.next().is_some() {
-        println!("warning: '{}' is not empty", output);
-        if !confirm("continue?") {
+/// Everything after the options are parsed, on the runtime they asked for.
+async fn run(mut opts: Arc<Options>) -> Result<()> {
+    if opts.print_config {
+        print!("{}", opts.to_json());
+        process::exit(0);
+    }
+    if opts.doctor {
+        process::exit(if doctor::run(&opts) { 0 } else { 1 });
+    }
+    if opts.info {
+        process::exit(match info::run(&opts) {
+            Ok(ok) => i32::from(!ok),
+            Err(err) => {
+                eprintln!("error: {:#}", err);
+                1
+            }
+        });
+    }
+    if let Err(err) = init_logging(&opts) {
+        eprintln!("error: {:#}", err);
+        process::exit(2);
+    }
+    if let Some(decoder) = &opts.decoder {
+        let available: Vec<_> = HEIF
+            .decoder_descriptors(MAX_CODECS, None)
+            .iter()
+            .map(|decoder| decoder.id().to_string())
+            .collect();
+        if !available.contains(decoder) {
+            eprintln!(
+                "error: invalid value '{}' for '--decoder', expected one of: {}\n{}",
+                decoder,
+                available.join(", "),
+                options::USAGE
+            );
+            process::exit(2);
+        }
+    }
+    let storage: Arc<dyn Storage> = match output::storage(&opts.output) {
+        Ok(storage) => storage.into(),
+        Err(err) => {
+            eprintln!("error: {:#}\n{}", err, options::USAGE);
+            process::exit(2);
+        }
+    };
+
+    let output = &opts.output;
+
+    // a run writes a tree of files, which can't be streamed through a single pipe
+    if let Some(kind) = output
+        .metadata()
+        .ok()
+        .and_then(|meta| output::special_kind(meta.file_type()))
+    {
+        eprintln!(
+            "error: '{}' is {}, but OUTPUT_DIR has to be a directory to write files into\n{}",
+            output,
+            kind,
+            options::USAGE
+        );
+        process::exit(2);
+    }
+
+    // fine if it's already there, including when another run makes it at the same moment
+    std::fs::create_dir_all(output).with_context(|| format!("failed to create '{}'", output))?;
+
+    // released by the OS when the process exits, so an interrupted run can't leave it behind
+    #[cfg(unix)]
+    let _lock = match lock_output(output)? {
+        Ok(lock) => Some(lock),
+        Err(_) if opts.force => None,
+        Err(pid) => {
+            eprintln!(
+                "error: another run{} is writing to '{}', run with --force if that's intended",
+                pid.map(|pid| format!(" (pid {})", pid)).unwrap_or_default(),
+                output
+            );
             process::exit(1);
         }
+    };
+
+    let archives = opts
+        .inputs
+        .iter()
+        .filter(|input| archive::is_archive(input))
+        .count();
+    if archives > 0 {
+        if archives < opts.inputs.len() {
+            eprintln!(
+                "error: archives and directories can't be converted in the same run\n{}",
+                options::USAGE
+            );
+            process::exit(2);
+        }
+        if opts.on_conflict == OnConflict::Smaller {
+            eprintln!(
+                "error: '--on-conflict smaller' isn't supported for archives\n{}",
+                options::USAGE
+            );
+            process::exit(2);
+        }
+        if opts.pick {
+            eprintln!(
+                "error: '--pick' isn't supported for archives\n{}",
+                options::USAGE
+            );
+            process::exit(2);
+        }
+        match archive::run(&*storage, &opts) {
+            Ok(stats) => end_run(i32::from(stats.errors > 0), &stats, &opts),
+            Err(err) => {
+                eprintln!("error: {:#}", err);
+                process::exit(1);
+            }
+        }
     }
 
-    let (tx, rx) = mpsc::unbounded_channel::<Event>();
+    // anything wrong with the inputs themselves stops the run before it starts, while files that
+    // can't be planned fail on their own once it's underway
+    let mut groups = match plan_groups(&opts) {
+        Ok(groups) => groups,
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            process::exit(1);
+        }
+    };
 
-    let entries = spawn_file_processors(tx.clone(), &input, &output)?;
+    // an empty run is more often a wrong path than nothing left to do, so it gets its own status,
+    // where copies only count with `--copy-only`. With `--stream` nothing has been listed yet
+    let mut convertible = 0;
+    for input in opts.inputs.iter().filter(|_| !opts.stream) {
+        let groups = groups
+            .iter()
+            .filter(|group| group.source.starts_with(input));
+        let (jobs, finished) = groups.fold((0, 0), |(jobs, finished), group| {
+            let converts = group
+                .jobs
+                .iter()
+                .filter(|job| job.action == Action::Convert || opts.copy_only);
+            (
+                jobs + converts.count(),
+                finished + group.skips.values().sum::<usize>(),
+            )
+        });
+        if jobs == 0 {
+            let done = if finished > 0 {
+                format!(", {} already done", finished)
+            } else {
+                String::new()
+            };
+            let what = if opts.copy_only { "copy" } else { "convert" };
+            eprintln!("warning: no files to {} in '{}'{}", what, input, done);
+        }
+        convertible += jobs;
+    }
+    // files that couldn't be planned still have to be reported as failing
+    let unplanned = groups.iter().any(|group| !group.errors.is_empty());
+    if convertible == 0 && !unplanned && !opts.stream {
+        process::exit(4);
+    }
 
-    task::spawn(async move {
-        let mut stdin = termion::async_stdin().bytes();
-        loop {
-            if let Some(byte) = stdin.next() {
-                let event = termion::event::parse_event(byte.unwrap(), &mut stdin).unwrap();
-                if matches!(event, termion::event::Event::Key(Key::Ctrl('c'))) {
-                    tx.send(Event::Quit).unwrap();
-                    break;
-                }
+    // after the check for empty inputs, which is about what's there rather than what's picked
+    if opts.pick {
+        match pick::run(&groups) {
+            Ok(Some(picked)) => {
+                let mut picked = picked.into_iter();
+                groups.retain(|_| picked.next().unwrap_or(false));
+            }
+            Ok(None) => process::exit(1),
+            Err(err) => {
+                eprintln!("error: {:#}", err);
+                process::exit(1);
             }
-            time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    // put in place once nothing else is borrowing the options
+    let tuned = opts.autotune.then(|| match autotune::run(&groups, &opts) {
+        Ok(decode_jobs) => decode_jobs,
+        Err(err) => {
+            eprintln!("error: {:#}", err);
+            process::exit(1);
         }
     });
+    if opts.autotune_only {
+        process::exit(0);
+    }
 
-    let mut stdout = io::stdout().into_raw_mode()?;
-    write!(&mut stdout, "{}", termion::cursor::Hide)?;
+    #[cfg(unix)]
+    if !opts.ignore_space && !opts.stream {
+        let needed = estimate_space(&groups, &opts);
+        let available = available_space(output)?;
+        if needed > available {
+            eprintln!(
+                "error: the output needs about {} MB but only {} MB is free, \
+                 run with --ignore-space to convert anyway",
+                needed / 1_000_000,
+                available / 1_000_000
+            );
+            process::exit(1);
+        }
+    }
 
-    let status = event_loop(rx, entries, &mut stdout).await?;
+    // merging into an existing output is fine as long as nothing in it gets replaced, and with
+    // `--update` replacing stale outputs is the point
+    let collisions = groups
+        .iter()
+        .flat_map(|group| &group.jobs)
+        .filter(|job| job.dest.symlink_metadata().is_ok())
+        .count();
+    if collisions > 0 && !opts.update && opts.on_conflict == OnConflict::Ask {
+        eprintln!(
+            "warning: {} files in '{}' would be overwritten",
+            collisions, output
+        );
+        if !confirm("continue?", &opts) {
+            process::exit(1);
+        }
+    }
 
-    write!(&mut stdout, "{}", termion::cursor::Show)?;
-    process::exit(status)
-}
+    if let Some(decode_jobs) = tuned {
+        Arc::make_mut(&mut opts).decode_jobs = decode_jobs;
+    }
 
-async fn event_loop<W: Write>(
-    mut rx: UnboundedReceiver<Event>,
-    mut entries: IndexMap<usize, Entry>,
-    mut stdout: W,
-) -> Result<i32> {
-    let mut progress = entries.values().filter(|entry| entry.total > 0).count();
+    if let Some(addr) = opts.metrics_addr {
+        metrics::serve(addr).await?;
+    }
 
-    write!(&mut stdout, "{}", vec!["\n\r"; progress].join(""))?;
+    let (tx, rx) = mpsc::channel::<Event>(EVENT_QUEUE_SIZE);
+    let (pause_tx, pause_rx) = watch::channel(false);
 
-    loop {
-        let event = rx.recv().await.with_context(|| "event receiver closed")?;
+    let entries = spawn_file_processors(tx.clone(), pause_rx, groups, storage, &opts)?;
 
-        let mut quit = false;
+    if opts.quiet {
+        let (status, stats) = event_loop(rx, entries, &opts, &pause_tx, &mut Quiet).await?;
+        end_run(status, &stats, &opts)
+    }
 
-        match event.clone() {
-            Event::Progress { id, file } => {
-                let entry = entries.get_mut(&id).unwrap();
-                entry.last_file = Some(file);
-                entry.last_err = None;
+    // piped output can't be put in raw mode, nor would the other displays make sense in it
+    let mut stdout = match io::stdout().into_raw_mode() {
+        Ok(stdout) => stdout,
+        Err(err) => {
+            eprintln!(
+                "can't use the terminal ({}), so progress is printed as plain lines",
+                err
+            );
+            let (status, stats) =
+                event_loop(rx, entries, &opts, &pause_tx, &mut Plain::default()).await?;
+            end_run(status, &stats, &opts)
+        }
+    };
 
-                entry.completed += 1;
-                if entry.completed == entry.total {
-                    progress -= 1;
-                    entry.last_file = None;
+    // a blocking read on its own thread, which only wakes up when a key is pressed
+    task::spawn_blocking(move || {
+        let mut paused = false;
+        for key in io::stdin().keys() {
+            let event = match key {
+                Ok(Key::Ctrl('c')) => Event::Quit,
+                Ok(Key::Up | Key::Char('k')) => Event::Scroll(-1),
+                Ok(Key::Down | Key::Char('j')) => Event::Scroll(1),
+                Ok(Key::Char('e')) => Event::ToggleErrorsOnly,
+                Ok(Key::Char('p')) => {
+                    paused = !paused;
+                    if paused {
+                        Event::Pause
+                    } else {
+                        Event::Resume
+                    }
                 }
-            }
-            Event::Quit => {
-                quit = true;
-            }
-            Event::Err { id, err } => {
-                let entry = entries.get_mut(&id).unwrap();
-                entry.last_err = Some(err);
-                quit = true;
+                _ => continue,
+            };
+            let quit = matches!(event, Event::Quit);
+            if tx.blocking_send(event).is_err() || quit {
+                break;
             }
         }
+    });
+
+    write!(&mut stdout, "{}", termion::cursor::Hide)?;
+
+    let mut renderer: Box<dyn Renderer + '_> = match opts.ui {
+        Ui::Tui => Box::new(Tui::new(&mut stdout)),
+        Ui::Compact => Box::new(Compact::new(&mut stdout)),
+        Ui::Bars => Box::new(Bars::default()),
+    };
+    let (status, stats) = event_loop(rx, entries, &opts, &pause_tx, &mut *renderer).await?;
+    drop(renderer);
+
+    write!(&mut stdout, "{}", termion::cursor::Show)?;
+    // out of raw mode, so the failures come out as ordinary lines
+    drop(stdout);
+    end_run(status, &stats, &opts)
+}
 
-        render_update(&mut stdout, &entries.values().collect::<Vec<_>>())?;
+/// Exits with `status` once every failure has been listed on stderr as a `path<TAB>error` line,
+/// for scripts to pick up, and the run has been notified with `--notify` and `--on-complete`.
+fn end_run(status: i32, stats: &Stats, opts: &Options) -> ! {
+    // a tab or line break in either would throw off the columns
+    let field = |text: &str| text.replace(['\t', '\n', '\r'], " ");
+    let mut stderr = io::stderr().lock();
+    for (file, err) in &stats.failures {
+        writeln!(stderr, "{}\t{}", field(file.as_str()), field(err)).ok();
+    }
+    if status == 6 {
+        writeln!(
+            stderr,
+            "time limit reached after {} files, the rest are left for the next run",
+            stats.completed
+        )
+        .ok();
+    }
+    drop(stderr);
 
-        if quit {
-            break Ok(1);
-        } else if progress == 0 {
-            break Ok(0);
+    // before `--on-complete`, which may well be what serves the pages
+    let status = match opts.gallery.then(|| gallery::write(&opts.output)) {
+        Some(Err(err)) => {
+            eprintln!("error: failed to write the gallery: {:#}", err);
+            status.max(1)
+        }
+        Some(Ok(pages)) => {
+            info!("wrote {} gallery pages", pages);
+            status
         }
+        None => status,
+    };
+    if opts.notify {
+        notify(status, stats);
     }
+    if let Some(command) = &opts.on_complete {
+        run_on_complete(command, status, stats);
+    }
+    process::exit(status)
 }
 
-fn render_update<W: Write>(stdout: W, entries: &[&Entry]) -> Result<()> {
-    let buf = &mut BufWriter::new(stdout);
-    write!(buf, "{}", termion::cursor::Up(entries.len() as u16))?;
+/// Runs the `--on-complete` command and waits for it, passing the run's status and totals in
+/// the environment. Its failure is only warned about, since the run itself is over.
+fn run_on_complete(command: &[String], status: i32, stats: &Stats) {
+    let result = process::Command::new(&command[0])
+        .args(&command[1..])
+        .env("MASS_HEIF_STATUS", status.to_string())
+        .env("MASS_HEIF_COMPLETED", stats.completed.to_string())
+        .env("MASS_HEIF_FAILED", stats.errors.to_string())
+        .env("MASS_HEIF_BYTES", stats.bytes.to_string())
+        .env(
+            "MASS_HEIF_ELAPSED",
+            format!("{:.1}", stats.started.elapsed().as_secs_f64()),
+        )
+        .status();
+    match result {
+        Ok(exit) if exit.success() => {}
+        Ok(exit) => eprintln!("warning: '{}' failed with {}", command[0], exit),
+        Err(err) => eprintln!("warning: failed to run '{}': {}", command[0], err),
+    }
+}
 
-    for entry in entries {
-        let color = if entry.last_err.is_some() {
-            termion::color::Red.fg_str()
-        } else if entry.completed == entry.total {
-            termion::color::LightGreen.fg_str()
-        } else {
-            termion::color::LightBlue.fg_str()
-        };
+/// Describes this build and the libheif it's linked against, including which codecs are available
+/// to it.
+fn version() -> String {
+    let [major, minor, patch] = HEIF.version();
+    let mut version = format!(
+        "{} {}\nlibheif {}.{}.{}\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION"),
+        major,
+        minor,
+        patch
+    );
 
-        let last_file = entry
-            .last_file
-            .as_ref()
-            .map(|file| format!(" [{}]", file))
-            .unwrap_or_default();
-        let last_err = entry.last_err.as_deref().unwrap_or_default();
-
-        write!(
-            buf,
-            "{}{}{} | {:04}/{:04} {} {}\r\n",
-            termion::clear::CurrentLine,
-            color,
-            entry.name,
-            entry.completed,
-            entry.total,
-            last_file,
-            last_err
-        )?;
+    // libheif-rs allocates room for as many codecs as are asked for, so the limit is kept small
+    version += "decoders:\n";
+    for decoder in HEIF.decoder_descriptors(MAX_CODECS, None) {
+        version += &format!("  {:<12}  {}\n", decoder.id(), decoder.name());
     }
+    version += "encoders:\n";
+    for encoder in HEIF.encoder_descriptors(MAX_CODECS, None, None) {
+        version += &format!("  {:<12}  {}\n", encoder.id(), encoder.name());
+    }
+    version
+}
 
+/// Sends log messages to `--log-file`, keeping those allowed by `--log-level` or else `RUST_LOG`,
+/// and info and above if neither is set.
+fn init_logging(opts: &Options) -> Result<()> {
+    let filter = match &opts.log_level {
+        Some(level) => EnvFilter::try_new(level)
+            .with_context(|| format!("invalid value '{}' for '--log-level'", level))?,
+        None => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+    };
+
+    let logger = tracing_subscriber::fmt().with_env_filter(filter);
+    if opts.log_file == "-" {
+        logger.with_writer(io::stderr).init();
+    } else {
+        let file = std::fs::File::options()
+            .append(true)
+            .create(true)
+            .open(&opts.log_file)
+            .with_context(|| format!("failed to open '{}'", opts.log_file))?;
+        logger.with_ansi(false).with_writer(Mutex::new(file)).init();
+    }
     Ok(())
 }
 
-fn spawn_file_processors(
-    tx: UnboundedSender<Event>,
-    input: &Utf8Path,
-    output: &Utf8Path,
-) -> Result<IndexMap<usize, Entry>> {
-    let mut entries = IndexMap::new();
+async fn event_loop(
+    mut rx: Receiver<Event>,
+    mut entries: IndexMap<usize, Entry>,
+    opts: &Options,
+    paused: &watch::Sender<bool>,
+    renderer: &mut dyn Renderer,
+) -> Result<(i32, Stats)> {
+    let mut progress = entries.values().filter(|entry| !entry.finished()).count();
+    let files: usize = entries.values().map(|entry| entry.total).sum();
+    METRICS.files.store(files as u64, Ordering::Relaxed);
 
-    for (id, dir) in input.read_dir_utf8()?.enumerate() {
-        let dir_path = dir?.into_path();
-        let dir_name = dir_path.file_name().unwrap().to_string();
-        if dir_name == ".MISC" {
-            continue;
-        }
+    let mut stats = Stats {
+        started: Instant::now(),
+        completed: 0,
+        converted: 0,
+        bytes: 0,
+        errors: 0,
+        differences: 0,
+        paused: false,
+        failures: Vec::new(),
+    };
+    let mut checksums = Vec::new();
+    let mut rows = Vec::new();
 
-        let output = output.join(&dir_name);
-        if !output.exists() {
-            std::fs::create_dir(&output)?;
-        }
+    // directories with nothing left to do, such as empty ones, get no events to finish them on
+    for entry in entries.values_mut().filter(|entry| entry.finished()) {
+        finish_entry(entry, &mut stats, renderer, opts)?;
+    }
 
-        let semaphore = Arc::new(Semaphore::new(MAX_FILE_HANDLES));
+    let checkpoint_path = opts.output.join(CHECKPOINT_FILE);
+    let mut checkpoint = std::fs::File::options()
+        .create(true)
+        .write(true)
+        .append(opts.resume)
+        .truncate(!opts.resume)
+        .open(&checkpoint_path)
+        .with_context(|| format!("failed to open '{}'", checkpoint_path))?;
 
-        let mut total = 0;
-        for file in dir_path.read_dir_utf8()? {
-            total += 1;
+    // draw directories resumed from an earlier run before anything new finishes
+    renderer.start(&entries.values().collect::<Vec<_>>())?;
+    renderer.update(&entries.values().collect::<Vec<_>>(), &stats)?;
 
-            let source = file?.into_path();
-            let file_name = source.file_name().unwrap();
-            let ext = source.extension();
+    let deadline = opts.max_runtime.map(|limit| stats.started + limit);
+    let time_limit = async {
+        match deadline {
+            Some(deadline) => time::sleep_until(deadline).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::pin!(time_limit);
+    // once the time limit is reached, the files already started are waited for, since exiting
+    // under them would leave truncated output that a resumed run takes as done
+    let mut stopping = false;
+    let mut ticks = time::interval(RENDER_TICK);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
-            let dest = if ext == Some("HEIC") {
-                output.join(file_name).with_extension("PNG")
+    let status = loop {
+        if stopping && METRICS.in_flight.load(Ordering::SeqCst) == 0 {
+            // stopped like Ctrl-C, under a status of its own
+            break 6;
+        }
+        if progress == 0 {
+            break if stats.errors > 0 {
+                1
+            } else if stats.differences > 0 {
+                // a regression rather than a failure, so it's told apart
+                5
             } else {
-                output.join(file_name)
+                0
             };
+        }
+        let event = tokio::select! {
+            event = rx.recv() => event.with_context(|| "event receiver closed")?,
+            _ = ticks.tick() => {
+                if renderer.tick() {
+                    renderer.update(&entries.values().collect::<Vec<_>>(), &stats)?;
+                }
+                continue;
+            }
+            () = &mut time_limit, if !stopping => {
+                warn!("stopping, the run has reached its --max-runtime");
+                stopping = true;
+                paused.send_replace(true);
+                continue;
+            }
+        };
 
-            let semaphore = semaphore.clone();
-            let tx = tx.clone();
-            task::spawn(async move {
-                let permit = semaphore.acquire().await.unwrap();
-                match process_file(&source, &dest).await {
-                    Ok(()) => {
-                        tx.send(Event::Progress {
-                            id,
-                            file: source.clone(),
-                        })
-                        .unwrap();
-                    }
-                    Err(err) => {
-                        tx.send(Event::Err {
-                            id,
-                            err: format!("{:#}", err),
-                        })
-                        .unwrap();
-                    }
+        let mut quit = None;
+
+        match event.clone() {
+            Event::Progress {
+                id,
+                file,
+                bytes,
+                processed,
+                elapsed,
+            } => {
+                if opts.csv.is_some() {
+                    rows.push(CsvRow {
+                        path: file.clone(),
+                        action: Some(processed.action),
+                        input_bytes: bytes,
+                        output_bytes: processed.output_bytes,
+                        elapsed,
+                        error: None,
+                    });
                 }
-                drop(permit);
-            });
-        }
+                stats.completed += 1;
+                stats.bytes += bytes;
+                METRICS.bytes.fetch_add(bytes, Ordering::Relaxed);
 
-        entries.insert(
-            id,
-            Entry {
-                name: dir_name,
-                last_file: None,
-                last_err: None,
+                writeln!(checkpoint, "{}", file)?;
+
+                let entry = entries.get_mut(&id).unwrap();
+                if let Some(checksum) = processed.checksum {
+                    checksums.push((entry.relative.join(file.file_name().unwrap()), checksum));
+                }
+                if entry.decoding.as_ref() == Some(&file) {
+                    entry.decoding = None;
+                    entry.encoding = None;
+                }
+                if let Some(difference) = processed.difference {
+                    warn!("{}: {}", file, difference);
+                    stats.differences += 1;
+                    entry.differences.push((file.clone(), difference));
+                }
+                entry.last_file = Some(file);
+                entry.copying = None;
 
+                entry.completed += 1;
+                if processed.action == Action::Convert {
+                    entry.converted += 1;
+                    stats.converted += 1;
+                    METRICS.converted.fetch_add(1, Ordering::Relaxed);
+                } else {
+                    entry.copied += 1;
+                    METRICS.copied.fetch_add(1, Ordering::Relaxed);
+                }
+                entry.thumbnails.extend(processed.thumbnail);
+            }
+            // a resume would start new files while stopping
+            Event::Pause | Event::Resume if stopping => {}
+            Event::Pause | Event::Resume => {
+                stats.paused = matches!(event, Event::Pause);
+                paused.send_replace(stats.paused);
+            }
+            Event::Started { id, file } => {
+                let entry = entries.get_mut(&id).unwrap();
+                entry.decoding = Some(file);
+                entry.encoding = None;
+            }
+            Event::Encoding {
+                id,
+                file,
+                encoded,
                 total,
-                completed: 0,
-            },
-        );
-    }
+            } => {
+                // only the file shown is tracked, when several are being converted
+                let entry = entries.get_mut(&id).unwrap();
+                if entry.decoding.as_ref() == Some(&file) {
+                    entry.encoding = Some((encoded, total));
+                }
+            }
+            Event::Copying {
+                id,
+                file,
+                copied,
+                size,
+            } => {
+                let entry = entries.get_mut(&id).unwrap();
+                entry.last_file = Some(file);
+                entry.copying = Some((copied, size));
+            }
+            Event::Skipped { id, reason } => {
+                let entry = entries.get_mut(&id).unwrap();
+                entry.skipped += 1;
+                *entry.skips.entry(reason).or_default() += 1;
+                METRICS.skipped.fetch_add(1, Ordering::Relaxed);
+            }
+            Event::Found {
+                id,
+                files,
+                finished,
+            } => {
+                let entry = entries.get_mut(&id).unwrap();
+                let finished_files: usize = finished.values().sum();
+                entry.total += files + finished_files;
+                entry.completed += finished_files;
+                for (reason, count) in finished {
+                    *entry.skips.entry(reason).or_default() += count;
+                }
+                METRICS
+                    .files
+                    .fetch_add((files + finished_files) as u64, Ordering::Relaxed);
+            }
+            Event::Listed { id } => {
+                entries.get_mut(&id).unwrap().listing = false;
+            }
+            Event::Scroll(delta) => renderer.scroll(delta),
+            Event::ToggleErrorsOnly => renderer.toggle_errors_only(),
+            Event::Quit => {
+                quit = Some(1);
+            }
+            Event::Err {
+                id,
+                file,
+                err,
+                fatal,
+                action,
+                bytes,
+                elapsed,
+            } => {
+                if opts.csv.is_some() {
+                    rows.push(CsvRow {
+                        path: file.clone(),
+                        action,
+                        input_bytes: bytes,
+                        output_bytes: 0,
+                        elapsed,
+                        error: Some(err.clone()),
+                    });
+                }
+                stats.errors += 1;
+                METRICS.failed.fetch_add(1, Ordering::Relaxed);
+                error!("{}: {}", file, err);
+                renderer.error(&file, &err)?;
+                let entry = entries.get_mut(&id).unwrap();
+                if entry.decoding.as_ref() == Some(&file) {
+                    entry.decoding = None;
+                    entry.encoding = None;
+                }
+                entry.errors.push((file, err));
+                entry.copying = None;
 
-    Ok(entries)
-}
+                quit = match opts.max_errors {
+                    _ if fatal => Some(1),
+                    // a distinct status, since this usually means something is wrong with every file
+                    Some(max) if stats.errors > max => Some(3),
+                    Some(_) => None,
+                    None if opts.keep_going || opts.stop_directory_on_error => None,
+                    None => Some(1),
+                };
+            }
+        }
 
-async fn process_file(source: &Utf8Path, dest: &Utf8Path) -> Result<()> {
-    if source.extension() != Some("HEIC") {
-        tokio::fs::copy(source, dest).await?;
-    } else {
-        let source = source.to_owned();
-        let dest = dest.to_owned();
+        if let Event::Progress { id, .. }
+        | Event::Err { id, .. }
+        | Event::Skipped { id, .. }
+        | Event::Listed { id } = event
+        {
+            let entry = entries.get_mut(&id).unwrap();
+            if entry.finished() {
+                progress -= 1;
+                entry.last_file = None;
+                finish_entry(entry, &mut stats, renderer, opts)?;
+            }
+        }
 
-        task::spawn_blocking(move || {
-            let file = std::fs::File::create(&dest)?;
+        renderer.update(&entries.values().collect::<Vec<_>>(), &stats)?;
 
-            heif_to_png(&source, file)?;
+        if let Some(status) = quit {
+            break status;
+        }
+    };
 
-            Ok::<_, anyhow::Error>(())
-        })
-        .await??;
+    renderer.finish(&entries.values().collect::<Vec<_>>())?;
+
+    if opts.checksum {
+        write_checksums(&opts.output.join(CHECKSUMS_FILE), checksums)?;
+    }
+    if let Some(path) = &opts.csv {
+        write_csv(path, &rows)?;
     }
+    if let Some(path) = &opts.save_status {
+        render::save_status(path, &entries.values().collect::<Vec<_>>(), &stats)?;
+    }
+    if opts.trim_empty_output {
+        // innermost first, so that parents left with only empty directories go too
+        let mut dirs: Vec<_> = entries
+            .values()
+            .flat_map(|entry| &entry.created_dirs)
+            .collect();
+        dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+        for dir in dirs {
+            // the files are all done by now, so this is only tidying up
+            let removed = match dir.read_dir_utf8().map(|mut files| files.next().is_none()) {
+                Ok(true) => std::fs::remove_dir(dir),
+                Ok(false) => Ok(()),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = removed {
+                warn!("failed to remove empty '{}': {}", dir, err);
+            }
+        }
+    }
+    // every file was processed, even if some came out different
+    if status == 0 || status == 5 {
+        drop(checkpoint);
+        std::fs::remove_file(&checkpoint_path)?;
+    }
+    // files first, then directories that failed to be finished
+    let mut failures: Vec<_> = entries
+        .into_values()
+        .flat_map(|entry| entry.errors)
+        .collect();
+    failures.append(&mut stats.failures);
+    stats.failures = failures;
+    Ok((status, stats))
+}
 
+/// Writes the contact sheet of a directory whose files are all done, and gives its output the
+/// permissions of its source. Either failing counts as an error against the directory's output,
+/// without stopping the run.
+fn finish_entry(
+    entry: &mut Entry,
+    stats: &mut Stats,
+    renderer: &mut dyn Renderer,
+    opts: &Options,
+) -> Result<()> {
+    let mut finished = Ok(());
+    if opts.contact_sheet && !entry.thumbnails.is_empty() {
+        let path = opts.output.join(format!("{}_contactsheet.png", entry.name));
+        finished = contact_sheet::write(&path, &std::mem::take(&mut entry.thumbnails));
+    }
+    // applied once the directory is finished, in case the source isn't writable
+    if opts.preserve_perms {
+        finished = finished.and_then(|()| copy_permissions(&entry.source, &entry.output, opts));
+    }
+    if let Err(err) = finished {
+        let err = format!("{:#}", err);
+        stats.errors += 1;
+        METRICS.failed.fetch_add(1, Ordering::Relaxed);
+        error!("{}: {}", entry.output, err);
+        renderer.error(&entry.output, &err)?;
+        stats.failures.push((entry.output.clone(), err));
+    }
     Ok(())
 }
 
-fn heif_to_png<W: Write>(source: &Utf8Path, writer: W) -> Result<()> {
-    let ctx = HeifContext::read_from_file(source.as_str())?;
-    let handle = ctx.primary_image_handle()?;
-
-    let image = HEIF.decode(&handle, heif::ColorSpace::Rgb(heif::RgbChroma::Rgb), None)?;
-    let planes = image.planes();
-    let plane = planes.interleaved.unwrap();
+/// Shows a desktop notification summarizing the run, if there's anywhere to show it.
+fn notify(status: i32, stats: &Stats) {
+    let summary = if status == 0 {
+        "Conversion finished"
+    } else {
+        "Conversion stopped"
+    };
+    let body = format!(
+        "{} files done, {} converted, {} errors",
+        stats.completed, stats.converted, stats.errors
+    );
 
-    let target_size = plane.width * plane.height * 3;
-    let actual_size = plane.data.len();
+    // headless systems have no notification service, which isn't worth failing over
+    if let Err(err) = notify_rust::Notification::new()
+        .summary(summary)
+        .body(&body)
+        .show()
+    {
+        warn!("couldn't show notification: {}", err);
+    }
+}
 
-    let mut encoder = png::Encoder::new(writer, plane.width, plane.height);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_compression(png::Compression::Best);
+/// Writes a manifest in the format of `sha256sum`, so that it can be checked with
+/// `sha256sum -c` from inside the input directory.
+fn write_checksums(path: &Utf8Path, mut checksums: Vec<(Utf8PathBuf, String)>) -> Result<()> {
+    checksums.sort();
 
-    let mut writer = encoder.write_header()?;
-    if target_size as usize == actual_size {
-        log(format!("converting {} (full stream)", source));
-        let mut stream = writer.stream_writer()?;
-        stream.write_all(plane.data)?;
-    } else {
-        log(format!("converting {} (chunked)", source));
-        let chunk_size = actual_size / plane.height as usize;
-        let mut stream = writer.stream_writer_with_size(chunk_size)?;
-        for chunk in plane.data.chunks_exact(chunk_size) {
-            stream.write_all(chunk)?;
-        }
+    let mut file = io::BufWriter::new(
+        std::fs::File::create(path).with_context(|| format!("failed to create '{}'", path))?,
+    );
+    for (file_path, checksum) in checksums {
+        writeln!(file, "{}  {}", checksum, file_path)?;
     }
-
-    log(format!("done converting {}", source));
+    file.flush()?;
     Ok(())
 }
 
-fn confirm(msg: &str) -> bool {
-    print!("{} [y/N]: ", msg);
-    io::stdout().flush().unwrap();
+/// A processed file's row in the `--csv` report.
+struct CsvRow {
+    path: Utf8PathBuf,
+    /// What was done with the file, or `None` if it failed before that was worked out.
+    action: Option<Action>,
+    input_bytes: u64,
+    output_bytes: u64,
+    elapsed: Duration,
+    /// Why the file failed, if it did.
+    error: Option<String>,
+}
 
-    let mut res = String::new();
-    if io::stdin().read_line(&mut res).is_err() {
-        return false;
+/// Writes `rows` to `path` as CSV under a header line, in the order the files finished.
+fn write_csv(path: &Utf8Path, rows: &[CsvRow]) -> Result<()> {
+    let mut csv =
+        csv::Writer::from_path(path).with_context(|| format!("failed to create '{}'", path))?;
+    csv.write_record([
+        "path",
+        "action",
+        "status",
+        "input_bytes",
+        "output_bytes",
+        "duration_ms",
+        "error",
+    ])?;
+    for row in rows {
+        let action = match row.action {
+            Some(Action::Convert) => "convert",
+            Some(Action::Copy) => "copy",
+            Some(Action::Symlink) => "symlink",
+            None => "",
+        };
+        let status = if row.error.is_some() { "failed" } else { "ok" };
+        csv.write_record([
+            row.path.as_str(),
+            action,
+            status,
+            &row.input_bytes.to_string(),
+            &row.output_bytes.to_string(),
+            &row.elapsed.as_millis().to_string(),
+            row.error.as_deref().map_or("", str::trim),
+        ])?;
     }
-    res.trim() == "y"
+    csv.flush()?;
+    Ok(())
 }
 
-fn log(msg: impl std::fmt::Display) {
-    let mut file = std::fs::File::options()
-        .append(true)
-        .create(true)
-        .open("log.txt")
-        .unwrap();
-    writeln!(file, "{}", msg).unwrap();
+/// A source directory and the files to convert from it into a single output directory.
+#[derive(Clone, Debug)]
+struct Group {
+    source: Utf8PathBuf,
+    /// Path of `source` relative to the input it was found in.
+    relative: Utf8PathBuf,
+    name: String,
+    output: Utf8PathBuf,
+    jobs: Vec<Job>,
+    /// Files that need nothing done, and so have no jobs, by why.
+    skips: BTreeMap<Skip, usize>,
+    /// Files that couldn't be planned, or the directory itself if it couldn't be listed, with
+    /// their errors. They're reported as failures once the run starts, like any other.
+    errors: Vec<(Utf8PathBuf, String)>,
+    /// Left to be listed while the directory is processed, with `--stream`.
+    listing: Option<DirPlan>,
+}
+
+#[derive(Clone, Debug)]
+struct Job {
+    source: Utf8PathBuf,
+    dest: Utf8PathBuf,
+    action: Action,
+}
+
+/// Works out every directory and file to convert and where each one will be written, without
+/// touching the output.
+fn plan_groups(opts: &Options) -> Result<Vec<Group>> {
+    let mut dir_names = HashSet::new();
+
+    let checkpoint = opts.output.join(CHECKPOINT_FILE);
+    let resumed: Arc<HashSet<Utf8PathBuf>> = Arc::new(if opts.resume && checkpoint.exists() {
+        std::fs::read_to_string(&checkpoint)
+            .with_context(|| format!("failed to read '{}'", checkpoint))?
+            .lines()
+            .map(Utf8PathBuf::from)
+            .collect()
+    } else {
+        HashSet::new()
+    });
+
+    let changed = if opts.changed_only {
+        let mut changed = HashSet::new();
+        for input in &opts.inputs {
+            changed.extend(git_changed(input)?);
+        }
+        Some(Arc::new(changed))
+    } else {
+        None
+    };
+
+    let mut dirs = Vec::new();
+    let mut visited = HashSet::new();
+    let mut input_names = HashSet::new();
+    let output = opts.output.canonicalize().ok();
+    for input in &opts.inputs {
+        let canonical = input
+            .canonicalize()
+            .with_context(|| format!("failed to open '{}'", input))?;
+        if output
+            .as_ref()
+            .is_some_and(|output| output != &canonical && output.starts_with(&canonical))
+        {
+            eprintln!(
+                "warning: the output '{}' is inside '{}', so it's left out of what's converted",
+                opts.output, input
+            );
+        }
+        // inputs with the same name, like two cards' DCIM, get separate directories
+        let subdir = opts
+            .per_input_subdir
+            .then(|| unique_name(&mut input_names, input_name(input, &canonical)));
+        visited.insert(canonical);
+        let mut groups = Vec::new();
+        find_groups(input, opts.depth.get(), opts, &mut visited, &mut groups)?;
+        let base = opts.relative_to.as_deref().unwrap_or(input);
+        for group in groups {
+            let name = relative_path(&group, base)?;
+            dirs.push((base, subdir.clone(), group, name));
+        }
+    }
+
+    let mut groups = Vec::new();
+    let mut mapped = HashSet::new();
+    // files listed in the directories before, for `--global-index`
+    let mut listed = 0;
+    for (base, subdir, dir_path, relative) in dirs {
+        let name = match opts.dir_map.get_key_value(Utf8Path::new(&relative)) {
+            Some((from, to)) => {
+                mapped.insert(from);
+                to.to_string()
+            }
+            None if opts.collapse_single => collapse_single(base, &relative)?,
+            None => relative.clone(),
+        };
+        let name = match subdir {
+            Some(subdir) if name.is_empty() => subdir,
+            Some(subdir) => Utf8Path::new(&subdir).join(name).into_string(),
+            None => name,
+        };
+        // directories with the same name under different inputs get separate outputs, and so do
+        // directories mapped to the same place
+        let dir_name = unique_name(&mut dir_names, normalize_name(name, opts));
+        // with `--format-subdirs`, copies go along with the first format
+        let output = opts.format_output(opts.formats[0]);
+        let output = if dir_name.is_empty() {
+            output
+        } else {
+            output.join(&dir_name)
+        };
+        // collapsed all the way into the output, but still shown by its own name
+        let dir_name = if dir_name.is_empty() {
+            relative.clone()
+        } else {
+            dir_name
+        };
+        let mut plan = DirPlan::new(
+            dir_path.clone(),
+            output.clone(),
+            resumed.clone(),
+            changed.clone(),
+        );
+
+        let mut jobs = Vec::new();
+        let mut skips = BTreeMap::new();
+        let mut errors = Vec::new();
+        if !opts.stream {
+            // only the input itself has to be there, anything unreadable inside it fails alone
+            let mut files = Vec::new();
+            match dir_path.read_dir_utf8() {
+                Ok(listing) => {
+                    for file in listing {
+                        match file.and_then(|file| Ok((file.file_type()?, file.into_path()))) {
+                            Ok((file_type, path)) => files.push((path, file_type)),
+                            Err(err) => {
+                                errors.push((dir_path.clone(), format!("failed to list: {}", err)))
+                            }
+                        }
+                    }
+                }
+                Err(err) => errors.push((dir_path.clone(), format!("failed to list: {}", err))),
+            }
+            // sorted so that `{index}` in name templates is the same from one run to the next
+            files.sort_by(|(a, _), (b, _)| a.file_name().cmp(&b.file_name()));
+
+            let first = if opts.global_index { listed + 1 } else { 1 };
+            listed += files.len();
+            for (index, (path, file_type)) in (first..).zip(files) {
+                match plan.plan(path, file_type, index, opts) {
+                    Planned::Job(job) => jobs.push(job),
+                    Planned::Finished(reason) => *skips.entry(reason).or_default() += 1,
+                    Planned::Ignored => {}
+                }
+            }
+        }
+
+        groups.push(Group {
+            source: dir_path,
+            relative: relative.into(),
+            name: dir_name,
+            output,
+            jobs,
+            skips,
+            errors,
+            listing: opts.stream.then_some(plan),
+        });
+    }
+
+    // most likely a typo, which would otherwise go unnoticed until the output is checked
+    for from in opts.dir_map.keys().filter(|from| !mapped.contains(from)) {
+        eprintln!("warning: no input directory matches '{}' in --map", from);
+    }
+
+    Ok(groups)
+}
+
+/// Works out where each file of a directory goes, one file at a time, so that with `--stream`
+/// files can be planned as they're listed.
+#[derive(Clone, Debug)]
+struct DirPlan {
+    source: Utf8PathBuf,
+    output: Utf8PathBuf,
+    /// Copied with the names and contents it has.
+    verbatim: bool,
+    /// Sources finished by the run being resumed, with `--resume`.
+    resumed: Arc<HashSet<Utf8PathBuf>>,
+    /// The only sources to handle, with `--changed-only`.
+    changed: Option<Arc<HashSet<Utf8PathBuf>>>,
+    /// Outputs given out so far, so two sources can't be renamed to the same free name or both
+    /// written where `--on-conflict skip` should leave the second out. It's only kept for those
+    /// two, so that other runs, `--stream` ones in particular, don't grow it with every file.
+    planned: HashSet<Utf8PathBuf>,
+}
+
+/// What's to become of a single file in a directory.
+enum Planned {
+    Job(Job),
+    /// Nothing, as an earlier run finished it or its output is there already.
+    Finished(Skip),
+    /// Nothing, as it was modified outside the range given or git has no changes to it.
+    Ignored,
+}
+
+impl DirPlan {
+    fn new(
+        source: Utf8PathBuf,
+        output: Utf8PathBuf,
+        resumed: Arc<HashSet<Utf8PathBuf>>,
+        changed: Option<Arc<HashSet<Utf8PathBuf>>>,
+    ) -> Self {
+        Self {
+            verbatim: source.file_name() == Some(MISC_DIR),
+            source,
+            output,
+            resumed,
+            changed,
+            planned: HashSet::new(),
+        }
+    }
+
+    /// Plans the file at `source`, which is the `index`th in the directory counting from 1 and
+    /// has the type `file_type` as listed, without following symlinks.
+    fn plan(
+        &mut self,
+        source: Utf8PathBuf,
+        file_type: FileType,
+        index: usize,
+        opts: &Options,
+    ) -> Planned {
+        let (output, verbatim) = (&self.output, self.verbatim);
+        // a `.MISC` directory is copied whole, dotfiles included
+        if is_hidden(&source) && !verbatim && !opts.include_hidden {
+            return Planned::Ignored;
+        }
+        let symlink = file_type.is_symlink();
+        // only the files directly inside are converted, the directories below are `--depth`'s
+        if file_type.is_dir() || symlink && opts.follow_symlinks && source.is_dir() {
+            warn!(
+                "skipping directory '{}', which is below the --depth being converted",
+                source
+            );
+            return Planned::Ignored;
+        }
+        if self.resumed.contains(&source) {
+            return Planned::Finished(Skip::Resumed);
+        }
+
+        let file_name = match &opts.name_template {
+            _ if verbatim => source.file_name().unwrap().to_string(),
+            Some(template) => {
+                let dir = self.source.file_name().unwrap();
+                let name = template.render(dir, source.file_stem().unwrap(), index);
+                match source.extension() {
+                    Some(ext) => format!("{}.{}", name, ext),
+                    None => name,
+                }
+            }
+            None => source.file_name().unwrap().to_string(),
+        };
+        let file_name = if verbatim {
+            file_name
+        } else {
+            normalize_name(file_name, opts)
+        };
+
+        let action = if symlink && !opts.follow_symlinks {
+            Action::Symlink
+        } else if verbatim {
+            Action::Copy
+        } else {
+            match opts.rule(&source) {
+                Some(Rule::Skip) => return Planned::Finished(Skip::Ruled),
+                Some(Rule::Copy) => Action::Copy,
+                Some(Rule::Convert { .. }) if !opts.copy_only => Action::Convert,
+                None if is_heif(&source) && !opts.copy_only => Action::Convert,
+                _ => Action::Copy,
+            }
+        };
+
+        let dest = output.join(file_name);
+        let mut dest = match source.extension() {
+            Some(ext) if !verbatim => {
+                let converted =
+                    (action == Action::Convert).then(|| opts.formats_for(&source)[0].extension());
+                dest.with_extension(output_extension(ext, converted, opts.extension_case))
+            }
+            _ => dest,
+        };
+        if let (Action::Convert, Some(suffix)) = (action, &opts.suffix) {
+            let name = format!(
+                "{}{}.{}",
+                dest.file_stem().unwrap(),
+                suffix,
+                dest.extension().unwrap()
+            );
+            dest.set_file_name(name);
+        }
+        if opts.lowercase_names && !verbatim {
+            let name = dest.file_name().unwrap().to_lowercase();
+            dest.set_file_name(name);
+        }
+        if opts.sort_by_type && !verbatim {
+            let name = dest.file_name().unwrap().to_string();
+            dest = output.join(type_dir(&source, action)).join(name);
+        }
+        if opts.bucket_by_month && !verbatim {
+            let relative = dest.strip_prefix(output).unwrap().to_owned();
+            dest = output.join(month_bucket(&source)).join(relative);
+        }
+        if let Some(burst) = burst_id(&source).filter(|_| opts.group_bursts && !verbatim) {
+            let name = dest.file_name().unwrap().to_string();
+            dest.set_file_name(format!("burst-{}", burst));
+            dest.push(name);
+        }
+        if !modified_in_range(&source, opts)
+            || self
+                .changed
+                .as_ref()
+                .is_some_and(|changed| !changed.contains(&source))
+        {
+            return Planned::Ignored;
+        }
+        if opts.images_only && action != Action::Convert {
+            return Planned::Finished(Skip::NotImage);
+        }
+        if opts.update && up_to_date(&source, &dest) {
+            return Planned::Finished(Skip::UpToDate);
+        }
+        if !opts.update && (self.planned.contains(&dest) || dest.symlink_metadata().is_ok()) {
+            match opts.on_conflict {
+                OnConflict::Skip => return Planned::Finished(Skip::Exists),
+                OnConflict::Rename => dest = free_name(&dest, &self.planned),
+                // a copy is the size of its source, so one that's already there is never bigger
+                OnConflict::Smaller
+                    if action != Action::Convert
+                        && dest.symlink_metadata().is_ok_and(|meta| {
+                            source
+                                .metadata()
+                                .is_ok_and(|source| meta.len() <= source.len())
+                        }) =>
+                {
+                    return Planned::Finished(Skip::Exists)
+                }
+                OnConflict::Ask | OnConflict::Overwrite | OnConflict::Smaller => {}
+            }
+        }
+        if matches!(opts.on_conflict, OnConflict::Skip | OnConflict::Rename) {
+            self.planned.insert(dest.clone());
+        }
+        Planned::Job(Job {
+            source,
+            dest,
+            action,
+        })
+    }
+}
+
+/// Batches of files still to be started, kept per directory so a worker sticks to its own
+/// directory for as long as that has work, then helps out wherever the most is left.
+struct Queue {
+    pending: Mutex<Pending>,
+    /// Woken whenever `pending` changes, for workers waiting on directories still being listed
+    /// and listers waiting for room.
+    changed: Notify,
+    /// How many started directories may have batches left at once.
+    max_active: usize,
+    /// Set for a directory once a file in it fails with `--stop-directory-on-error`, so the rest
+    /// are skipped.
+    aborted: Vec<AtomicBool>,
+}
+
+struct Pending {
+    batches: Vec<VecDeque<Vec<Job>>>,
+    /// Set while a directory is still being listed with `--stream`, so running out of batches
+    /// doesn't mean it's done.
+    listing: Vec<bool>,
+    /// How many directories, in order, have been started; the rest wait for
+    /// `--max-active-dirs` to allow them.
+    started: usize,
+}
+
+impl Queue {
+    /// Takes the next batch from directory `current`. Once that has none, it starts the next
+    /// directory if fewer than `max_active` have batches left, or else takes from the started
+    /// directory with the most batches left. Returns the batch along with the directory it came
+    /// from, which becomes `current`, waiting for directories still being listed if need be.
+    async fn next(&self, current: &mut Option<usize>) -> Option<(usize, Vec<Job>)> {
+        self.wait_until(|pending| {
+            let Pending {
+                batches,
+                listing,
+                started,
+            } = pending;
+            let id = match *current {
+                Some(id) if !batches[id].is_empty() => Some(id),
+                _ => {
+                    let active = (0..*started)
+                        .filter(|&id| listing[id] || !batches[id].is_empty())
+                        .count();
+                    while *started < batches.len()
+                        && !listing[*started]
+                        && batches[*started].is_empty()
+                    {
+                        *started += 1;
+                    }
+                    if active < self.max_active && *started < batches.len() {
+                        *started += 1;
+                        // its lister may be waiting for it to start
+                        self.changed.notify_waiters();
+                        Some(*started - 1)
+                    } else {
+                        (0..*started)
+                            .filter(|&id| !batches[id].is_empty())
+                            .max_by_key(|&id| batches[id].len())
+                    }
+                }
+            };
+            if let Some(id) = id {
+                *current = Some(id);
+                if let Some(batch) = batches[id].pop_front() {
+                    self.changed.notify_waiters();
+                    return Some(Some((id, batch)));
+                }
+            }
+            // nothing to take until more is listed
+            (!listing.contains(&true)).then_some(None)
+        })
+        .await
+    }
+
+    /// Adds a batch listed from directory `id`, once it has fewer than `room` batches waiting.
+    async fn push(&self, id: usize, batch: Vec<Job>, room: usize) {
+        let mut batch = Some(batch);
+        self.wait_until(|pending| {
+            (pending.batches[id].len() < room).then(|| {
+                pending.batches[id].extend(batch.take());
+            })
+        })
+        .await;
+        self.changed.notify_waiters();
+    }
+
+    /// Marks directory `id` as listed in full.
+    fn listed(&self, id: usize) {
+        self.pending.lock().unwrap().listing[id] = false;
+        self.changed.notify_waiters();
+    }
+
+    /// Calls `ready` each time `pending` changes until it returns a value.
+    async fn wait_until<T>(&self, mut ready: impl FnMut(&mut Pending) -> Option<T>) -> T {
+        loop {
+            // registered before checking, so a change in between isn't missed
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            let value = ready(&mut self.pending.lock().unwrap());
+            if let Some(value) = value {
+                return value;
+            }
+            changed.await;
+        }
+    }
+}
+
+/// Files are only started while `paused` is false, though files already being converted are
+/// left to finish.
+fn spawn_file_processors(
+    tx: Sender<Event>,
+    paused: watch::Receiver<bool>,
+    groups: Vec<Group>,
+    storage: Arc<dyn Storage>,
+    opts: &Arc<Options>,
+) -> Result<IndexMap<usize, Entry>> {
+    let mut entries = IndexMap::new();
+
+    let limits = Arc::new(Limits {
+        decodes: Semaphore::new(opts.decode_jobs.get()),
+        memory: opts
+            .max_memory
+            .map(|bytes| Semaphore::new(budget_mib(bytes) as usize)),
+        high_water: opts
+            .memory_high_water
+            .zip(opts.memory_low_water)
+            .map(|(high, low)| pressure::watch(high, low)),
+    });
+
+    let mut pending = Vec::with_capacity(groups.len());
+    let mut listings = Vec::new();
+    let mut unplanned = Vec::new();
+    for (id, group) in groups.into_iter().enumerate() {
+        let created_dirs: Vec<_> = group
+            .output
+            .ancestors()
+            .take_while(|dir| !dir.exists())
+            .map(Utf8Path::to_owned)
+            .collect();
+        std::fs::create_dir_all(&group.output)
+            .with_context(|| format!("failed to create '{}'", group.output))?;
+
+        let finished: usize = group.skips.values().sum();
+        let total = finished + group.jobs.len() + group.errors.len();
+        unplanned.extend(group.errors.into_iter().map(|(file, err)| (id, file, err)));
+
+        // each batch is worked through in order by a single worker
+        let mut batches = VecDeque::new();
+        let mut jobs = group.jobs.into_iter().peekable();
+        while jobs.peek().is_some() {
+            batches.push_back(jobs.by_ref().take(opts.batch_size.get()).collect());
+        }
+        pending.push(batches);
+        if let Some(plan) = group.listing.clone() {
+            listings.push((id, plan));
+        }
+
+        entries.insert(
+            id,
+            Entry {
+                name: group.name,
+                source: group.source,
+                relative: group.relative,
+                output: group.output,
+                last_file: None,
+                copying: None,
+                decoding: None,
+                encoding: None,
+
+                total,
+                completed: finished,
+                converted: 0,
+                copied: 0,
+                errors: Vec::new(),
+                skipped: 0,
+                skips: group.skips,
+                differences: Vec::new(),
+                thumbnails: Vec::new(),
+                created_dirs,
+                listing: group.listing.is_some(),
+            },
+        );
+    }
+
+    // every directory brings up to `--io-jobs` workers, which move on to other directories once
+    // their own runs out, so a large directory isn't left working alone at the end. With
+    // `--max-active-dirs` there are only enough workers for that many directories, and they
+    // start on directories as they reach them. Directories still to be listed could have any
+    // number of batches
+    let listing: Vec<_> = (0..pending.len())
+        .map(|id| listings.iter().any(|(listed, _)| *listed == id))
+        .collect();
+    let batches = |id: usize| {
+        if listing[id] {
+            usize::MAX
+        } else {
+            pending[id].len()
+        }
+    };
+    let workers: Vec<_> = match opts.max_active_dirs {
+        None => (0..pending.len())
+            .flat_map(|home| std::iter::repeat_n(Some(home), batches(home).min(opts.io_jobs.get())))
+            .collect(),
+        Some(max) => {
+            let batches =
+                (0..pending.len()).fold(0, |sum: usize, id| sum.saturating_add(batches(id)));
+            std::iter::repeat_n(
+                None,
+                opts.io_jobs.get().saturating_mul(max.get()).min(batches),
+            )
+            .collect()
+        }
+    };
+    let queue = Arc::new(Queue {
+        aborted: pending.iter().map(|_| AtomicBool::new(false)).collect(),
+        changed: Notify::new(),
+        max_active: opts.max_active_dirs.map_or(usize::MAX, |max| max.get()),
+        pending: Mutex::new(Pending {
+            started: if opts.max_active_dirs.is_some() {
+                0
+            } else {
+                pending.len()
+            },
+            batches: pending,
+            listing,
+        }),
+    });
+
+    // failed while planning, but counted like files that fail once the run is underway
+    let failed = tx.clone();
+    task::spawn(async move {
+        for (id, file, err) in unplanned {
+            let event = Event::Err {
+                id,
+                file,
+                err,
+                fatal: false,
+                action: None,
+                bytes: 0,
+                elapsed: Duration::ZERO,
+            };
+            if failed.send(event).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    for (id, plan) in listings {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        let opts = opts.clone();
+        task::spawn(async move {
+            let source = plan.source.clone();
+            if let Err(err) = list_dir(id, plan, &queue, &tx, &opts).await {
+                send_unlisted(id, source, err, &tx).await;
+            }
+            queue.listed(id);
+            tx.send(Event::Listed { id }).await.ok();
+        });
+    }
+
+    for mut current in workers {
+        let queue = queue.clone();
+        let limits = limits.clone();
+        let storage = storage.clone();
+        let tx = tx.clone();
+        let mut paused = paused.clone();
+        let opts = opts.clone();
+        task::spawn(async move {
+            'worker: while let Some((id, batch)) = queue.next(&mut current).await {
+                for Job {
+                    source,
+                    dest,
+                    action,
+                } in batch
+                {
+                    // counted as in flight before pausing is checked again, so that the event
+                    // loop, which pauses before waiting for none to be in flight, can't miss a
+                    // file that's just starting
+                    loop {
+                        // fails only once the event loop has stopped, leaving nothing to wait for
+                        if paused.wait_for(|paused| !paused).await.is_err() {
+                            break 'worker;
+                        }
+                        METRICS.in_flight.fetch_add(1, Ordering::SeqCst);
+                        if !*paused.borrow() {
+                            break;
+                        }
+                        METRICS.in_flight.fetch_sub(1, Ordering::SeqCst);
+                    }
+
+                    if queue.aborted[id].load(Ordering::Relaxed) {
+                        METRICS.in_flight.fetch_sub(1, Ordering::SeqCst);
+                        let reason = Skip::Aborted;
+                        if tx.send(Event::Skipped { id, reason }).await.is_err() {
+                            break 'worker;
+                        }
+                        continue;
+                    }
+
+                    // progress updates are dropped rather than waited on when the queue is full,
+                    // and a closed queue means the run is over
+                    let progress = |step| {
+                        let file = source.clone();
+                        let event = match step {
+                            FileProgress::Decoding => Event::Started { id, file },
+                            FileProgress::Copied(copied, size) => Event::Copying {
+                                id,
+                                file,
+                                copied,
+                                size,
+                            },
+                            FileProgress::Encoded(encoded, total) => Event::Encoding {
+                                id,
+                                file,
+                                encoded,
+                                total,
+                            },
+                        };
+                        !matches!(tx.try_send(event), Err(TrySendError::Closed(_)))
+                    };
+                    let started = Instant::now();
+                    let result =
+                        process_file(&source, &dest, action, &limits, &storage, &progress, &opts)
+                            .await;
+                    let elapsed = started.elapsed();
+                    let bytes = tokio::fs::metadata(&source)
+                        .await
+                        .map_or(0, |meta| meta.len());
+                    let event = match result {
+                        Ok(None) => Event::Skipped {
+                            id,
+                            reason: Skip::KnownHash,
+                        },
+                        Ok(Some(processed)) => Event::Progress {
+                            id,
+                            file: source.clone(),
+                            bytes,
+                            processed,
+                            elapsed,
+                        },
+                        Err(err) => {
+                            if opts.stop_directory_on_error {
+                                queue.aborted[id].store(true, Ordering::Relaxed);
+                            }
+                            let fatal = output_unwritable(&err);
+                            let err = if fatal {
+                                err.context("output is full or read-only, stopping")
+                            } else {
+                                err
+                            };
+                            Event::Err {
+                                id,
+                                file: source.clone(),
+                                err: format!("{:#}", err),
+                                fatal,
+                                action: Some(action),
+                                bytes,
+                                elapsed,
+                            }
+                        }
+                    };
+                    // only once it's been sent, so that a stopping run still counts the file
+                    let sent = tx.send(event).await;
+                    METRICS.in_flight.fetch_sub(1, Ordering::SeqCst);
+                    // the event loop has stopped, as after a quit
+                    if sent.is_err() {
+                        break 'worker;
+                    }
+                }
+            }
+        });
+    }
+
+    Ok(entries)
+}
+
+/// Lists directory `id` with `--stream`, reporting its files and handing them to `queue` a batch
+/// at a time, so that only a few batches of it are held at once.
+async fn list_dir(
+    id: usize,
+    mut plan: DirPlan,
+    queue: &Queue,
+    tx: &Sender<Event>,
+    opts: &Options,
+) -> Result<()> {
+    // not opened until its turn comes, with `--max-active-dirs`
+    queue
+        .wait_until(|pending| (id < pending.started).then_some(()))
+        .await;
+    let mut dir = tokio::fs::read_dir(&plan.source)
+        .await
+        .with_context(|| format!("failed to list '{}'", plan.source))?;
+    let mut batch = Vec::new();
+    let mut finished = BTreeMap::new();
+    let mut index = 0;
+    loop {
+        let file = dir
+            .next_entry()
+            .await
+            .with_context(|| format!("failed to list '{}'", plan.source))?;
+        if let Some(file) = &file {
+            index += 1;
+            let source = match Utf8PathBuf::try_from(file.path()) {
+                Ok(source) => source,
+                Err(_) => {
+                    let name = Utf8PathBuf::from(file.path().to_string_lossy().into_owned());
+                    let err = anyhow::anyhow!("the file name isn't UTF-8");
+                    if !send_unlisted(id, name, err, tx).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            let file_type = match file.file_type().await {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    let err = anyhow::Error::from(err).context("failed to read the file type");
+                    if !send_unlisted(id, source, err, tx).await {
+                        return Ok(());
+                    }
+                    continue;
+                }
+            };
+            match plan.plan(source, file_type, index, opts) {
+                Planned::Job(job) => batch.push(job),
+                Planned::Finished(reason) => *finished.entry(reason).or_default() += 1,
+                Planned::Ignored => {}
+            }
+        }
+
+        let done = file.is_none();
+        if batch.len() == opts.batch_size.get()
+            || done && (!batch.is_empty() || !finished.is_empty())
+        {
+            let found = Event::Found {
+                id,
+                files: batch.len(),
+                finished: std::mem::take(&mut finished),
+            };
+            // the event loop has stopped, as after a quit
+            if tx.send(found).await.is_err() {
+                return Ok(());
+            }
+            if !batch.is_empty() {
+                // up to one batch waiting for each worker the directory brings
+                queue
+                    .push(id, std::mem::take(&mut batch), opts.io_jobs.get())
+                    .await;
+            }
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Reports `file` of directory `id` as found and failed with `--stream`, for files that couldn't
+/// be planned and directories that couldn't be listed. It's counted as one more file, so the
+/// directory can still finish. Returns false once the event loop has stopped.
+async fn send_unlisted(
+    id: usize,
+    file: Utf8PathBuf,
+    err: anyhow::Error,
+    tx: &Sender<Event>,
+) -> bool {
+    let found = Event::Found {
+        id,
+        files: 1,
+        finished: BTreeMap::new(),
+    };
+    let event = Event::Err {
+        id,
+        file,
+        err: format!("{:#}", err),
+        fatal: false,
+        action: None,
+        bytes: 0,
+        elapsed: Duration::ZERO,
+    };
+    tx.send(found).await.is_ok() && tx.send(event).await.is_ok()
+}
+
+/// Estimates how many bytes the outputs of `groups` will take up.
+#[cfg(unix)]
+fn estimate_space(groups: &[Group], opts: &Options) -> u64 {
+    let factor: u64 = opts
+        .formats
+        .iter()
+        .map(|format| match format {
+            Format::Png => PNG_SIZE_FACTOR,
+            Format::Jpeg => JPEG_SIZE_FACTOR,
+            Format::Webp => WEBP_SIZE_FACTOR,
+        })
+        .sum();
+    groups
+        .iter()
+        .flat_map(|group| &group.jobs)
+        .map(|job| {
+            let size = job.source.symlink_metadata().map_or(0, |meta| meta.len());
+            match job.action {
+                Action::Convert => size * factor,
+                Action::Copy => size,
+                Action::Symlink => 0,
+            }
+        })
+        .sum()
+}
+
+/// Returns the bytes available to unprivileged users on the filesystem containing `path`.
+#[cfg(unix)]
+fn available_space(path: &Utf8Path) -> Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit};
+
+    let c_path = CString::new(path.as_str())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: `c_path` is a valid C string and `stat` is only read after statvfs fills it in
+    if unsafe { libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error())
+            .with_context(|| format!("failed to check free space on '{}'", path));
+    }
+    let stat = unsafe { stat.assume_init() };
+    // the field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Takes an exclusive lock on `output` for as long as the returned file is open. If another
+/// process holds it, returns that process's id instead, where it's known.
+#[cfg(unix)]
+fn lock_output(output: &Utf8Path) -> Result<Result<std::fs::File, Option<u32>>> {
+    use std::{io::Read, os::unix::io::AsRawFd};
+
+    let path = output.join(LOCK_FILE);
+    let mut file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("failed to open '{}'", path))?;
+
+    // SAFETY: the descriptor belongs to `file`, which is open for the duration of the call
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(err).with_context(|| format!("failed to lock '{}'", path));
+        }
+        let mut pid = String::new();
+        file.read_to_string(&mut pid).ok();
+        return Ok(Err(pid.trim().parse().ok()));
+    }
+
+    file.set_len(0)?;
+    write!(file, "{}", process::id())?;
+    Ok(Ok(file))
+}
+
+/// Finds the directories `depth` levels below `dir`, each of which is converted as a group.
+/// `visited` holds the canonical paths of directories already found, so that symlinks can't make
+/// the same directory appear twice or send the search into a loop.
+fn find_groups(
+    dir: &Utf8Path,
+    depth: usize,
+    opts: &Options,
+    visited: &mut HashSet<PathBuf>,
+    groups: &mut Vec<Utf8PathBuf>,
+) -> Result<()> {
+    // in name order rather than the filesystem's, so that directories are numbered, shown and
+    // given ` (2)` suffixes the same way on every run and machine
+    let mut entries = dir
+        .read_dir_utf8()
+        .and_then(|entries| entries.collect::<io::Result<Vec<_>>>())
+        .with_context(|| format!("failed to list '{}'", dir))?;
+    entries.sort_by(|a, b| a.file_name().cmp(b.file_name()));
+    // an output inside the input mustn't be converted into itself
+    let output = opts.output.canonicalize().ok();
+    for entry in entries {
+        if entry.file_type()?.is_symlink() && !opts.follow_symlinks {
+            continue;
+        }
+
+        let path = entry.into_path();
+        let misc = path.file_name() == Some(MISC_DIR);
+        // `.MISC` has `--keep-misc` of its own
+        let hidden = is_hidden(&path) && !misc && !opts.include_hidden;
+        if !path.is_dir() || (misc && !opts.keep_misc) || hidden {
+            continue;
+        }
+        let canonical = path.canonicalize()?;
+        if output.as_ref() == Some(&canonical) {
+            info!("skipping {}, which is the output", path);
+            continue;
+        }
+        if !visited.insert(canonical) {
+            info!("skipping {}, already visited through a symlink", path);
+            continue;
+        }
+
+        // kept as a whole wherever it's found, rather than searched for directories to convert
+        if depth > 1 && !misc {
+            find_groups(&path, depth - 1, opts, visited, groups)?;
+        } else {
+            groups.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Returns the path of `dir` below `base`, which is its input or `--relative-to`. Paths that
+/// don't match as given, like `./photos/a` under `photos`, are compared once resolved.
+fn relative_path(dir: &Utf8Path, base: &Utf8Path) -> Result<String> {
+    if let Ok(relative) = dir.strip_prefix(base) {
+        return Ok(relative.to_string());
+    }
+    let resolved = dir.canonicalize_utf8()?;
+    let base_resolved = base
+        .canonicalize_utf8()
+        .with_context(|| format!("failed to open '{}'", base))?;
+    match resolved.strip_prefix(&base_resolved) {
+        Ok(relative) => Ok(relative.to_string()),
+        Err(_) => bail!("'{}' isn't inside '--relative-to {}'", dir, base),
+    }
+}
+
+/// Checks `source`'s modification time against `--since` and `--until`.
+fn modified_in_range(source: &Utf8Path, opts: &Options) -> bool {
+    if opts.since.is_none() && opts.until.is_none() {
+        return true;
+    }
+    match source.symlink_metadata().and_then(|meta| meta.modified()) {
+        Ok(modified) => {
+            opts.since.is_none_or(|since| modified >= since)
+                && opts.until.is_none_or(|until| modified < until)
+        }
+        // left for processing to report
+        Err(_) => true,
+    }
+}
+
+/// Lists the files under `input` that `git status` has as untracked, added or modified, for
+/// `--changed-only`, as paths starting with `input` like the ones found by listing it.
+fn git_changed(input: &Utf8Path) -> Result<HashSet<Utf8PathBuf>> {
+    let git = |args: &[&str]| -> Result<String> {
+        let output = process::Command::new("git")
+            .arg("-C")
+            .arg(input)
+            .args(args)
+            .output()
+            .context("failed to run git, which --changed-only needs")?;
+        if !output.status.success() {
+            bail!(
+                "'{}' isn't in a git repository, which --changed-only needs: {}",
+                input,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        String::from_utf8(output.stdout).context("git listed a path that isn't UTF-8")
+    };
+    let root = Utf8PathBuf::from(git(&["rev-parse", "--show-toplevel"])?.trim_end());
+    let status = git(&["status", "--porcelain", "-z", "--untracked-files=all", "."])?;
+    // the root is given with symlinks resolved, so the input is compared in the same form
+    let canonical = Utf8PathBuf::try_from(input.canonicalize()?)?;
+
+    let mut changed = HashSet::new();
+    let mut fields = status.split('\0');
+    while let Some(field) = fields.next() {
+        let Some((code, path)) = field.split_at_checked(3) else {
+            continue;
+        };
+        // renames and copies are followed by the path they came from
+        if code.starts_with(['R', 'C']) {
+            fields.next();
+        }
+        if code.contains('D') {
+            continue;
+        }
+        if let Ok(relative) = root.join(path).strip_prefix(&canonical) {
+            changed.insert(input.join(relative));
+        }
+    }
+    Ok(changed)
+}
+
+/// Checks whether `dest` exists and was modified no earlier than `source`.
+fn up_to_date(source: &Utf8Path, dest: &Utf8Path) -> bool {
+    let modified = |path: &Utf8Path| path.symlink_metadata().and_then(|meta| meta.modified());
+    match (modified(source), modified(dest)) {
+        (Ok(source), Ok(dest)) => dest >= source,
+        _ => false,
+    }
+}
+
+fn normalize_name(name: String, opts: &Options) -> String {
+    match opts.normalize_names {
+        Some(NormalizationForm::Nfc) => name.nfc().collect(),
+        Some(NormalizationForm::Nfd) => name.nfd().collect(),
+        None => name,
+    }
+}
+
+/// Picks the extension for an output file, which is `new_ext` for converted files and the source
+/// extension otherwise.
+fn output_extension(ext: &str, new_ext: Option<&str>, case: ExtensionCase) -> String {
+    match (case, new_ext) {
+        (ExtensionCase::Preserve, None) => ext.to_string(),
+        (ExtensionCase::Preserve, Some(new_ext)) if ext.chars().any(char::is_lowercase) => {
+            new_ext.to_lowercase()
+        }
+        (ExtensionCase::Preserve, Some(new_ext)) => new_ext.to_uppercase(),
+        (ExtensionCase::Lower, _) => new_ext.unwrap_or(ext).to_lowercase(),
+        (ExtensionCase::Upper, _) => new_ext.unwrap_or(ext).to_uppercase(),
+    }
+}
+
+/// Leaves out the components of `relative`, a directory under `input`, that are the only entry in
+/// their parent, for `--collapse-single`.
+fn collapse_single(input: &Utf8Path, relative: &str) -> Result<String> {
+    let mut parent = input.to_owned();
+    let mut kept = Utf8PathBuf::new();
+    for component in Utf8Path::new(relative).components() {
+        let mut entries = parent.read_dir_utf8()?;
+        let only = entries.next().is_some() && entries.next().is_none();
+        parent.push(component);
+        if !only {
+            kept.push(component);
+        }
+    }
+    Ok(kept.into_string())
+}
+
+/// Names the output directory of `input` for `--per-input-subdir`, after the directory itself
+/// even when it's given as `.` or `..`.
+fn input_name(input: &Utf8Path, canonical: &std::path::Path) -> String {
+    input
+        .file_name()
+        .or_else(|| canonical.file_name()?.to_str())
+        .unwrap_or("root")
+        .to_string()
+}
+
+fn unique_name(taken: &mut HashSet<String>, name: String) -> String {
+    let mut unique = name.clone();
+    let mut n = 1;
+    while !taken.insert(unique.clone()) {
+        n += 1;
+        unique = format!("{} ({})", name, n);
+    }
+    unique
+}
+
+/// Finds the first of `name (1).ext`, `name (2).ext` and so on next to `dest` that doesn't exist
+/// and isn't in `planned`.
+fn free_name(dest: &Utf8Path, planned: &HashSet<Utf8PathBuf>) -> Utf8PathBuf {
+    let stem = dest.file_stem().unwrap_or_default();
+    (1..)
+        .map(|n| {
+            dest.with_file_name(match dest.extension() {
+                Some(ext) => format!("{} ({}).{}", stem, n, ext),
+                None => format!("{} ({})", stem, n),
+            })
+        })
+        .find(|name| !planned.contains(name) && name.symlink_metadata().is_err())
+        .unwrap()
+}
+
+/// Converts, copies or links `source` to `dest`, returning `None` if it was skipped instead for
+/// having a hash in `--skip-hashes`.
+#[instrument(skip_all, fields(file = %source))]
+async fn process_file(
+    source: &Utf8Path,
+    dest: &Utf8Path,
+    action: Action,
+    limits: &Limits,
+    storage: &Arc<dyn Storage>,
+    progress: ProgressFn<'_>,
+    opts: &Arc<Options>,
+) -> Result<Option<Processed>> {
+    // conversions hold their turn for the whole file, so the reading, hashing and checks around
+    // a decode count against `--decode-jobs` too
+    let _turn = match action {
+        Action::Convert => Some(limits.admit_decode(opts).await?),
+        Action::Copy | Action::Symlink => None,
+    };
+    // sources to convert are read into memory once for hashing, within the turn, and decoded
+    // from those same bytes, while copies, which can be videos far too big to hold, are hashed a
+    // chunk at a time and then still left to the OS
+    let hashed = opts.checksum || !opts.skip_hashes.is_empty();
+    let source_path = source.to_owned();
+    let (data, checksum) = match action {
+        Action::Convert if hashed => {
+            let data = task::spawn_blocking(move || read_source(&source_path)).await??;
+            let checksum = format!("{:x}", Sha256::digest(&data));
+            (Some(data), Some(checksum))
+        }
+        Action::Copy if hashed => {
+            let checksum = task::spawn_blocking(move || hash_file(&source_path)).await??;
+            (None, Some(checksum))
+        }
+        _ => (None, None),
+    };
+    if checksum
+        .as_ref()
+        .is_some_and(|checksum| opts.skip_hashes.contains(checksum))
+    {
+        info!("known, skipped");
+        return Ok(None);
+    }
+    let checksum = checksum.filter(|_| opts.checksum);
+    // a pipe in the way would otherwise hang the copy or conversion until something reads it
+    if action != Action::Symlink {
+        output::check_writable(dest)?;
+    }
+    // made as they're needed, so there are no empty ones for types a directory doesn't have
+    if opts.sort_by_type || opts.bucket_by_month || opts.group_bursts {
+        tokio::fs::create_dir_all(dest.parent().unwrap()).await?;
+    }
+
+    match action {
+        Action::Copy => {
+            copy_file(source, dest, progress, opts).await?;
+            if opts.video_poster && is_video(source) {
+                write_poster(source, dest).await;
+            }
+        }
+        Action::Symlink => {
+            let target = tokio::fs::read_link(source).await?;
+            tokio::fs::remove_file(dest).await.ok();
+            #[cfg(unix)]
+            tokio::fs::symlink(target, dest).await?;
+            #[cfg(windows)]
+            tokio::fs::symlink_file(target, dest).await?;
+        }
+        Action::Convert => {
+            // converted beside the existing output, to be compared with it once it's done
+            let replacing =
+                opts.on_conflict == OnConflict::Smaller && dest.symlink_metadata().is_ok();
+            let target = if replacing {
+                let name = format!("{}{}", PARTIAL_PREFIX, dest.file_name().unwrap());
+                dest.with_file_name(name)
+            } else {
+                dest.to_owned()
+            };
+            match convert_file(source, &target, data, limits, storage, progress, opts).await {
+                Err(err) if opts.fallback_copy => {
+                    warn!("copying as-is, conversion failed: {:#}", err);
+                    tokio::fs::remove_file(&target).await.ok();
+
+                    let dest = dest.with_extension(source.extension().unwrap());
+                    copy_file(source, &dest, progress, opts).await?;
+                    return Ok(Some(Processed {
+                        action: Action::Copy,
+                        checksum,
+                        thumbnail: None,
+                        difference: None,
+                        output_bytes: output_size(&[dest]).await,
+                    }));
+                }
+                Err(err) if replacing => {
+                    tokio::fs::remove_file(&target).await.ok();
+                    return Err(err);
+                }
+                result => {
+                    let (thumbnail, mut written) = result?;
+                    if replacing {
+                        written = keep_smaller(written).await?;
+                    }
+                    for output in &written {
+                        if opts.fsync {
+                            sync_file(output).await?;
+                        }
+                        // conversions are written with the default permissions, so the
+                        // attributes go on before the source's, which may be read-only
+                        if opts.preserve_xattrs {
+                            copy_xattrs(source, output, opts)?;
+                        }
+                        if opts.preserve_times {
+                            copy_times(source, output)?;
+                        }
+                        if opts.preserve_perms {
+                            copy_permissions(source, output, opts)?;
+                        }
+                        if let Some(command) = &opts.after_each {
+                            match run_after_each(command, source, output).await {
+                                Err(err) if opts.after_each_fatal => return Err(err),
+                                Err(err) => warn!("{:#}", err),
+                                Ok(()) => {}
+                            }
+                        }
+                    }
+                    // only PNGs can be compared, so JPEGs from `--png-max-size` are left out
+                    let wrote_png = opts.formats_for(source)[0] == Format::Png
+                        && written.first().is_some_and(|first| first == dest);
+                    let difference = if wrote_png {
+                        compare_output(dest, opts).await?
+                    } else {
+                        None
+                    };
+                    return Ok(Some(Processed {
+                        action,
+                        checksum,
+                        thumbnail,
+                        difference,
+                        output_bytes: output_size(&written).await,
+                    }));
+                }
+            }
+        }
+    }
+
+    Ok(Some(Processed {
+        action,
+        checksum,
+        thumbnail: None,
+        difference: None,
+        output_bytes: output_size(&[dest]).await,
+    }))
+}
+
+/// Adds up the sizes of `outputs`, counting symlinks as the links themselves.
+async fn output_size(outputs: &[impl AsRef<Utf8Path>]) -> u64 {
+    let mut size = 0;
+    for output in outputs {
+        size += tokio::fs::symlink_metadata(output.as_ref())
+            .await
+            .map_or(0, |meta| meta.len());
+    }
+    size
+}
+
+/// Moves each of the files converted for `--on-conflict smaller` over the existing output it was
+/// named after, unless that's no bigger, in which case the new one is removed instead. Returns
+/// the outputs that were replaced.
+async fn keep_smaller(partials: Vec<Utf8PathBuf>) -> Result<Vec<Utf8PathBuf>> {
+    let mut replaced = Vec::new();
+    for partial in partials {
+        let name = partial.file_name().unwrap();
+        let output = partial.with_file_name(name.strip_prefix(PARTIAL_PREFIX).unwrap_or(name));
+        let size = tokio::fs::metadata(&partial).await?.len();
+        match tokio::fs::metadata(&output).await {
+            Ok(existing) if existing.len() <= size => {
+                info!(
+                    "keeping '{}', which is {} bytes to the new {}",
+                    output,
+                    existing.len(),
+                    size
+                );
+                tokio::fs::remove_file(&partial).await?;
+            }
+            _ => {
+                tokio::fs::rename(&partial, &output).await?;
+                replaced.push(output);
+            }
+        }
+    }
+    Ok(replaced)
+}
+
+/// Checks a converted file against the file at the same path under `--compare`.
+async fn compare_output(dest: &Utf8Path, opts: &Options) -> Result<Option<String>> {
+    let Some(reference) = &opts.compare else {
+        return Ok(None);
+    };
+    let reference = reference.join(dest.strip_prefix(opts.format_output(opts.formats[0]))?);
+    let dest = dest.to_owned();
+    let tolerance = opts.compare_tolerance;
+    task::spawn_blocking(move || compare::compare(&dest, &reference, tolerance)).await?
+}
+
+/// Picks the `--sort-by-type` subdirectory for `source`.
+fn type_dir(source: &Utf8Path, action: Action) -> &'static str {
+    let ext = source.extension().unwrap_or_default().to_lowercase();
+    if action == Action::Convert || IMAGE_EXTENSIONS.contains(&ext.as_str()) {
+        "images"
+    } else if VIDEO_EXTENSIONS.contains(&ext.as_str()) {
+        "videos"
+    } else {
+        "other"
+    }
+}
+
+/// Picks the `--bucket-by-month` subdirectory for `source`, from the EXIF date of HEIF images
+/// and the modification time, in UTC, of everything else or images without one.
+fn month_bucket(source: &Utf8Path) -> String {
+    is_heif(source)
+        .then(|| exif_month(source))
+        .flatten()
+        .or_else(|| {
+            let modified = source.metadata().and_then(|meta| meta.modified()).ok()?;
+            Some(options::format_time(modified)[..7].to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Picks out the burst `source` was taken in for `--group-bursts`, from the timestamp in names
+/// like `IMG_1234_BURST20170814121015_COVER.JPG` or else the EXIF of HEIF images.
+fn burst_id(source: &Utf8Path) -> Option<String> {
+    let stem = source.file_stem()?;
+    if let Some((_, rest)) = stem.to_uppercase().split_once("_BURST") {
+        let end = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if end > 0 {
+            return Some(rest[..end].to_string());
+        }
+    }
+    is_heif(source).then(|| exif_burst(source)).flatten()
+}
+
+/// Whether `path` is named as a HEIF image to convert, which is Apple's `.HEIC`, the `.HEICS` of
+/// Apple's image sequences, the `.hif` of Canon and Sony cameras or a plain `.heif`, all in any
+/// case since Android phones write `.heic`. Sequences are converted like any file holding several
+/// images, to their primary image, or to an animated PNG with `--animated`.
+fn is_heif(path: &Utf8Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| HEIF_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Whether `path` is named like a dotfile, such as `.DS_Store` or `.Trashes`, which are left out
+/// of runs unless `--include-hidden` is given.
+fn is_hidden(path: &Utf8Path) -> bool {
+    path.file_name().is_some_and(|name| name.starts_with('.'))
+}
+
+fn is_video(path: &Utf8Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| VIDEO_EXTENSIONS.contains(&ext.to_lowercase().as_str()))
+}
+
+/// Writes the first frame of the video at `source` to `<name>_poster.png` beside its copy at
+/// `dest`. A missing ffmpeg or a frame it can't extract only costs the poster, not the copy.
+async fn write_poster(source: &Utf8Path, dest: &Utf8Path) {
+    if NO_FFMPEG.load(Ordering::Relaxed) {
+        return;
+    }
+    let poster = dest.with_file_name(format!("{}_poster.png", dest.file_stem().unwrap_or("")));
+    let output = tokio::process::Command::new("ffmpeg")
+        .args(["-nostdin", "-v", "error", "-y", "-i"])
+        .arg(source)
+        .args(["-frames:v", "1"])
+        .arg(&poster)
+        .stdin(process::Stdio::null())
+        .output()
+        .await;
+    match output {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => {
+            warn!(
+                "failed to extract a poster from '{}': {}",
+                source,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            tokio::fs::remove_file(&poster).await.ok();
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            if !NO_FFMPEG.swap(true, Ordering::Relaxed) {
+                warn!("ffmpeg isn't installed, so videos are copied without posters");
+            }
+        }
+        Err(err) => warn!("failed to run ffmpeg for '{}': {}", source, err),
+    }
+}
+
+/// Runs the `--after-each` command for `source`, converted to `output`. Its output is only kept
+/// for the error when it fails, as it would garble the display otherwise.
+async fn run_after_each(command: &[String], source: &Utf8Path, output: &Utf8Path) -> Result<()> {
+    let args: Vec<_> = command
+        .iter()
+        .map(|word| {
+            word.replace("{input}", source.as_str())
+                .replace("{output}", output.as_str())
+        })
+        .collect();
+    let result = tokio::process::Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(process::Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("failed to run '{}'", args[0]))?;
+    if !result.status.success() {
+        let stderr = String::from_utf8_lossy(&result.stderr);
+        let mut err = format!("'{}' failed with {}", args[0], result.status);
+        if !stderr.trim().is_empty() {
+            err += &format!(": {}", stderr.trim());
+        }
+        bail!(err);
+    }
+    Ok(())
+}
+
+/// Copies `source` to `dest` unchanged, or hardlinks it with `--hardlink-copies` if both are on
+/// the same filesystem. With `--reflink` the copy shares its blocks with the source where the
+/// filesystem supports it.
+async fn copy_file(
+    source: &Utf8Path,
+    dest: &Utf8Path,
+    progress: ProgressFn<'_>,
+    opts: &Options,
+) -> Result<()> {
+    if opts.hardlink_copies {
+        tokio::fs::remove_file(dest).await.ok();
+        match tokio::fs::hard_link(source, dest).await {
+            // a link shares the source's permissions and owner already
+            Ok(()) => return Ok(()),
+            Err(err) => info!("copying instead of linking: {}", err),
+        }
+    }
+
+    let reflinked = if opts.reflink {
+        let (src, dst) = (source.to_owned(), dest.to_owned());
+        match task::spawn_blocking(move || reflink(&src, &dst)).await? {
+            Ok(()) => true,
+            Err(err) => {
+                info!("copying instead of reflinking: {}", err);
+                false
+            }
+        }
+    } else {
+        false
+    };
+    if !reflinked {
+        let size = tokio::fs::metadata(source).await?.len();
+        if size >= STREAM_COPY_THRESHOLD {
+            stream_copy(source, dest, size, progress).await?;
+        } else {
+            match tokio::fs::copy(source, dest).await {
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                    info!("retrying the copy with a read-only open: {}", err);
+                    let (src, dst) = (source.to_owned(), dest.to_owned());
+                    task::spawn_blocking(move || copy_read_only(&src, &dst)).await??;
+                }
+                copied => {
+                    copied?;
+                }
+            }
+        }
+    }
+    if let Some(verify) = opts.verify {
+        verify_copy(source, dest, verify).await?;
+    }
+    if opts.fsync {
+        sync_file(dest).await?;
+    }
+
+    if opts.preserve_xattrs {
+        // the copy may have been given a read-only source's permissions already
+        with_owner_write(dest, || copy_xattrs(source, dest, opts))?;
+    }
+    if opts.preserve_times {
+        copy_times(source, dest)?;
+    }
+    if opts.preserve_perms {
+        copy_permissions(source, dest, opts)?;
+    }
+    Ok(())
+}
+
+/// Copies `source` to `dest` through a read-only open, for files `fs::copy` was refused on. The
+/// copy is given the permissions of the source where the output allows it, which network shares
+/// sometimes don't.
+fn copy_read_only(source: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    let mut reader = open_read_only(source)?;
+    let mut writer =
+        std::fs::File::create(dest).with_context(|| format!("failed to create '{}'", dest))?;
+    io::copy(&mut reader, &mut writer).with_context(|| format!("failed to write '{}'", dest))?;
+    set_copied_permissions(&reader, dest)
+}
+
+/// Gives a copy the permission bits of the source it was read from, like `fs::copy`, only
+/// warning when the output refuses them.
+fn set_copied_permissions(source: &std::fs::File, dest: &Utf8Path) -> Result<()> {
+    let permissions = source.metadata()?.permissions();
+    match std::fs::set_permissions(dest, permissions) {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            warn!(
+                "couldn't give '{}' the permissions of its source: {}",
+                dest, err
+            );
+            Ok(())
+        }
+        set => set.with_context(|| format!("failed to set permissions of '{}'", dest)),
+    }
+}
+
+/// Returns the SHA-256 sum of the file at `path` in hex, reading it a chunk at a time so that it
+/// never has to fit in memory.
+fn hash_file(path: &Utf8Path) -> Result<String> {
+    let mut file = open_read_only(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    loop {
+        let read = file
+            .read(&mut buf)
+            .with_context(|| format!("failed to read '{}'", path))?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Checks the copy at `dest` against `source` for `--verify`, removing it if it doesn't match so
+/// that a later run copies it again rather than taking it for done.
+async fn verify_copy(source: &Utf8Path, dest: &Utf8Path, verify: Verify) -> Result<()> {
+    let expected = tokio::fs::metadata(source).await?.len();
+    let size = tokio::fs::metadata(dest).await?.len();
+    let mismatch = if size != expected {
+        Some(format!(
+            "the copy is {} bytes but the source is {}",
+            size, expected
+        ))
+    } else if verify == Verify::Hash {
+        // streamed, since a copy that's being verified can be of any size
+        let (source, dest_path) = (source.to_owned(), dest.to_owned());
+        let expected = task::spawn_blocking(move || hash_file(&source)).await??;
+        let hash = task::spawn_blocking(move || hash_file(&dest_path)).await??;
+        (hash != expected).then(|| "the copy's SHA-256 sum doesn't match the source's".to_string())
+    } else {
+        None
+    };
+    if let Some(mismatch) = mismatch {
+        tokio::fs::remove_file(dest).await.ok();
+        bail!("{}, so it was removed", mismatch);
+    }
+    Ok(())
+}
+
+/// Flushes the file at `path` to disk for `--fsync`, along with the directory holding it so that
+/// the file can't go missing either. The file is opened read-only, which is enough to sync it
+/// and works for copies that were given a read-only source's permissions.
+async fn sync_file(path: &Utf8Path) -> Result<()> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open '{}' to flush it", path))?;
+    file.sync_all()
+        .await
+        .with_context(|| format!("failed to flush '{}' to disk", path))?;
+    // directories can't be opened for syncing on Windows, where the entry is written through
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let dir = tokio::fs::File::open(parent);
+        async { dir.await?.sync_all().await }
+            .await
+            .with_context(|| format!("failed to flush directory '{}' to disk", parent))?;
+    }
+    Ok(())
+}
+
+/// Copies `source` to `dest` a chunk at a time, reporting progress after each one. If `progress`
+/// asks for the copy to stop, the partial file is removed.
+async fn stream_copy(
+    source: &Utf8Path,
+    dest: &Utf8Path,
+    size: u64,
+    progress: ProgressFn<'_>,
+) -> Result<()> {
+    let src = source.to_owned();
+    let reader = task::spawn_blocking(move || open_read_only(&src)).await??;
+    let mut reader = tokio::fs::File::from_std(reader);
+    let mut writer = tokio::fs::File::create(dest).await?;
+    let mut buf = vec![0; COPY_CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            break;
+        }
+        writer.write_all(&buf[..len]).await?;
+        copied += len as u64;
+
+        if !progress(FileProgress::Copied(copied, size)) {
+            drop(writer);
+            tokio::fs::remove_file(dest).await.ok();
+            bail!("copy cancelled");
+        }
+    }
+    writer.flush().await?;
+
+    // matching `fs::copy`, which carries the permission bits over
+    let (reader, dest) = (reader.into_std().await, dest.to_owned());
+    task::spawn_blocking(move || set_copied_permissions(&reader, &dest)).await?
+}
+
+/// Converts a `--max-memory` limit into semaphore permits, which tokio caps at `u32::MAX` per
+/// acquisition.
+fn budget_mib(bytes: u64) -> u64 {
+    (bytes / MIB).clamp(1, u32::MAX as u64)
+}
+
+/// Converts `source` into `dest` and any other formats, within a decode turn the caller holds.
+async fn convert_file(
+    source: &Utf8Path,
+    dest: &Utf8Path,
+    data: Option<Vec<u8>>,
+    limits: &Limits,
+    storage: &Arc<dyn Storage>,
+    progress: ProgressFn<'_>,
+    opts: &Arc<Options>,
+) -> Result<(Option<Thumbnail>, Vec<Utf8PathBuf>)> {
+    let source = source.to_owned();
+    let dest = dest.to_owned();
+    let storage = storage.clone();
+    let opts = opts.clone();
+
+    let (_reserved, data) = match &limits.memory {
+        Some(memory) => {
+            let src = source.clone();
+            let (size, data) = task::spawn_blocking(move || {
+                let pixels = |data: Option<&[u8]>| {
+                    read_heif(&src, data).and_then(|ctx| {
+                        let handle = ctx.primary_image_handle()?;
+                        Ok(handle.width() as u64 * handle.height() as u64)
+                    })
+                };
+                match pixels(data.as_deref()) {
+                    // read the way the conversion would retry it, and kept for it
+                    Err(err) if data.is_none() && libheif_cant_open(&err) => {
+                        match read_source(&src) {
+                            Ok(data) => (pixels(Some(&data)), Some(data)),
+                            Err(err) => (Err(err), None),
+                        }
+                    }
+                    size => (size, data),
+                }
+            })
+            .await?;
+
+            // images too big for the whole budget still get converted, just one at a time
+            let budget = budget_mib(opts.max_memory.unwrap());
+            let mib = (size? * DECODE_BYTES_PER_PIXEL)
+                .div_ceil(MIB)
+                .clamp(1, budget);
+            (Some(memory.acquire_many(mib as u32).await?), data)
+        }
+        None => (None, data),
+    };
+
+    progress(FileProgress::Decoding);
+
+    // passed on from the blocking thread as it's awaited, keeping only the latest
+    let (encoded_tx, mut encoded) = watch::channel((0, 0));
+    let encoded_progress = move |done, total| {
+        encoded_tx.send_replace((done, total));
+    };
+    if opts.isolate_decodes {
+        // the child reads the file for itself, so `data` isn't needed here
+        let converted = isolate::convert(&source, &dest, &encoded_progress);
+        tokio::pin!(converted);
+        return loop {
+            tokio::select! {
+                converted = &mut converted => break converted,
+                Ok(()) = encoded.changed() => {
+                    let (done, total) = *encoded.borrow_and_update();
+                    progress(FileProgress::Encoded(done, total));
+                }
+            }
+        };
+    }
+
+    // blocking threads don't inherit the span of the file being processed
+    let span = Span::current();
+    let mut conversion = task::spawn_blocking(move || {
+        span.in_scope(|| {
+            heif_to_formats(
+                &source,
+                data.as_deref(),
+                &dest,
+                &*storage,
+                &encoded_progress,
+                &opts,
+            )
+            .map_err(|err| explain_grid(err, &source, data.as_deref()))
+        })
+    });
+    loop {
+        tokio::select! {
+            converted = &mut conversion => break converted?,
+            // stops matching once the conversion is done with the sender
+            Ok(()) = encoded.changed() => {
+                let (done, total) = *encoded.borrow_and_update();
+                progress(FileProgress::Encoded(done, total));
+            }
+        }
+    }
+}
+
+/// Checks whether `err` came from the output filesystem being full or read-only, which will
+/// fail every other file too.
+fn output_unwritable(err: &anyhow::Error) -> bool {
+    err.chain()
+        .any(|cause| match cause.downcast_ref::<io::Error>() {
+            Some(io_err) => io_unwritable(io_err),
+            None => cause
+                .downcast_ref::<png::EncodingError>()
+                .is_some_and(png_unwritable),
+        })
+}
+
+fn io_unwritable(err: &io::Error) -> bool {
+    if matches!(
+        err.kind(),
+        io::ErrorKind::StorageFull | io::ErrorKind::ReadOnlyFilesystem
+    ) {
+        return true;
+    }
+
+    let Some(inner) = err.get_ref() else {
+        return false;
+    };
+    if let Some(png_err) = inner.downcast_ref::<png::EncodingError>() {
+        return png_unwritable(png_err);
+    }
+    // some errors from the PNG encoder only keep the message of the original
+    #[cfg(unix)]
+    if err.kind() == io::ErrorKind::Other {
+        let message = inner.to_string();
+        return [libc::ENOSPC, libc::EROFS]
+            .into_iter()
+            .any(|code| io::Error::from_raw_os_error(code).to_string() == message);
+    }
+    false
+}
+
+/// The PNG encoder wraps IO errors without exposing them as a `source`, and its stream writer
+/// wraps its own errors in turn.
+fn png_unwritable(err: &png::EncodingError) -> bool {
+    matches!(err, png::EncodingError::IoError(io_err) if io_unwritable(io_err))
+}
+
+/// Makes `dest` a copy-on-write clone of `source`, with the same permissions as a regular copy.
+#[cfg(target_os = "linux")]
+fn reflink(source: &Utf8Path, dest: &Utf8Path) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let src = std::fs::File::open(source)?;
+    let dst = std::fs::File::create(dest)?;
+    // SAFETY: both descriptors stay open for the duration of the call
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } != 0 {
+        let err = io::Error::last_os_error();
+        drop(dst);
+        std::fs::remove_file(dest).ok();
+        return Err(err);
+    }
+    dst.set_permissions(src.metadata()?.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Utf8Path, _dest: &Utf8Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Gives `dest` the permissions of `source`, and its owner and group if the process is allowed to
+/// change them, which usually requires running as root, or else fails with `--strict`.
+fn copy_permissions(source: &Utf8Path, dest: &Utf8Path, opts: &Options) -> Result<()> {
+    let meta = std::fs::metadata(source)?;
+    std::fs::set_permissions(dest, meta.permissions())
+        .with_context(|| format!("failed to set permissions of '{}'", dest))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if let Err(err) = std::os::unix::fs::chown(dest, Some(meta.uid()), Some(meta.gid())) {
+            if opts.strict {
+                bail!("couldn't change owner of '{}' (--strict): {}", dest, err);
+            }
+            warn!("couldn't change owner of {}: {}", dest, err);
+        }
+    }
+    Ok(())
+}
+
+/// Gives `dest` the modification and access times of `source`, where they don't already match,
+/// such as for hard links. Setting them only takes owning the file rather than being able to
+/// write it, so it works on copies that already have a read-only source's permissions.
+fn copy_times(source: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    let meta = std::fs::metadata(source)?;
+    let modified = meta.modified()?;
+    if std::fs::metadata(dest)?.modified()? == modified {
+        return Ok(());
+    }
+    let times = std::fs::FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(modified);
+    std::fs::File::open(dest)
+        .and_then(|file| file.set_times(times))
+        .with_context(|| format!("failed to set times of '{}'", dest))
+}
+
+/// Runs `change` with the owner's write permission given to `path` for as long as it takes, for
+/// copies that already have a read-only source's permissions but still need attributes set,
+/// which takes write permission.
+fn with_owner_write(path: &Utf8Path, change: impl FnOnce() -> Result<()>) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let permissions = std::fs::metadata(path)?.permissions();
+        if permissions.mode() & 0o200 == 0 {
+            let writable = std::fs::Permissions::from_mode(permissions.mode() | 0o200);
+            std::fs::set_permissions(path, writable)
+                .with_context(|| format!("failed to set permissions of '{}'", path))?;
+            let changed = change();
+            std::fs::set_permissions(path, permissions)
+                .with_context(|| format!("failed to set permissions of '{}'", path))?;
+            return changed;
+        }
+    }
+    change()
+}
+
+/// Copies the extended attributes of `source`, such as Finder tags, onto `dest`. Attributes that
+/// can't be read or set, e.g. because the output filesystem doesn't support them or they belong
+/// to a namespace only root can write, are logged and skipped, or fail the file with `--strict`.
+fn copy_xattrs(source: &Utf8Path, dest: &Utf8Path, opts: &Options) -> Result<()> {
+    if !xattr::SUPPORTED_PLATFORM {
+        return Ok(());
+    }
+    let names = match xattr::list(source) {
+        Ok(names) => names,
+        Err(err) if opts.strict => {
+            bail!("couldn't list extended attributes (--strict): {}", err)
+        }
+        Err(err) => {
+            info!("couldn't list extended attributes: {}", err);
+            return Ok(());
+        }
+    };
+    for name in names {
+        let result = xattr::get(source, &name).and_then(|value| match value {
+            Some(value) => xattr::set(dest, &name, &value),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            if opts.strict {
+                bail!(
+                    "couldn't copy extended attribute {} (--strict): {}",
+                    name.to_string_lossy(),
+                    err
+                );
+            }
+            info!(
+                "couldn't copy extended attribute {}: {}",
+                name.to_string_lossy(),
+                err
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Asks `msg` on the terminal, where anything but `y` is a no. With `--yes` it's taken as
+/// answered, and without a terminal to answer on it's a no rather than a wait that never ends.
+/// It goes to stderr, like warnings, so that stdout only has what was asked for, which is nothing
+/// with `--quiet`.
+fn confirm(msg: &str, opts: &Options) -> bool {
+    eprint!("{} [y/N]: ", msg);
+    if opts.yes {
+        eprintln!("y");
+        return true;
+    }
+    if !termion::is_tty(&io::stdin()) {
+        eprintln!("N (stdin isn't a terminal, run with --yes to answer yes)");
+        return false;
+    }
+    io::stderr().flush().unwrap();
+
+    let mut res = String::new();
+    if io::stdin().read_line(&mut res).is_err() {
+        return false;
+    }
+    res.trim() == "y"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{num::NonZeroUsize, sync::atomic::AtomicBool};
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn decodes_are_bounded_across_directories() {
+        let dir = std::env::temp_dir().join(format!("mass-heif-convert-bounded-{}", process::id()));
+        let dir = Utf8PathBuf::from_path_buf(dir).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        let fixture = std::fs::read(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/fixtures/gradient.heic"
+        ))
+        .unwrap();
+        // many more files than turns, in more directories than turns, each of which brings its
+        // own `--io-jobs` workers
+        for d in 0..6 {
+            let photos = dir.join(format!("in/dir{}", d));
+            std::fs::create_dir_all(&photos).unwrap();
+            for f in 0..8 {
+                std::fs::write(photos.join(format!("{}.heic", f)), &fixture).unwrap();
+            }
+        }
+        let opts = Arc::new(Options {
+            inputs: vec![dir.join("in")],
+            output: dir.join("out"),
+            decode_jobs: NonZeroUsize::new(2).unwrap(),
+            ..Options::default()
+        });
+
+        let groups = plan_groups(&opts).unwrap();
+        assert_eq!(groups.len(), 6);
+        let files: usize = groups.iter().map(|group| group.jobs.len()).sum();
+        assert_eq!(files, 48);
+        let storage: Arc<dyn Storage> = output::storage(&opts.output).unwrap().into();
+        let (tx, mut rx) = mpsc::channel(EVENT_QUEUE_SIZE);
+        // kept, since the workers stop once nothing can pause them
+        let (_pause, paused) = watch::channel(false);
+
+        // watched from a thread of its own, so that it keeps up however busy the runtime is
+        let done = Arc::new(AtomicBool::new(false));
+        let watcher = {
+            let done = done.clone();
+            std::thread::spawn(move || {
+                let mut most = 0;
+                while !done.load(Ordering::Relaxed) {
+                    most = most.max(METRICS.decoding.load(Ordering::Relaxed));
+                }
+                most
+            })
+        };
+        spawn_file_processors(tx, paused, groups, storage, &opts).unwrap();
+        let mut finished = 0;
+        while finished < files {
+            match rx.recv().await.unwrap() {
+                Event::Progress { .. } => finished += 1,
+                Event::Err { file, err, .. } => panic!("{}: {}", file, err),
+                _ => {}
+            }
+        }
+        done.store(true, Ordering::Relaxed);
+        let most = watcher.join().unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert!(most >= 1, "no decodes were seen");
+        assert!(most <= 2, "{} decodes at once", most);
+        assert_eq!(METRICS.decoding.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn only_conflict_policies_that_need_them_remember_outputs() {
+        let dir = std::env::temp_dir().join(format!("mass-heif-convert-planned-{}", process::id()));
+        let dir = Utf8PathBuf::from_path_buf(dir).unwrap();
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("in")).unwrap();
+        for f in 0..4 {
+            std::fs::write(dir.join(format!("in/{}.txt", f)), "").unwrap();
+        }
+        let planned = |on_conflict| {
+            let opts = Options {
+                on_conflict,
+                ..Options::default()
+            };
+            let mut plan = DirPlan::new(dir.join("in"), dir.join("out"), Arc::default(), None);
+            for f in 0..4 {
+                let source = dir.join(format!("in/{}.txt", f));
+                let file_type = source.symlink_metadata().unwrap().file_type();
+                plan.plan(source, file_type, f + 1, &opts);
+            }
+            plan.planned.len()
+        };
+        let (overwrite, rename) = (planned(OnConflict::Overwrite), planned(OnConflict::Rename));
+        std::fs::remove_dir_all(&dir).ok();
+
+        assert_eq!(overwrite, 0);
+        assert_eq!(rename, 4);
+    }
 }
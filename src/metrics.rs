@@ -0,0 +1,148 @@
+use std::{
+    fmt::Write as _,
+    net::SocketAddr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tracing::debug;
+
+/// Counters for the whole run, served in the Prometheus text format with `--metrics-addr`.
+pub struct Metrics {
+    /// Files found to process, including ones done by an earlier run.
+    pub files: AtomicU64,
+    pub converted: AtomicU64,
+    pub copied: AtomicU64,
+    pub failed: AtomicU64,
+    pub skipped: AtomicU64,
+    /// Files being processed right now.
+    pub in_flight: AtomicU64,
+    /// Conversions holding one of the `--decode-jobs` turns right now.
+    pub decoding: AtomicU64,
+    /// Size of the source files processed so far.
+    pub bytes: AtomicU64,
+}
+
+pub static METRICS: Metrics = Metrics {
+    files: AtomicU64::new(0),
+    converted: AtomicU64::new(0),
+    copied: AtomicU64::new(0),
+    failed: AtomicU64::new(0),
+    skipped: AtomicU64::new(0),
+    in_flight: AtomicU64::new(0),
+    decoding: AtomicU64::new(0),
+    bytes: AtomicU64::new(0),
+};
+
+/// Largest request read before answering, which is plenty for a scraper's `GET`.
+const MAX_REQUEST: usize = 8192;
+
+/// Starts answering scrapes on `addr` in the background, failing only if it can't be listened on.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to listen on {} for metrics", addr))?;
+    task::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    task::spawn(async move {
+                        if let Err(err) = respond(stream).await {
+                            debug!("metrics request failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => debug!("failed to accept a metrics connection: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn respond(mut stream: TcpStream) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") && request.len() < MAX_REQUEST {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let response = if request.starts_with(b"GET /metrics ") || request.starts_with(b"GET / ") {
+        let body = render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn render() -> String {
+    let metrics = [
+        ("files", "gauge", "Files found to process", &METRICS.files),
+        (
+            "converted_total",
+            "counter",
+            "Files converted",
+            &METRICS.converted,
+        ),
+        (
+            "copied_total",
+            "counter",
+            "Files copied or linked as-is",
+            &METRICS.copied,
+        ),
+        (
+            "failed_total",
+            "counter",
+            "Files that failed to process",
+            &METRICS.failed,
+        ),
+        (
+            "skipped_total",
+            "counter",
+            "Files skipped for any reason",
+            &METRICS.skipped,
+        ),
+        (
+            "in_flight",
+            "gauge",
+            "Files being processed",
+            &METRICS.in_flight,
+        ),
+        (
+            "decoding",
+            "gauge",
+            "Conversions holding a decode turn",
+            &METRICS.decoding,
+        ),
+        (
+            "source_bytes_total",
+            "counter",
+            "Size of the source files processed",
+            &METRICS.bytes,
+        ),
+    ];
+
+    let mut out = String::new();
+    for (name, kind, help, value) in metrics {
+        let name = format!("mass_heif_{}", name);
+        writeln!(out, "# HELP {} {}.", name, help).unwrap();
+        writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+        writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).unwrap();
+    }
+    out
+}
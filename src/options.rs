@@ -0,0 +1,2385 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    net::SocketAddr,
+    num::{NonZeroU32, NonZeroUsize},
+    str::FromStr,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use libheif_rs::CompressionFormat;
+use once_cell::sync::Lazy;
+
+use crate::HEIF;
+
+const DEFAULT_IO_JOBS: usize = 10;
+
+/// What `auto` means for job counts, looked up once since it doesn't change while running.
+static CORES: Lazy<NonZeroUsize> =
+    Lazy::new(|| thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
+
+/// Config files looked up in the working directory when `--config` isn't given.
+const CONFIG_FILES: &[&str] = &["mass-heif.toml", ".mass-heif.toml"];
+
+/// Extensions of the HEIF images that are converted, in lowercase.
+pub const HEIF_EXTENSIONS: &[&str] = &["heic", "heics", "heif", "hif"];
+
+/// Extensions of the other images libheif reads, in lowercase, with the format it needs a decoder
+/// for to decode them.
+const OTHER_DECODABLE: &[(&str, CompressionFormat)] = &[
+    ("avif", CompressionFormat::Av1),
+    ("avifs", CompressionFormat::Av1),
+];
+
+/// Images larger than this are assumed to be corrupt rather than genuinely huge.
+const DEFAULT_MAX_PIXELS: u64 = 256 * 1024 * 1024;
+
+pub const USAGE: &str = "usage: mass-heif-convert <INPUT_DIR>... <OUTPUT_DIR> [OPTIONS]";
+
+const OPTIONS_HELP: &str = "\
+options:
+  -h, --help                  print this help
+  -V, --version               print the version, along with libheif's and its codecs
+      --doctor                check the codecs, directories and terminal without converting
+                              anything, where the directories are optional
+      --info                  list the size, bit depth and codec of each HEIC file a run would
+                              convert, without decoding them, where OUTPUT_DIR is left out
+      --print-config          print the options in effect after the config file, preset and
+                              flags as JSON, without converting anything
+  -q, --quiet                 print only errors, to stderr
+      --ui STYLE              show progress as a line per directory (tui, the default), a
+                              single line (compact) or a progress bar per directory (bars)
+      --compact               same as --ui compact
+      --notify                show a desktop notification when the run ends
+      --metrics-addr ADDR     serve counters for Prometheus at http://ADDR/metrics while running,
+                              e.g. 127.0.0.1:9100
+      --log-level FILTER      log error, warn, info (default) or debug messages, or filter them
+                              like RUST_LOG, which is used when this isn't given
+      --log-file FILE         write the log to FILE, or - for stderr (default: log.txt)
+      --config FILE           read default options from FILE (default: ./mass-heif.toml)
+      --preset NAME           apply a preset, see below
+      --depth N               convert directories N levels below each input (default 1)
+      --map FILE              rename output directories, with a line of the form
+                              'INPUT_SUBDIR => OUTPUT_SUBDIR' in FILE for each one
+      --collapse-single       leave directories out of output paths when they're the only
+                              thing in their parent, such as 'Camera Roll' in
+                              input/Camera Roll
+      --relative-to BASE      mirror each directory's path relative to BASE in the output,
+                              instead of its path relative to its input
+      --per-input-subdir      give each input its own directory in the output, named after it,
+                              instead of merging them all into the output
+      --pick                  pick which of the directories found to convert from a checklist
+                              on the terminal before starting
+      --follow-symlinks       follow symlinks instead of skipping linked directories and
+                              recreating linked files as links
+      --keep-misc             copy .MISC directories into the output as-is instead of skipping
+                              them
+      --include-hidden        convert and copy files and directories whose names start with a
+                              dot, like .DS_Store, which are otherwise left out
+      --max-pixels N          reject images larger than N pixels, 0 for no limit
+      --keep-going            carry on after files fail instead of stopping at the first error
+      --max-errors N          carry on after errors, but stop with exit code 3 once more than N
+                              files have failed
+      --max-runtime DURATION  stop starting files once the run has gone on for DURATION, such
+                              as 2h or 1h30m, and exit with code 6 when those already started
+                              are done
+      --stop-directory-on-error
+                              skip the rest of a directory after one of its files fails, while
+                              other directories carry on
+      --fallback-copy         copy HEIF files that fail to convert as-is
+      --copy-only             copy every file as-is, HEIF files included, to convert later
+      --images-only           only write converted images, skipping every other file instead
+                              of copying it
+      --hardlink-copies       hardlink files that aren't converted instead of copying them,
+                              where the output is on the same filesystem
+      --ignore-space          start even if the output looks too small for the converted files
+      --force                 start even if another run is writing to the output directory
+  -y, --yes                   answer yes to the question before overwriting files, which is
+                              otherwise answered no when stdin isn't a terminal
+      --update                only convert files whose output is missing or older than the source
+      --on-conflict POLICY    what to do with outputs that already exist: overwrite, skip,
+                              rename, which writes 'name (1).png' and so on, or smaller, which
+                              keeps whichever of the old and new output is smaller (default:
+                              ask before overwriting)
+      --rewrite-if-smaller    same as --on-conflict smaller
+      --since TIME            only handle files modified at or after TIME, see below
+      --until TIME            only handle files modified before TIME
+      --changed-only          only handle files that git status lists as new or modified in
+                              the git repository each input is in
+      --resume                skip files finished by an interrupted run into the same output
+      --trim-empty-output     remove output directories created by the run that end up empty
+      --contact-sheet         write a grid of thumbnails for each directory to
+                              OUTPUT_DIR/<dir>_contactsheet.png
+      --sidecar-thumbnails SIZE
+                              also write an sRGB JPEG at most SIZE pixels either way beside
+                              each converted image, as NAME.thumb.jpg, e.g. 256
+      --gallery               write an index.html into OUTPUT_DIR and each directory in it once
+                              the run is done, showing the images as thumbnails, from
+                              --sidecar-thumbnails where there are any
+      --video-poster          also write NAME_poster.png from the first frame of each copied
+                              video, using ffmpeg where it's installed
+      --after-each CMD        run CMD after each file is converted, split into words at spaces,
+                              with {input} and {output} replaced by the file's paths
+      --after-each-fatal      count a file as failed when its --after-each command fails,
+                              rather than only logging it
+      --on-complete CMD       run CMD once the run is over, with its exit status and totals in
+                              MASS_HEIF_STATUS, MASS_HEIF_COMPLETED, MASS_HEIF_FAILED,
+                              MASS_HEIF_BYTES and MASS_HEIF_ELAPSED (in seconds)
+      --compare REF_DIR       check converted files against the PNGs at the same paths in
+                              REF_DIR, listing any that differ and exiting with code 5
+      --compare-tolerance N   let pixel values differ from REF_DIR by up to N (default: 0)
+      --checksum              write SHA-256 sums of the sources to checksums.txt in the output
+      --csv FILE              write a row for each file processed to FILE, with its action,
+                              status, input and output sizes, time taken and any error
+      --save-status FILE      write the final line of each directory and the totals to FILE as
+                              plain text once the run ends
+      --verify LEVEL          check every copy against its source once it's written, by size
+                              or by size and SHA-256 (hash), failing copies that don't match
+      --rules FILE            convert, copy or skip files by extension as the TOML tables in
+                              FILE say, e.g. [bmp] with an action of skip, or [heic] with one
+                              of convert and a format or quality, with the rest handled as
+                              usual
+      --skip-hashes FILE      skip sources whose SHA-256 sum is listed in FILE, one per line
+                              as in checksums.txt
+      --reflink               make copy-on-write copies of files that aren't converted, on
+                              filesystems that support it (Linux only)
+      --preserve-perms        give output files and directories the permissions and owner of
+                              their source
+      --preserve-xattrs       copy extended attributes, such as Finder tags, onto output files
+      --preserve-times        give output files the modification time of their source
+      --fsync                 flush each output file to disk before counting it as done
+      --io-jobs N             files open at once per directory, shared with other
+                              directories once it runs out of work (default 10)
+      --max-active-dirs N     process at most N directories at a time, starting the next as
+                              one runs out of work
+      --stream                start on each directory while it's still being listed, holding
+                              only a few batches of it at a time, without the checks for free
+                              space, overwrites and inputs with nothing to convert, in memory
+                              that doesn't grow with the directory unless --on-conflict is skip
+                              or rename, which have to remember every output name
+  -j, --decode-jobs N         images decoded at once across all directories, or auto for the
+                              number of cores (default), also as --jobs
+      --autotune              time a sample of the files at several --decode-jobs levels first,
+                              then convert with the fewest jobs that come close to the fastest
+      --autotune-only         print the --decode-jobs that --autotune picks without converting
+      --runtime-threads N     run the async work for listing, copying and writing on N
+                              threads, rather than one per core
+      --animated              convert files holding several images, such as bursts and
+                              .HEICS sequences, to animated PNGs and WebPs rather than just
+                              their primary image
+      --decode-scale SCALE    convert at 1/2, 1/4 or 1/8 of the full size, from the embedded
+                              thumbnail where it's big enough
+      --thumbnail-above MP    convert images over MP megapixels, e.g. 50, from their embedded
+                              thumbnail instead, where they have one
+      --resize WxH            make converted images exactly W by H pixels, e.g. 1920x1080
+      --max-dimension N       shrink converted images whose longer side is over N pixels to
+                              fit, keeping their shape, unless --resize is given
+      --fit MODE              how --resize fits images of another shape: contain (the default)
+                              pads them, cover crops them and stretch distorts them
+      --pad-color RRGGBB      color of the padding from --fit contain (default: 000000)
+      --decoder ID            decode with the libheif decoder plugin ID, see --version
+      --isolate-decodes       convert each image in a process of its own, so a file that
+                              crashes the decoder fails alone instead of ending the run
+      --strict                fail files over what's otherwise worked around: no color profile,
+                              more than 8 bits, no EXIF with --exif-orient, and attributes or
+                              owners that --preserve-xattrs or --preserve-perms can't copy
+      --exif-orient           rotate and flip images as their EXIF Orientation tag says, for
+                              files that don't store the rotation in the HEIF container
+      --max-memory SIZE       hold off on decodes that would take the estimated memory use over
+                              SIZE, e.g. 4G or 512M
+      --memory-high-water SIZE
+                              hold off on starting decodes while this process is measured using
+                              more than SIZE of memory, until it's back under the low water mark
+      --memory-low-water SIZE where decodes start again after --memory-high-water
+                              (default: 3/4 of it)
+      --batch-size N          files handled one after another by each task (default 1)
+      --format FORMAT         write converted images as png (the default), jpeg or webp, given
+                              more than once to write each from a single decode, beside each
+                              other
+      --format-subdirs        write each format under a directory of the output named after it,
+                              such as png and jpeg, the first along with the copied files
+      --jpeg-encoder ENCODER  encode JPEGs with the built-in encoder (baseline, the default) or
+                              mozjpeg, for smaller files at the same quality, in builds with
+                              the mozjpeg feature
+      --jpeg-quality Q        quality of JPEGs, from 1 to 100 (default 85)
+      --progressive           write progressive JPEGs, which show in full at a lower quality
+                              first on slow connections and are often a little smaller
+      --webp-quality Q        quality of lossy WebPs, from 0 to 100 (default 85)
+      --webp-lossless         write WebPs losslessly, which makes them several times bigger
+      --compression LEVEL     PNG compression: fast, default or best (default)
+      --png-filter FILTER     PNG row filter: none, sub, up, avg, paeth or adaptive
+      --adaptive-png          pick the compression and filter of each PNG from its content,
+                              best and none for flat graphics, fast and paeth for photos,
+                              in place of --compression and --png-filter
+      --color-chunk CHUNK     mark PNGs as sRGB (srgb), or with a gamma of 2.2 (gamma), for
+                              viewers that would otherwise guess (default: none)
+      --quantize MODE         write PNGs with a palette of up to 256 colors: auto for images
+                              with few enough colors not to be photos, or always
+      --bit-reduce N          dither true-color PNGs down to N bits per channel, from 1 to 7,
+                              for smaller files where banding is acceptable
+      --embed-source-name     record the names of each PNG's source file and directory in
+                              Source and Source Directory text chunks
+      --strip-metadata        leave the source's whole EXIF block, with its GPS tags, dates and
+                              maker notes, and its ICC profile out of PNGs, also as --strip;
+                              JPEG and WebP output never carries either
+      --scrub-gps             leave only the GPS tags out of the EXIF block copied into PNGs,
+                              keeping the camera, date and orientation
+      --png-max-size SIZE     write a JPEG instead of any PNG that would be bigger than SIZE,
+                              e.g. 5M, for photos that don't compress well losslessly
+      --extension-case CASE   output extension case: preserve (default), lower or upper
+      --normalize-names FORM  Unicode-normalize output names: nfc or nfd
+      --lowercase-names       lowercase the whole name of every output file, extension included
+      --sort-by-type          put files into images, videos and other subdirectories of their
+                              directory's output
+      --bucket-by-month       put files into YYYY-MM subdirectories of their directory's output,
+                              by EXIF capture date or else modification time
+      --group-bursts          put the photos of each burst into a burst-ID subdirectory
+      --suffix STR            add STR to the names of converted files, before the extension
+      --name-template TEMPLATE
+                              name output files from TEMPLATE, see below
+      --global-index          number files for {index} across all directories, in the order
+                              they're shown, rather than from 1 in each
+";
+
+/// Named bundles of defaults for common kinds of run, which explicit flags still override.
+pub const PRESETS: &[(&str, &str)] = &[
+    (
+        "fast",
+        "fast PNG compression and twice as many jobs as cores",
+    ),
+    (
+        "archive",
+        "smallest lossless PNGs keeping all metadata and times, with hash-verified copies",
+    ),
+    (
+        "web",
+        "JPEGs at quality 80, at most 2048 pixels on a side, without metadata",
+    ),
+];
+
+/// Settings for a conversion run, parsed from the command line.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Print the `--help` text and exit, in which case nothing else is checked.
+    pub help: bool,
+    /// Print the version and exit, in which case nothing else is checked.
+    pub version: bool,
+    /// Report on the environment instead of converting, in which case the directories are
+    /// optional.
+    pub doctor: bool,
+    /// List the HEIC files in the inputs instead of converting, in which case there's no output
+    /// directory.
+    pub info: bool,
+    /// Print the options as JSON and exit.
+    pub print_config: bool,
+    /// Input directories, each containing the directories to convert.
+    pub inputs: Vec<Utf8PathBuf>,
+    pub output: Utf8PathBuf,
+
+    /// How many directory levels below each input to descend before converting each directory as
+    /// a group, mirroring the levels above in the output.
+    pub depth: NonZeroUsize,
+    /// Output paths, relative to `output`, for directories that shouldn't mirror their path
+    /// relative to the input.
+    pub dir_map: HashMap<Utf8PathBuf, Utf8PathBuf>,
+    /// Drop a directory from output paths when its parent holds nothing else, so that a wrapper
+    /// around the only directory with anything in it doesn't add a level to the output.
+    pub collapse_single: bool,
+    /// Directory that output paths mirror the path below, or `None` for each directory's input.
+    pub relative_to: Option<Utf8PathBuf>,
+    /// Whether each input's directories go under a directory of the output named after the
+    /// input, rather than straight into the output alongside those of other inputs.
+    pub per_input_subdir: bool,
+    /// Whether the directories to convert are picked on the terminal before the run starts.
+    pub pick: bool,
+    /// Treat symlinks as the files and directories they point to, instead of skipping symlinked
+    /// directories and recreating symlinked files as links.
+    pub follow_symlinks: bool,
+    /// Copy the files in `.MISC` directories into the output unchanged, instead of skipping the
+    /// directories.
+    pub keep_misc: bool,
+    /// Handle dotfiles and dot directories like any others instead of leaving them out.
+    pub include_hidden: bool,
+
+    /// Suppress the progress display, printing only per-file errors to stderr.
+    pub quiet: bool,
+    /// How progress is shown, unless `quiet` is set.
+    pub ui: Ui,
+    /// Show a desktop notification with the totals once the run ends.
+    pub notify: bool,
+    /// Address to serve Prometheus metrics on during the run.
+    pub metrics_addr: Option<SocketAddr>,
+    /// Which log messages to keep, in `RUST_LOG` syntax, or `None` to go by `RUST_LOG` itself.
+    pub log_level: Option<String>,
+    /// Where log messages are appended, with `-` for stderr.
+    pub log_file: Utf8PathBuf,
+    /// Reject images with more pixels than this before decoding them, or 0 for no limit.
+    pub max_pixels: u64,
+    /// Process the remaining files after one fails, instead of stopping the run.
+    pub keep_going: bool,
+    /// Like `keep_going`, but stop anyway once more than this many files have failed.
+    pub max_errors: Option<usize>,
+    /// How long the run may go on for before it's stopped, or `None` for no limit.
+    pub max_runtime: Option<Duration>,
+    /// After a file fails, skip the rest of its directory but carry on with the others.
+    pub stop_directory_on_error: bool,
+    /// Copy HEIF files that fail to convert as-is instead of reporting an error.
+    pub fallback_copy: bool,
+    /// Copy HEIF files like any other file instead of converting them.
+    pub copy_only: bool,
+    /// Leave out everything that isn't converted, such as sidecar and video files, instead of
+    /// copying it.
+    pub images_only: bool,
+    /// Skip the check that the output filesystem has room for the estimated output size.
+    pub ignore_space: bool,
+    /// Start even if another run holds the lock on the output directory.
+    pub force: bool,
+    /// Go ahead without asking where the run would ask first.
+    pub yes: bool,
+    /// Skip files whose output exists and is at least as new as the source, and replace the rest
+    /// without asking.
+    pub update: bool,
+    /// What to do when an output already exists, unless `update` replaces it.
+    pub on_conflict: OnConflict,
+    /// Skip source files last modified before this.
+    pub since: Option<SystemTime>,
+    /// Skip source files last modified at or after this.
+    pub until: Option<SystemTime>,
+    /// Skip source files that git doesn't list as untracked, added or modified.
+    pub changed_only: bool,
+    /// Skip files listed in the checkpoint left in the output directory by an earlier run that
+    /// didn't finish.
+    pub resume: bool,
+    /// Remove the output directories this run created if nothing was written to them.
+    pub trim_empty_output: bool,
+    /// Write a PNG of thumbnails of the converted images in each directory once it's done.
+    pub contact_sheet: bool,
+    /// Write a JPEG of each converted image scaled to fit in this many pixels either way beside
+    /// its output, from the same decode, for galleries to load quickly.
+    pub sidecar_thumbnails: Option<NonZeroU32>,
+    /// Write a static HTML page into the output and each directory in it at the end of the run,
+    /// for browsing what was converted.
+    pub gallery: bool,
+    /// Extract the first frame of copied videos into a PNG beside the copy.
+    pub video_poster: bool,
+    /// Command run after each file is converted, as a program and its arguments, with `{input}`
+    /// and `{output}` in them replaced by the paths of the file.
+    pub after_each: Option<Vec<String>>,
+    /// Fail files whose `after_each` command fails, rather than just logging a warning.
+    pub after_each_fatal: bool,
+    /// Command run once the run is over, split into words like `after_each`.
+    pub on_complete: Option<Vec<String>>,
+    /// Output directory of an earlier run to compare converted files with.
+    pub compare: Option<Utf8PathBuf>,
+    /// How far each channel of a pixel may be from the one in `compare` before it counts as
+    /// different.
+    pub compare_tolerance: u16,
+    /// Hash every source file and write the sums to a manifest in the output directory.
+    pub checksum: bool,
+    /// Where to write a CSV report with a row for each file processed, or `None` for nowhere.
+    pub csv: Option<Utf8PathBuf>,
+    /// Where to write the display's final state as plain text, or `None` for nowhere.
+    pub save_status: Option<Utf8PathBuf>,
+    /// How closely copies are checked against their sources after they're written.
+    pub verify: Option<Verify>,
+    /// SHA-256 sums, in lowercase hex, of sources to skip as already archived.
+    pub skip_hashes: HashSet<String>,
+    /// What to do with files of each extension from `--rules`, keyed by the extension in
+    /// lowercase. Files with other extensions are converted or copied as they would be anyway.
+    pub rules: BTreeMap<String, Rule>,
+    /// Hardlink files that would otherwise be copied, falling back to a copy if linking fails.
+    pub hardlink_copies: bool,
+    /// Clone files that would otherwise be copied so they share blocks with the source, falling
+    /// back to a copy where the filesystem doesn't support it.
+    pub reflink: bool,
+    /// Copy the permissions of source files and directories onto their outputs, along with the
+    /// owner where the process is allowed to change it.
+    pub preserve_perms: bool,
+    /// Copy the extended attributes of source files onto their outputs, where the platform and
+    /// filesystems support them.
+    pub preserve_xattrs: bool,
+    /// Give output files the modification and access times of their source.
+    pub preserve_times: bool,
+    /// Flush each output file and its directory entry to disk before the file is counted as done,
+    /// so that nothing reported finished is lost to a crash or power cut.
+    pub fsync: bool,
+
+    /// How many files each directory may have open at once, though its workers move on to other
+    /// directories once it has nothing left to start.
+    pub io_jobs: NonZeroUsize,
+    /// How many directories may have files waiting to be started at once, with the rest started
+    /// in order as those run out, or `None` to start them all together.
+    pub max_active_dirs: Option<NonZeroUsize>,
+    /// List each directory as its files are processed rather than planning every file before
+    /// starting, so huge directories don't have to be held in memory.
+    pub stream: bool,
+    /// How many images may be decoded and encoded at once across all directories.
+    pub decode_jobs: NonZeroUsize,
+    /// Benchmark a sample of the files before the run and replace `decode_jobs` with the fewest
+    /// that come close to the best throughput.
+    pub autotune: bool,
+    /// Stop after printing what `autotune` picked.
+    pub autotune_only: bool,
+    /// How many worker threads the tokio runtime has, or `None` for one per core. Decodes run
+    /// on blocking threads, so this doesn't limit them.
+    pub runtime_threads: Option<NonZeroUsize>,
+    /// Encode every top-level image of files that have several as frames of an animated PNG or
+    /// WebP.
+    pub animated: bool,
+    /// Divisor for the dimensions of converted images, or `None` for full size.
+    pub decode_scale: Option<NonZeroU32>,
+    /// Size in megapixels over which images are converted from their largest embedded thumbnail
+    /// instead, or `None` to always decode the full image.
+    pub thumbnail_above: Option<f64>,
+    /// Exact width and height for converted images, or `None` to keep their size.
+    pub resize: Option<(u32, u32)>,
+    /// Longest side converted images are shrunk to fit within when `resize` isn't given, or
+    /// `None` to keep their size.
+    pub max_dimension: Option<NonZeroU32>,
+    /// How images of another shape are brought to the `resize` dimensions.
+    pub fit: Fit,
+    /// Color of the borders `Fit::Contain` pads images out with, as red, green and blue.
+    pub pad_color: [u8; 3],
+    /// Id of the libheif decoder to use, or `None` to let libheif pick one for each image.
+    pub decoder: Option<String>,
+    /// Convert each image in a child process, which libheif crashing on a malformed file only
+    /// takes down along with that one file.
+    pub isolate_decodes: bool,
+    /// Apply the EXIF Orientation tag to images without container transforms.
+    pub exif_orient: bool,
+    /// Fail files over the things about them that are normally worked around, for pipelines that
+    /// would rather know.
+    pub strict: bool,
+    /// Estimated memory in bytes that decodes in flight may use between them, on top of the
+    /// `decode_jobs` limit.
+    pub max_memory: Option<u64>,
+    /// Resident memory in bytes over which no more decodes are started, as measured rather than
+    /// estimated, or `None` not to measure it.
+    pub memory_high_water: Option<u64>,
+    /// Resident memory in bytes that decodes held off by `memory_high_water` wait to get under.
+    pub memory_low_water: Option<u64>,
+    /// Number of files each spawned task converts in sequence. Larger batches cut down on task
+    /// overhead for directories full of small files.
+    pub batch_size: NonZeroUsize,
+
+    /// Formats each converted image is written in, the first under the name it's planned with
+    /// and the rest beside it under their own extensions.
+    pub formats: Vec<Format>,
+    /// Whether each format is written to its own tree under the output rather than side by side.
+    pub format_subdirs: bool,
+    /// Encoder for JPEG output.
+    pub jpeg_encoder: JpegEncoder,
+    /// Quality of JPEG output, from 1 to 100.
+    pub jpeg_quality: u8,
+    /// Write progressive rather than baseline JPEGs.
+    pub progressive: bool,
+    /// Quality of lossy WebP output, from 0 to 100, which looks about as good as a JPEG's a few
+    /// points higher.
+    pub webp_quality: u8,
+    /// Write WebPs losslessly, which ignores `webp_quality`.
+    pub webp_lossless: bool,
+    /// Compression level for PNG output.
+    pub compression: png::Compression,
+    /// Row filter for PNG output, or `None` to leave it to the encoder.
+    pub png_filter: Option<PngFilter>,
+    /// Whether `compression` and `png_filter` are chosen for each image by sampling its pixels.
+    pub adaptive_png: bool,
+    /// Color space chunk for PNG output, or `None` to write none. It's left out of images whose
+    /// nclx profile puts them in another color space, which get a `cICP` chunk saying so instead.
+    pub color_chunk: Option<ColorChunk>,
+    /// When to write PNGs with a palette rather than true color, or `None` for never.
+    pub quantize: Option<Quantize>,
+    /// Bits per color channel to dither true-color PNGs down to, or `None` to keep all 8.
+    pub bit_reduce: Option<u8>,
+    /// Write the names of the source file and its directory into text chunks of each PNG.
+    pub embed_source_name: bool,
+    /// Leave the source's EXIF block and ICC profile out of PNGs, which otherwise carry them.
+    pub strip_metadata: bool,
+    /// Remove the GPS IFD from the EXIF block copied into PNGs, keeping the rest of it.
+    pub scrub_gps: bool,
+    /// Largest PNG to write, in bytes, with bigger ones written as JPEGs instead.
+    pub png_max_size: Option<u64>,
+
+    /// Casing of output file extensions.
+    pub extension_case: ExtensionCase,
+    /// Unicode normalization form for output names, or `None` to keep them as they are.
+    pub normalize_names: Option<NormalizationForm>,
+    /// Lowercase output file names entirely, including the extension and any suffix.
+    pub lowercase_names: bool,
+    /// Write each directory's files into `images`, `videos` and `other` subdirectories of its
+    /// output, by extension.
+    pub sort_by_type: bool,
+    /// Write each directory's files into a `YYYY-MM` subdirectory of its output for the month
+    /// they were taken, or `unknown` when there's no telling.
+    pub bucket_by_month: bool,
+    /// Write the photos of each burst into a `burst-ID` subdirectory of where they'd go, going by
+    /// Apple's `_BURST` names or the burst identifier in their EXIF maker note.
+    pub group_bursts: bool,
+    /// Text added to the end of converted file names, before the extension.
+    pub suffix: Option<String>,
+    /// Template for output file names, or `None` to keep the source names.
+    pub name_template: Option<NameTemplate>,
+    /// Whether `{index}` counts the files of every directory in turn rather than each on its own.
+    pub global_index: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Ui {
+    /// A line per directory, redrawn in place.
+    Tui,
+    /// The totals on a single line.
+    Compact,
+    /// A progress bar per directory and one for the totals.
+    Bars,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ExtensionCase {
+    /// Keep the source extension's case, and give converted files the same case as their source.
+    Preserve,
+    Lower,
+    Upper,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum NormalizationForm {
+    /// Composed, as most Linux and Windows software produces.
+    Nfc,
+    /// Decomposed, as Apple filesystems and devices tend to produce.
+    Nfd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OnConflict {
+    /// Overwrite, once the user agrees to.
+    Ask,
+    Overwrite,
+    /// Leave the existing output alone and count the file as done.
+    Skip,
+    /// Write to the first free name with a number added in parentheses.
+    Rename,
+    /// Convert to a temporary file, and replace the existing output with it only if it's
+    /// smaller, so that rerunning with other settings never makes an output bigger.
+    Smaller,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum PngFilter {
+    /// Use the same filter for every row.
+    Fixed(png::FilterType),
+    /// Pick the best filter for each row individually.
+    Adaptive,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorChunk {
+    /// An `sRGB` chunk, along with the `gAMA` and `cHRM` chunks that stand in for it.
+    Srgb,
+    /// Just a `gAMA` chunk, for the sRGB transfer curve's usual approximation as a gamma of 2.2.
+    Gamma,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verify {
+    /// The copy is the same size as the source.
+    Size,
+    /// The copy also has the same SHA-256 sum as the source.
+    Hash,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Quantize {
+    /// Only for images with so few colors that they're probably graphics or screenshots, which
+    /// come through a palette looking the same, unlike photos.
+    Auto,
+    /// For every image, photos included.
+    Always,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fit {
+    /// Scale to fit within the dimensions, padding out the rest.
+    Contain,
+    /// Scale to cover the dimensions, cropping off whatever's left over from the middle out.
+    Cover,
+    /// Scale each axis on its own, changing the aspect ratio.
+    Stretch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Png,
+    /// At `--jpeg-quality`, by default high enough that photos don't visibly suffer.
+    Jpeg,
+    /// Lossy at `--webp-quality` unless `--webp-lossless`, smaller than JPEGs and with alpha and
+    /// animation.
+    Webp,
+}
+
+/// What `--rules` says to do with the files of one extension.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Rule {
+    /// Convert them, which only images libheif can decode can be, to `formats` if there are any
+    /// instead of `--format`'s, and at `quality` if it's given instead of `--jpeg-quality` and
+    /// `--webp-quality`.
+    Convert {
+        formats: Vec<Format>,
+        quality: Option<u8>,
+    },
+    Copy,
+    /// Leave them out of the output.
+    Skip,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Rule::Convert { formats, quality } => {
+                let names: Vec<_> = formats.iter().map(|format| format.name()).collect();
+                write!(f, "convert to {}", names.join(", "))?;
+                match quality {
+                    Some(quality) => write!(f, " at quality {}", quality),
+                    None => Ok(()),
+                }
+            }
+            Rule::Copy => write!(f, "copy"),
+            Rule::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JpegEncoder {
+    /// The pure Rust `jpeg-encoder` crate, which every build has.
+    Baseline,
+    /// libjpeg-turbo with mozjpeg's trellis quantization and progressive scans, which makes
+    /// files noticeably smaller at the same quality, at the cost of encoding time.
+    Mozjpeg,
+}
+
+impl Format {
+    /// The name `--format` takes.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpeg",
+            Format::Webp => "webp",
+        }
+    }
+
+    /// Extension of the files written in this format, in lowercase.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Png => "png",
+            Format::Jpeg => "jpg",
+            Format::Webp => "webp",
+        }
+    }
+}
+
+/// An output file name made of literal text and `{token}` placeholders, not including the
+/// extension.
+#[derive(Clone, Debug)]
+pub struct NameTemplate(Vec<NamePart>);
+
+#[derive(Clone, Debug)]
+enum NamePart {
+    Literal(String),
+    /// The source file name without its extension.
+    Stem,
+    /// The name of the directory the file is in.
+    Dir,
+    /// The file's position in its directory, counting from 1 in name order, padded with zeros to
+    /// the given width.
+    Index(usize),
+}
+
+/// Writes the template back out in the form `--name-template` takes.
+impl Display for NameTemplate {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for part in &self.0 {
+            match part {
+                NamePart::Literal(text) => f.write_str(text)?,
+                NamePart::Stem => f.write_str("{stem}")?,
+                NamePart::Dir => f.write_str("{dir}")?,
+                NamePart::Index(0) => f.write_str("{index}")?,
+                NamePart::Index(width) => write!(f, "{{index:{}}}", width)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl NameTemplate {
+    pub fn render(&self, dir: &str, stem: &str, index: usize) -> String {
+        let mut name = String::new();
+        for part in &self.0 {
+            match part {
+                NamePart::Literal(text) => name += text,
+                NamePart::Stem => name += stem,
+                NamePart::Dir => name += dir,
+                NamePart::Index(width) => name += &format!("{:0width$}", index, width = width),
+            }
+        }
+        name
+    }
+
+    fn has_index(&self) -> bool {
+        self.0.iter().any(|part| matches!(part, NamePart::Index(_)))
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            help: false,
+            version: false,
+            doctor: false,
+            info: false,
+            print_config: false,
+            inputs: Vec::new(),
+            output: Utf8PathBuf::new(),
+
+            depth: NonZeroUsize::MIN,
+            dir_map: HashMap::new(),
+            collapse_single: false,
+            relative_to: None,
+            per_input_subdir: false,
+            pick: false,
+            follow_symlinks: false,
+            keep_misc: false,
+            include_hidden: false,
+
+            quiet: false,
+            ui: Ui::Tui,
+            notify: false,
+            metrics_addr: None,
+            log_level: None,
+            log_file: "log.txt".into(),
+            max_pixels: DEFAULT_MAX_PIXELS,
+            keep_going: false,
+            max_errors: None,
+            max_runtime: None,
+            stop_directory_on_error: false,
+            fallback_copy: false,
+            copy_only: false,
+            images_only: false,
+            ignore_space: false,
+            force: false,
+            yes: false,
+            update: false,
+            on_conflict: OnConflict::Ask,
+            since: None,
+            until: None,
+            changed_only: false,
+            resume: false,
+            trim_empty_output: false,
+            contact_sheet: false,
+            sidecar_thumbnails: None,
+            gallery: false,
+            video_poster: false,
+            after_each: None,
+            after_each_fatal: false,
+            on_complete: None,
+            compare: None,
+            compare_tolerance: 0,
+            checksum: false,
+            csv: None,
+            save_status: None,
+            verify: None,
+            skip_hashes: HashSet::new(),
+            rules: BTreeMap::new(),
+            hardlink_copies: false,
+            reflink: false,
+            preserve_perms: false,
+            fsync: false,
+            preserve_xattrs: false,
+            preserve_times: false,
+
+            io_jobs: NonZeroUsize::new(DEFAULT_IO_JOBS).unwrap(),
+            max_active_dirs: None,
+            stream: false,
+            decode_jobs: *CORES,
+            autotune: false,
+            autotune_only: false,
+            runtime_threads: None,
+            animated: false,
+            decode_scale: None,
+            thumbnail_above: None,
+            resize: None,
+            max_dimension: None,
+            fit: Fit::Contain,
+            pad_color: [0; 3],
+            decoder: None,
+            isolate_decodes: false,
+            exif_orient: false,
+            strict: false,
+            max_memory: None,
+            memory_high_water: None,
+            memory_low_water: None,
+            batch_size: NonZeroUsize::MIN,
+
+            formats: vec![Format::Png],
+            format_subdirs: false,
+            jpeg_encoder: JpegEncoder::Baseline,
+            jpeg_quality: 85,
+            progressive: false,
+            webp_quality: 85,
+            webp_lossless: false,
+            compression: png::Compression::Best,
+            png_filter: None,
+            adaptive_png: false,
+            color_chunk: None,
+            quantize: None,
+            bit_reduce: None,
+            embed_source_name: false,
+            strip_metadata: false,
+            scrub_gps: false,
+            png_max_size: None,
+
+            extension_case: ExtensionCase::Preserve,
+            suffix: None,
+            name_template: None,
+            global_index: false,
+            normalize_names: None,
+            lowercase_names: false,
+            sort_by_type: false,
+            bucket_by_month: false,
+            group_bursts: false,
+        }
+    }
+}
+
+/// The `--help` section on placeholders for `--name-template`.
+const TEMPLATE_HELP: &str = "\
+\nname templates:
+  {stem}      source file name without its extension
+  {dir}       name of the directory the file is in
+  {index}     position of the file in its directory, in name order, counting from 1,
+              or in the whole run with --global-index; {index:04} pads it with zeros
+              to 4 digits
+";
+
+const TIME_HELP: &str = "\
+\ntimes:
+  2024-01-31  the start of that day, UTC
+  2024-01-31 18:30[:00]
+              that time of day, UTC, where a T can stand in for the space
+  12h, 30d, 2w
+              that many hours, days or weeks ago
+";
+
+const KEYS_HELP: &str = "\
+\nkeys:
+  p           pause or resume starting new files
+  j, k        scroll the directory list
+  e           show only directories with errors, or all of them again
+  ctrl-c      quit
+";
+
+/// Full `--help` text.
+pub fn help() -> String {
+    let mut help = format!(
+        "{}\n\n\
+        Converts the HEIF images in each directory under INPUT_DIR to PNG, mirroring them into\n\
+        OUTPUT_DIR along with copies of all other files. An INPUT_DIR can also be a .zip, .tar,\n\
+        .tar.gz or .tgz archive, whose files are converted and extracted straight into\n\
+        OUTPUT_DIR. HEIF images are .heic, .heics, .heif and .hif files in any case, and those\n\
+        holding several images, like .HEICS sequences, give their primary image unless\n\
+        --animated is given.\n\n\
+        {}\npresets:\n",
+        USAGE, OPTIONS_HELP
+    );
+    for (name, description) in PRESETS {
+        help += &format!("  {:<10}  {}\n", name, description);
+    }
+    help += TEMPLATE_HELP;
+    help += TIME_HELP;
+    help += KEYS_HELP;
+    help
+}
+
+impl Options {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args: Vec<String> = args.into_iter().collect();
+
+        let config = match find_flag(&args, "--config") {
+            Some(path) => Some(Utf8PathBuf::from(path)),
+            None => CONFIG_FILES
+                .iter()
+                .map(Utf8PathBuf::from)
+                .find(|path| path.is_file()),
+        };
+        if let Some(config) = config {
+            // flags from the config file come first so that the command line overrides them
+            args.splice(0..0, config_args(&config)?);
+        }
+
+        let mut opts = Options::default();
+        if let Some(preset) = find_flag(&args, "--preset") {
+            opts.apply_preset(preset)?;
+        }
+
+        let mut positional = Vec::new();
+        // replacing the default once any are given
+        let mut formats = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // accept both `--flag value` and `--flag=value`
+            let (arg, mut inline) = match arg.split_once('=') {
+                Some((flag, value)) if flag.starts_with("--") => {
+                    (flag.to_string(), Some(value.to_string()))
+                }
+                _ => (arg, None),
+            };
+            let mut value = |flag: &str| {
+                inline
+                    .take()
+                    .or_else(|| args.next())
+                    .with_context(|| format!("missing value for '{}'", flag))
+            };
+
+            match arg.as_str() {
+                "--config" | "--preset" => {
+                    // already applied before any other flags
+                    value(&arg)?;
+                }
+                "--depth" => opts.depth = parse(&arg, value(&arg)?)?,
+                "--map" => opts.dir_map = read_dir_map(value(&arg)?.as_ref())?,
+                "--follow-symlinks" => opts.follow_symlinks = true,
+                "--keep-misc" => opts.keep_misc = true,
+                "--include-hidden" => opts.include_hidden = true,
+                "--collapse-single" => opts.collapse_single = true,
+                "--relative-to" => opts.relative_to = Some(value(&arg)?.into()),
+                "--per-input-subdir" => opts.per_input_subdir = true,
+                "--pick" => opts.pick = true,
+                "-h" | "--help" => opts.help = true,
+                "-V" | "--version" => opts.version = true,
+                "--doctor" => opts.doctor = true,
+                "--info" => opts.info = true,
+                "--print-config" => opts.print_config = true,
+                "-q" | "--quiet" => opts.quiet = true,
+                "--ui" => opts.ui = parse_ui(value(&arg)?)?,
+                "--compact" => opts.ui = Ui::Compact,
+                "--notify" => opts.notify = true,
+                "--metrics-addr" => opts.metrics_addr = Some(parse(&arg, value(&arg)?)?),
+                "--log-level" => opts.log_level = Some(value(&arg)?),
+                "--log-file" => opts.log_file = value(&arg)?.into(),
+                "--max-pixels" => opts.max_pixels = parse(&arg, value(&arg)?)?,
+                "--keep-going" => opts.keep_going = true,
+                "--max-errors" => opts.max_errors = Some(parse(&arg, value(&arg)?)?),
+                "--max-runtime" => opts.max_runtime = Some(parse_duration(&arg, value(&arg)?)?),
+                "--stop-directory-on-error" => opts.stop_directory_on_error = true,
+                "--fallback-copy" => opts.fallback_copy = true,
+                "--copy-only" => opts.copy_only = true,
+                "--images-only" => opts.images_only = true,
+                "--ignore-space" => opts.ignore_space = true,
+                "--force" => opts.force = true,
+                "-y" | "--yes" => opts.yes = true,
+                "--update" => opts.update = true,
+                "--on-conflict" => opts.on_conflict = parse_on_conflict(value(&arg)?)?,
+                "--rewrite-if-smaller" => opts.on_conflict = OnConflict::Smaller,
+                "--since" => opts.since = Some(parse_time(&arg, value(&arg)?)?),
+                "--until" => opts.until = Some(parse_time(&arg, value(&arg)?)?),
+                "--changed-only" => opts.changed_only = true,
+                "--resume" => opts.resume = true,
+                "--trim-empty-output" => opts.trim_empty_output = true,
+                "--contact-sheet" => opts.contact_sheet = true,
+                "--gallery" => opts.gallery = true,
+                "--sidecar-thumbnails" => {
+                    opts.sidecar_thumbnails = Some(parse(&arg, value(&arg)?)?)
+                }
+                "--video-poster" => opts.video_poster = true,
+                "--after-each" => opts.after_each = Some(parse_command(&arg, value(&arg)?)?),
+                "--after-each-fatal" => opts.after_each_fatal = true,
+                "--on-complete" => opts.on_complete = Some(parse_command(&arg, value(&arg)?)?),
+                "--compare" => opts.compare = Some(value(&arg)?.into()),
+                "--compare-tolerance" => opts.compare_tolerance = parse(&arg, value(&arg)?)?,
+                "--checksum" => opts.checksum = true,
+                "--csv" => opts.csv = Some(value(&arg)?.into()),
+                "--save-status" => opts.save_status = Some(value(&arg)?.into()),
+                "--verify" => opts.verify = Some(parse_verify(value(&arg)?)?),
+                "--skip-hashes" => opts.skip_hashes = read_hashes(value(&arg)?.as_ref())?,
+                "--rules" => opts.rules = read_rules(value(&arg)?.as_ref())?,
+                "--hardlink-copies" => opts.hardlink_copies = true,
+                "--reflink" => opts.reflink = true,
+                "--preserve-perms" => opts.preserve_perms = true,
+                "--fsync" => opts.fsync = true,
+                "--preserve-xattrs" => opts.preserve_xattrs = true,
+                "--preserve-times" => opts.preserve_times = true,
+                "--io-jobs" => opts.io_jobs = parse(&arg, value(&arg)?)?,
+                "--max-active-dirs" => opts.max_active_dirs = Some(parse(&arg, value(&arg)?)?),
+                "--stream" => opts.stream = true,
+                "-j" | "--jobs" | "--decode-jobs" => {
+                    opts.decode_jobs = parse_jobs(&arg, value(&arg)?)?
+                }
+                "--autotune" => opts.autotune = true,
+                "--autotune-only" => {
+                    opts.autotune = true;
+                    opts.autotune_only = true;
+                }
+                "--runtime-threads" => opts.runtime_threads = Some(parse(&arg, value(&arg)?)?),
+                "--max-memory" => opts.max_memory = Some(parse_size(&arg, value(&arg)?)?),
+                "--memory-high-water" => {
+                    opts.memory_high_water = Some(parse_size(&arg, value(&arg)?)?)
+                }
+                "--memory-low-water" => {
+                    opts.memory_low_water = Some(parse_size(&arg, value(&arg)?)?)
+                }
+                "--batch-size" => opts.batch_size = parse(&arg, value(&arg)?)?,
+                "--format" => {
+                    let format = parse_format(value(&arg)?)?;
+                    if !formats.contains(&format) {
+                        formats.push(format);
+                    }
+                }
+                "--format-subdirs" => opts.format_subdirs = true,
+                "--jpeg-encoder" => opts.jpeg_encoder = parse_jpeg_encoder(value(&arg)?)?,
+                "--jpeg-quality" => opts.jpeg_quality = parse_quality(&arg, value(&arg)?, 1)?,
+                "--webp-quality" => opts.webp_quality = parse_quality(&arg, value(&arg)?, 0)?,
+                "--progressive" => opts.progressive = true,
+                "--webp-lossless" => opts.webp_lossless = true,
+                "--compression" => opts.compression = parse_compression(value(&arg)?)?,
+                "--png-filter" => opts.png_filter = Some(parse_png_filter(value(&arg)?)?),
+                "--adaptive-png" => opts.adaptive_png = true,
+                "--color-chunk" => opts.color_chunk = parse_color_chunk(value(&arg)?)?,
+                "--quantize" => opts.quantize = Some(parse_quantize(value(&arg)?)?),
+                "--bit-reduce" => opts.bit_reduce = Some(parse_bit_reduce(value(&arg)?)?),
+                "--embed-source-name" => opts.embed_source_name = true,
+                "--strip" | "--strip-metadata" => opts.strip_metadata = true,
+                "--scrub-gps" => opts.scrub_gps = true,
+                "--png-max-size" => opts.png_max_size = Some(parse_size(&arg, value(&arg)?)?),
+                "--extension-case" => opts.extension_case = parse_extension_case(value(&arg)?)?,
+                "--animated" => opts.animated = true,
+                "--decode-scale" => opts.decode_scale = Some(parse_decode_scale(value(&arg)?)?),
+                "--thumbnail-above" => {
+                    opts.thumbnail_above = Some(parse_megapixels(&arg, value(&arg)?)?)
+                }
+                "--resize" => opts.resize = Some(parse_dimensions(value(&arg)?)?),
+                "--max-dimension" => opts.max_dimension = Some(parse(&arg, value(&arg)?)?),
+                "--fit" => opts.fit = parse_fit(value(&arg)?)?,
+                "--pad-color" => opts.pad_color = parse_color(&arg, value(&arg)?)?,
+                "--decoder" => opts.decoder = Some(value(&arg)?),
+                "--isolate-decodes" => opts.isolate_decodes = true,
+                "--exif-orient" => opts.exif_orient = true,
+                "--strict" => opts.strict = true,
+                "--suffix" => opts.suffix = Some(value(&arg)?),
+                "--name-template" => opts.name_template = Some(parse_name_template(value(&arg)?)?),
+                "--global-index" => opts.global_index = true,
+                "--lowercase-names" => opts.lowercase_names = true,
+                "--sort-by-type" => opts.sort_by_type = true,
+                "--bucket-by-month" => opts.bucket_by_month = true,
+                "--group-bursts" => opts.group_bursts = true,
+                "--normalize-names" => {
+                    opts.normalize_names = Some(parse_normalization_form(value(&arg)?)?)
+                }
+                flag if flag.starts_with('-') && flag.len() > 1 => {
+                    bail!("unknown option '{}'", flag)
+                }
+                _ => positional.push(Utf8PathBuf::from(arg.as_str())),
+            }
+
+            if inline.is_some() {
+                bail!("option '{}' does not take a value", arg);
+            }
+        }
+
+        if opts.help || opts.version {
+            return Ok(opts);
+        }
+        if !formats.is_empty() {
+            opts.formats = formats;
+        }
+
+        match positional.len() {
+            0 | 1 if opts.doctor || opts.print_config => {
+                opts.output = positional.pop().unwrap_or_default()
+            }
+            0 => bail!("missing input directory argument"),
+            _ if opts.info => {}
+            1 => bail!("missing output directory argument"),
+            _ => opts.output = positional.pop().unwrap(),
+        }
+        for input in positional {
+            opts.inputs.extend(expand_input(input)?);
+        }
+
+        // both need every file in a directory to be known before any of them is started
+        if opts.stream && opts.on_conflict == OnConflict::Ask && !opts.update && !opts.yes {
+            bail!(
+                "'--stream' can't ask before overwriting, so it needs '--on-conflict', \
+                 '--update' or '--yes'"
+            );
+        }
+        if opts.stream
+            && opts
+                .name_template
+                .as_ref()
+                .is_some_and(NameTemplate::has_index)
+        {
+            bail!("'--stream' doesn't list files in name order, which '{{index}}' needs");
+        }
+        if opts.stream && opts.autotune {
+            bail!(
+                "'--autotune' samples files before the run starts, which '--stream' doesn't list"
+            );
+        }
+        if opts.copy_only && opts.images_only {
+            bail!("'--copy-only' and '--images-only' together would leave nothing to write");
+        }
+        match (opts.memory_high_water, opts.memory_low_water) {
+            (None, Some(_)) => bail!("'--memory-low-water' needs '--memory-high-water'"),
+            (Some(high), Some(low)) if low >= high => {
+                bail!("'--memory-low-water' has to be below '--memory-high-water'")
+            }
+            (Some(high), None) => opts.memory_low_water = Some(high / 4 * 3),
+            _ => {}
+        }
+
+        Ok(opts)
+    }
+
+    /// What `--rules` says to do with `source`, going by its extension.
+    pub fn rule(&self, source: &Utf8Path) -> Option<&Rule> {
+        self.rules.get(&source.extension()?.to_lowercase())
+    }
+
+    /// The formats `source` is converted to, which are its rule's if `--rules` gives it any.
+    pub fn formats_for(&self, source: &Utf8Path) -> &[Format] {
+        match self.rule(source) {
+            Some(Rule::Convert { formats, .. }) if !formats.is_empty() => formats,
+            _ => &self.formats,
+        }
+    }
+
+    /// These options as they apply to converting `source`, with its rule's quality in place of
+    /// `--jpeg-quality` and `--webp-quality` if `--rules` gives it one.
+    pub fn for_source(&self, source: &Utf8Path) -> Cow<'_, Options> {
+        match self.rule(source) {
+            Some(&Rule::Convert {
+                quality: Some(quality),
+                ..
+            }) => Cow::Owned(Options {
+                jpeg_quality: quality,
+                webp_quality: quality,
+                ..self.clone()
+            }),
+            _ => Cow::Borrowed(self),
+        }
+    }
+
+    /// The directory that `format` outputs are mirrored into, which is the output itself unless
+    /// there's one for each format with `--format-subdirs`.
+    pub fn format_output(&self, format: Format) -> Utf8PathBuf {
+        if self.format_subdirs {
+            self.output.join(format.name())
+        } else {
+            self.output.clone()
+        }
+    }
+
+    /// Writes out the options as a JSON object keyed by flag name, with values as they'd be given
+    /// on the command line where there's a flag for them.
+    pub fn to_json(&self) -> String {
+        let string = |text: &str| json_string(text);
+        let or_null = |value: Option<String>| value.unwrap_or_else(|| "null".to_string());
+        let time = |time: Option<SystemTime>| or_null(time.map(|time| string(&format_time(time))));
+
+        let mut map: Vec<_> = self.dir_map.iter().collect();
+        map.sort();
+        let fields = [
+            ("inputs", {
+                let inputs: Vec<_> = self
+                    .inputs
+                    .iter()
+                    .map(|input| string(input.as_str()))
+                    .collect();
+                format!("[{}]", inputs.join(", "))
+            }),
+            ("output", string(self.output.as_str())),
+            ("depth", self.depth.to_string()),
+            ("map", {
+                let map: Vec<_> = map
+                    .iter()
+                    .map(|(from, to)| format!("{}: {}", string(from.as_str()), string(to.as_str())))
+                    .collect();
+                format!("{{{}}}", map.join(", "))
+            }),
+            ("collapse-single", self.collapse_single.to_string()),
+            (
+                "relative-to",
+                or_null(self.relative_to.as_ref().map(|base| string(base.as_str()))),
+            ),
+            ("per-input-subdir", self.per_input_subdir.to_string()),
+            ("pick", self.pick.to_string()),
+            ("follow-symlinks", self.follow_symlinks.to_string()),
+            ("keep-misc", self.keep_misc.to_string()),
+            ("include-hidden", self.include_hidden.to_string()),
+            ("quiet", self.quiet.to_string()),
+            (
+                "ui",
+                string(match self.ui {
+                    Ui::Tui => "tui",
+                    Ui::Compact => "compact",
+                    Ui::Bars => "bars",
+                }),
+            ),
+            ("notify", self.notify.to_string()),
+            (
+                "metrics-addr",
+                or_null(self.metrics_addr.map(|addr| string(&addr.to_string()))),
+            ),
+            ("log-level", or_null(self.log_level.as_deref().map(string))),
+            ("log-file", string(self.log_file.as_str())),
+            ("max-pixels", self.max_pixels.to_string()),
+            ("keep-going", self.keep_going.to_string()),
+            (
+                "max-errors",
+                or_null(self.max_errors.map(|max| max.to_string())),
+            ),
+            (
+                "max-runtime",
+                or_null(
+                    self.max_runtime
+                        .map(|limit| string(&format!("{}s", limit.as_secs()))),
+                ),
+            ),
+            (
+                "stop-directory-on-error",
+                self.stop_directory_on_error.to_string(),
+            ),
+            ("fallback-copy", self.fallback_copy.to_string()),
+            ("copy-only", self.copy_only.to_string()),
+            ("images-only", self.images_only.to_string()),
+            ("ignore-space", self.ignore_space.to_string()),
+            ("force", self.force.to_string()),
+            ("yes", self.yes.to_string()),
+            ("update", self.update.to_string()),
+            (
+                "on-conflict",
+                string(match self.on_conflict {
+                    OnConflict::Ask => "ask",
+                    OnConflict::Overwrite => "overwrite",
+                    OnConflict::Skip => "skip",
+                    OnConflict::Rename => "rename",
+                    OnConflict::Smaller => "smaller",
+                }),
+            ),
+            ("since", time(self.since)),
+            ("until", time(self.until)),
+            ("changed-only", self.changed_only.to_string()),
+            ("resume", self.resume.to_string()),
+            ("trim-empty-output", self.trim_empty_output.to_string()),
+            ("contact-sheet", self.contact_sheet.to_string()),
+            (
+                "sidecar-thumbnails",
+                or_null(self.sidecar_thumbnails.map(|size| size.to_string())),
+            ),
+            ("gallery", self.gallery.to_string()),
+            ("video-poster", self.video_poster.to_string()),
+            (
+                "after-each",
+                or_null(
+                    self.after_each
+                        .as_ref()
+                        .map(|words| string(&words.join(" "))),
+                ),
+            ),
+            ("after-each-fatal", self.after_each_fatal.to_string()),
+            (
+                "on-complete",
+                or_null(
+                    self.on_complete
+                        .as_ref()
+                        .map(|words| string(&words.join(" "))),
+                ),
+            ),
+            (
+                "compare",
+                or_null(self.compare.as_ref().map(|dir| string(dir.as_str()))),
+            ),
+            ("compare-tolerance", self.compare_tolerance.to_string()),
+            ("checksum", self.checksum.to_string()),
+            (
+                "csv",
+                or_null(self.csv.as_ref().map(|path| string(path.as_str()))),
+            ),
+            (
+                "save-status",
+                or_null(self.save_status.as_ref().map(|path| string(path.as_str()))),
+            ),
+            (
+                "verify",
+                or_null(self.verify.map(|verify| {
+                    string(match verify {
+                        Verify::Size => "size",
+                        Verify::Hash => "hash",
+                    })
+                })),
+            ),
+            (
+                "rules",
+                format!(
+                    "{{{}}}",
+                    self.rules
+                        .iter()
+                        .map(|(ext, rule)| format!(
+                            "{}: {}",
+                            string(ext),
+                            string(&rule.to_string())
+                        ))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            ),
+            ("skip-hashes", {
+                let mut hashes: Vec<_> = self.skip_hashes.iter().map(|hash| string(hash)).collect();
+                hashes.sort();
+                format!("[{}]", hashes.join(", "))
+            }),
+            ("hardlink-copies", self.hardlink_copies.to_string()),
+            ("reflink", self.reflink.to_string()),
+            ("preserve-perms", self.preserve_perms.to_string()),
+            ("fsync", self.fsync.to_string()),
+            ("preserve-xattrs", self.preserve_xattrs.to_string()),
+            ("preserve-times", self.preserve_times.to_string()),
+            ("io-jobs", self.io_jobs.to_string()),
+            (
+                "max-active-dirs",
+                or_null(self.max_active_dirs.map(|max| max.to_string())),
+            ),
+            ("stream", self.stream.to_string()),
+            ("decode-jobs", self.decode_jobs.to_string()),
+            ("autotune", self.autotune.to_string()),
+            ("autotune-only", self.autotune_only.to_string()),
+            (
+                "runtime-threads",
+                or_null(self.runtime_threads.map(|threads| threads.to_string())),
+            ),
+            (
+                "max-memory",
+                or_null(self.max_memory.map(|bytes| bytes.to_string())),
+            ),
+            (
+                "memory-high-water",
+                or_null(self.memory_high_water.map(|bytes| bytes.to_string())),
+            ),
+            (
+                "memory-low-water",
+                or_null(self.memory_low_water.map(|bytes| bytes.to_string())),
+            ),
+            ("batch-size", self.batch_size.to_string()),
+            ("format", {
+                let formats: Vec<_> = self
+                    .formats
+                    .iter()
+                    .map(|format| string(format.name()))
+                    .collect();
+                format!("[{}]", formats.join(", "))
+            }),
+            ("format-subdirs", self.format_subdirs.to_string()),
+            (
+                "jpeg-encoder",
+                string(match self.jpeg_encoder {
+                    JpegEncoder::Baseline => "baseline",
+                    JpegEncoder::Mozjpeg => "mozjpeg",
+                }),
+            ),
+            ("jpeg-quality", self.jpeg_quality.to_string()),
+            ("progressive", self.progressive.to_string()),
+            ("webp-quality", self.webp_quality.to_string()),
+            ("webp-lossless", self.webp_lossless.to_string()),
+            (
+                "compression",
+                string(match self.compression {
+                    png::Compression::Fast => "fast",
+                    png::Compression::Best => "best",
+                    _ => "default",
+                }),
+            ),
+            (
+                "png-filter",
+                or_null(self.png_filter.map(|filter| {
+                    string(match filter {
+                        PngFilter::Fixed(png::FilterType::NoFilter) => "none",
+                        PngFilter::Fixed(png::FilterType::Sub) => "sub",
+                        PngFilter::Fixed(png::FilterType::Up) => "up",
+                        PngFilter::Fixed(png::FilterType::Avg) => "avg",
+                        PngFilter::Fixed(png::FilterType::Paeth) => "paeth",
+                        PngFilter::Adaptive => "adaptive",
+                    })
+                })),
+            ),
+            ("adaptive-png", self.adaptive_png.to_string()),
+            (
+                "color-chunk",
+                string(match self.color_chunk {
+                    Some(ColorChunk::Srgb) => "srgb",
+                    Some(ColorChunk::Gamma) => "gamma",
+                    None => "none",
+                }),
+            ),
+            (
+                "quantize",
+                or_null(self.quantize.map(|quantize| {
+                    string(match quantize {
+                        Quantize::Auto => "auto",
+                        Quantize::Always => "always",
+                    })
+                })),
+            ),
+            (
+                "bit-reduce",
+                or_null(self.bit_reduce.map(|bits| bits.to_string())),
+            ),
+            ("embed-source-name", self.embed_source_name.to_string()),
+            ("strip-metadata", self.strip_metadata.to_string()),
+            ("scrub-gps", self.scrub_gps.to_string()),
+            (
+                "png-max-size",
+                or_null(self.png_max_size.map(|bytes| bytes.to_string())),
+            ),
+            (
+                "extension-case",
+                string(match self.extension_case {
+                    ExtensionCase::Preserve => "preserve",
+                    ExtensionCase::Lower => "lower",
+                    ExtensionCase::Upper => "upper",
+                }),
+            ),
+            ("animated", self.animated.to_string()),
+            (
+                "decode-scale",
+                or_null(
+                    self.decode_scale
+                        .map(|scale| string(&format!("1/{}", scale))),
+                ),
+            ),
+            (
+                "thumbnail-above",
+                or_null(
+                    self.thumbnail_above
+                        .map(|megapixels| megapixels.to_string()),
+                ),
+            ),
+            (
+                "resize",
+                or_null(
+                    self.resize
+                        .map(|(width, height)| string(&format!("{}x{}", width, height))),
+                ),
+            ),
+            (
+                "max-dimension",
+                or_null(self.max_dimension.map(|size| size.to_string())),
+            ),
+            (
+                "fit",
+                string(match self.fit {
+                    Fit::Contain => "contain",
+                    Fit::Cover => "cover",
+                    Fit::Stretch => "stretch",
+                }),
+            ),
+            (
+                "pad-color",
+                string(&format!(
+                    "{:02x}{:02x}{:02x}",
+                    self.pad_color[0], self.pad_color[1], self.pad_color[2]
+                )),
+            ),
+            ("decoder", or_null(self.decoder.as_deref().map(string))),
+            ("isolate-decodes", self.isolate_decodes.to_string()),
+            ("exif-orient", self.exif_orient.to_string()),
+            ("strict", self.strict.to_string()),
+            ("suffix", or_null(self.suffix.as_deref().map(string))),
+            (
+                "name-template",
+                or_null(
+                    self.name_template
+                        .as_ref()
+                        .map(|template| string(&template.to_string())),
+                ),
+            ),
+            ("global-index", self.global_index.to_string()),
+            (
+                "normalize-names",
+                or_null(self.normalize_names.map(|form| {
+                    string(match form {
+                        NormalizationForm::Nfc => "nfc",
+                        NormalizationForm::Nfd => "nfd",
+                    })
+                })),
+            ),
+            ("lowercase-names", self.lowercase_names.to_string()),
+            ("sort-by-type", self.sort_by_type.to_string()),
+            ("bucket-by-month", self.bucket_by_month.to_string()),
+            ("group-bursts", self.group_bursts.to_string()),
+        ];
+
+        let fields: Vec<_> = fields
+            .iter()
+            .map(|(key, value)| format!("  \"{}\": {}", key, value))
+            .collect();
+        format!("{{\n{}\n}}\n", fields.join(",\n"))
+    }
+
+    fn apply_preset(&mut self, name: &str) -> Result<()> {
+        let cores = *CORES;
+        match name {
+            "fast" => {
+                self.compression = png::Compression::Fast;
+                self.io_jobs = self.io_jobs.saturating_mul(NonZeroUsize::new(2).unwrap());
+                self.decode_jobs = cores.saturating_mul(NonZeroUsize::new(2).unwrap());
+            }
+            "archive" => {
+                self.formats = vec![Format::Png];
+                self.compression = png::Compression::Best;
+                self.decode_jobs = cores;
+                self.strip_metadata = false;
+                self.scrub_gps = false;
+                self.preserve_xattrs = true;
+                self.preserve_times = true;
+                self.verify = Some(Verify::Hash);
+            }
+            "web" => {
+                self.formats = vec![Format::Jpeg];
+                self.jpeg_quality = 80;
+                self.max_dimension = NonZeroU32::new(2048);
+                self.strip_metadata = true;
+            }
+            _ => bail!(
+                "unknown preset '{}', expected one of: {}",
+                name,
+                PRESETS
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+        Ok(())
+    }
+}
+
+/// Finds the value of the last occurrence of `flag`, for flags that must be handled before the
+/// rest of the command line.
+fn find_flag<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+    args.iter()
+        .enumerate()
+        .rev()
+        .find_map(|(i, arg)| match arg.strip_prefix(flag) {
+            Some("") => args.get(i + 1).map(String::as_str),
+            Some(value) => value.strip_prefix('='),
+            None => None,
+        })
+}
+
+/// Reads a config file as the equivalent command-line flags, so it goes through the same parsing
+/// as the command line itself. Keys are long option names without the dashes, e.g.
+/// `decode-jobs = 4` or `quiet = true`, and arrays repeat the flag for each element.
+fn config_args(path: &Utf8Path) -> Result<Vec<String>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file '{}'", path))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("invalid config file '{}'", path))?;
+
+    let mut args = Vec::new();
+    for (key, value) in table {
+        if key == "config" {
+            bail!("config files can't include other config files");
+        }
+
+        let flag = format!("--{}", key);
+        match value {
+            toml::Value::Boolean(true) => args.push(flag),
+            toml::Value::Boolean(false) => {}
+            toml::Value::Array(values) => {
+                for value in values {
+                    args.push(flag.clone());
+                    args.push(config_value(&key, value)?);
+                }
+            }
+            value => {
+                args.push(flag);
+                args.push(config_value(&key, value)?);
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Reads a `--skip-hashes` file of SHA-256 sums, taking the first word of each line so the
+/// `hash  path` lines of `sha256sum` and `--checksum` can be used as they are. Blank lines and
+/// comments starting with `#` are skipped.
+fn read_hashes(path: &Utf8Path) -> Result<HashSet<String>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path))?;
+
+    let mut hashes = HashSet::new();
+    for (n, line) in text.lines().enumerate() {
+        let Some(hash) = line.split_whitespace().next() else {
+            continue;
+        };
+        if hash.starts_with('#') {
+            continue;
+        }
+        if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+            bail!("{}:{}: expected a SHA-256 sum, got '{}'", path, n + 1, hash);
+        }
+        hashes.insert(hash.to_ascii_lowercase());
+    }
+    Ok(hashes)
+}
+
+/// Whether files with the extension `ext`, in lowercase, can be converted: HEIF images, and the
+/// other images libheif reads where it has a decoder for them, such as AVIF ones.
+fn decodable(ext: &str) -> bool {
+    HEIF_EXTENSIONS.contains(&ext)
+        || OTHER_DECODABLE.iter().any(|&(other, format)| {
+            other == ext && !HEIF.decoder_descriptors(1, Some(format)).is_empty()
+        })
+}
+
+/// Reads a `--rules` file, which has a table for each extension with the `action` to take on
+/// files with it and, for conversions, the `format` or formats to convert them to instead of
+/// `--format`'s and the `quality` to convert them at:
+///
+/// ```toml
+/// [heic]
+/// action = "convert"
+/// format = ["png", "webp"]
+///
+/// [avif]
+/// action = "convert"
+/// format = "jpeg"
+/// quality = 70
+///
+/// [bmp]
+/// action = "skip"
+/// ```
+fn read_rules(path: &Utf8Path) -> Result<BTreeMap<String, Rule>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("invalid rules file '{}'", path))?;
+
+    let mut rules = BTreeMap::new();
+    for (ext, value) in table {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+        let toml::Value::Table(rule) = value else {
+            bail!("{}: expected a table for '{}'", path, ext);
+        };
+        let text = |key: &str, value: &toml::Value| match value {
+            toml::Value::String(text) => Ok(text.clone()),
+            _ => Err(anyhow!(
+                "{}: expected a string for '{}' of '{}'",
+                path,
+                key,
+                ext
+            )),
+        };
+        let mut action = None;
+        let mut formats = Vec::new();
+        let mut quality = None;
+        for (key, value) in &rule {
+            match key.as_str() {
+                "action" => action = Some(text(key, value)?),
+                "format" => match value {
+                    toml::Value::Array(values) => {
+                        for value in values {
+                            formats.push(parse_format(text(key, value)?)?);
+                        }
+                    }
+                    value => formats.push(parse_format(text(key, value)?)?),
+                },
+                "quality" => match value {
+                    toml::Value::Integer(n @ 1..=100) => quality = Some(*n as u8),
+                    _ => bail!(
+                        "{}: expected a quality from 1 to 100 for '{}' of '{}'",
+                        path,
+                        key,
+                        ext
+                    ),
+                },
+                _ => bail!("{}: unknown key '{}' for '{}'", path, key, ext),
+            }
+        }
+
+        let rule = match action.as_deref() {
+            Some("convert") if !decodable(&ext) => bail!(
+                "{}: '{}' files can't be converted, as libheif can't decode them here, so their \
+                 action has to be copy or skip",
+                path,
+                ext
+            ),
+            Some("convert") => Rule::Convert { formats, quality },
+            Some("copy" | "skip") if !formats.is_empty() || quality.is_some() => {
+                bail!(
+                    "{}: only conversions take a format or quality, for '{}'",
+                    path,
+                    ext
+                )
+            }
+            Some("copy") => Rule::Copy,
+            Some("skip") => Rule::Skip,
+            Some(action) => bail!(
+                "{}: invalid action '{}' for '{}', expected convert, copy or skip",
+                path,
+                action,
+                ext
+            ),
+            None => bail!("{}: no action for '{}'", path, ext),
+        };
+        rules.insert(ext, rule);
+    }
+    Ok(rules)
+}
+
+/// Reads a `--map` file of `INPUT_SUBDIR => OUTPUT_SUBDIR` lines, skipping blank lines and
+/// comments starting with `#`.
+fn read_dir_map(path: &Utf8Path) -> Result<HashMap<Utf8PathBuf, Utf8PathBuf>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("failed to read '{}'", path))?;
+
+    let mut map = HashMap::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = |reason: &str| anyhow::anyhow!("{}:{}: {}", path, n + 1, reason);
+        let (from, to) = line
+            .split_once("=>")
+            .ok_or_else(|| invalid("expected 'INPUT_SUBDIR => OUTPUT_SUBDIR'"))?;
+        let (from, to) = (Utf8PathBuf::from(from.trim()), Utf8PathBuf::from(to.trim()));
+        if from.as_str().is_empty() || to.as_str().is_empty() {
+            return Err(invalid("expected 'INPUT_SUBDIR => OUTPUT_SUBDIR'"));
+        }
+        if to.is_absolute() || to.components().any(|c| c.as_str() == "..") {
+            return Err(invalid("output directories must stay inside OUTPUT_DIR"));
+        }
+        if map.insert(from, to).is_some() {
+            return Err(invalid("directory is mapped more than once"));
+        }
+    }
+    Ok(map)
+}
+
+fn config_value(key: &str, value: toml::Value) -> Result<String> {
+    Ok(match value {
+        toml::Value::String(value) => value,
+        toml::Value::Integer(value) => value.to_string(),
+        toml::Value::Float(value) => value.to_string(),
+        value => bail!("unsupported value for '{}' in config file: {}", key, value),
+    })
+}
+
+fn parse_compression(value: String) -> Result<png::Compression> {
+    Ok(match value.as_str() {
+        "fast" => png::Compression::Fast,
+        "default" => png::Compression::Default,
+        "best" => png::Compression::Best,
+        _ => bail!(
+            "invalid value '{}' for '--compression', expected fast, default or best",
+            value
+        ),
+    })
+}
+
+fn parse_megapixels(flag: &str, value: String) -> Result<f64> {
+    match value.parse::<f64>() {
+        Ok(megapixels) if megapixels > 0.0 && megapixels.is_finite() => Ok(megapixels),
+        _ => bail!(
+            "invalid value '{}' for '{}', expected a number of megapixels like 50 or 12.5",
+            value,
+            flag
+        ),
+    }
+}
+
+fn parse_decode_scale(value: String) -> Result<NonZeroU32> {
+    let scale = match value.as_str() {
+        "1/2" => 2,
+        "1/4" => 4,
+        "1/8" => 8,
+        _ => bail!(
+            "invalid value '{}' for '--decode-scale', expected 1/2, 1/4 or 1/8",
+            value
+        ),
+    };
+    Ok(NonZeroU32::new(scale).unwrap())
+}
+
+fn parse_dimensions(value: String) -> Result<(u32, u32)> {
+    let dimensions = value.split_once('x').and_then(|(width, height)| {
+        let width: u32 = width.parse().ok().filter(|&width| width > 0)?;
+        let height: u32 = height.parse().ok().filter(|&height| height > 0)?;
+        Some((width, height))
+    });
+    dimensions.with_context(|| {
+        format!(
+            "invalid value '{}' for '--resize', expected WIDTHxHEIGHT, e.g. 1920x1080",
+            value
+        )
+    })
+}
+
+fn parse_fit(value: String) -> Result<Fit> {
+    Ok(match value.as_str() {
+        "contain" => Fit::Contain,
+        "cover" => Fit::Cover,
+        "stretch" => Fit::Stretch,
+        _ => bail!(
+            "invalid value '{}' for '--fit', expected contain, cover or stretch",
+            value
+        ),
+    })
+}
+
+/// Parses a color written as `RRGGBB` in hex, with or without a leading `#`.
+fn parse_color(flag: &str, value: String) -> Result<[u8; 3]> {
+    let hex = value.strip_prefix('#').unwrap_or(&value);
+    if hex.len() != 6 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        bail!(
+            "invalid value '{}' for '{}', expected a color as RRGGBB in hex",
+            value,
+            flag
+        );
+    }
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+fn parse<T>(flag: &str, value: String) -> Result<T>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err| anyhow::anyhow!("invalid value '{}' for '{}': {}", value, flag, err))
+}
+
+fn parse_jobs(flag: &str, value: String) -> Result<NonZeroUsize> {
+    match value.as_str() {
+        "auto" => Ok(*CORES),
+        _ => parse(flag, value),
+    }
+}
+
+/// Parses a size in bytes, with an optional K, M, G or T suffix for powers of 1024.
+fn parse_size(flag: &str, value: String) -> Result<u64> {
+    let invalid = |reason: &dyn Display| {
+        anyhow::anyhow!("invalid value '{}' for '{}': {}", value, flag, reason)
+    };
+
+    let (number, shift) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        Some('T') => (&value[..value.len() - 1], 40),
+        _ => (value.as_str(), 0),
+    };
+    let number: u64 = number.parse().map_err(|err| invalid(&err))?;
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| invalid(&"too large"))
+}
+
+/// Expands an input argument containing glob metacharacters into the directories it matches,
+/// for patterns that were quoted to keep the shell from expanding them.
+fn expand_input(input: Utf8PathBuf) -> Result<Vec<Utf8PathBuf>> {
+    if !input.as_str().contains(['*', '?', '[']) {
+        return Ok(vec![input]);
+    }
+
+    let mut dirs = Vec::new();
+    for path in glob::glob(input.as_str())? {
+        let path = Utf8PathBuf::try_from(path?)?;
+        if path.is_dir() {
+            dirs.push(path);
+        }
+    }
+    if dirs.is_empty() {
+        bail!("no directories match '{}'", input);
+    }
+    Ok(dirs)
+}
+
+/// Parses a duration as numbers of hours, minutes and seconds such as `2h`, `90m` or `1h30m`.
+fn parse_duration(flag: &str, value: String) -> Result<Duration> {
+    let invalid = || {
+        anyhow::anyhow!(
+            "invalid value '{}' for '{}', expected a duration like 2h, 90m or 1h30m",
+            value,
+            flag
+        )
+    };
+
+    let mut secs: u64 = 0;
+    let mut rest = value.as_str();
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .ok_or_else(invalid)?;
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('h') => 60 * 60,
+            Some('m') => 60,
+            Some('s') => 1,
+            _ => return Err(invalid()),
+        };
+        secs = number
+            .checked_mul(unit)
+            .and_then(|part| secs.checked_add(part))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+    if secs == 0 {
+        return Err(invalid());
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Parses a UTC date as `YYYY-MM-DD`, optionally followed by ` HH:MM[:SS]` or `THH:MM[:SS]`,
+/// or a time ago as a number of hours, days or weeks such as `12h`, `30d` or `2w`.
+fn parse_time(flag: &str, value: String) -> Result<SystemTime> {
+    let invalid = |reason: &dyn Display| {
+        anyhow::anyhow!("invalid value '{}' for '{}': {}", value, flag, reason)
+    };
+
+    let unit = match value.chars().last() {
+        Some('h') => Some(60 * 60),
+        Some('d') => Some(24 * 60 * 60),
+        Some('w') => Some(7 * 24 * 60 * 60),
+        _ => None,
+    };
+    if let Some(unit) = unit {
+        let number: u64 = value[..value.len() - 1]
+            .parse()
+            .map_err(|err| invalid(&err))?;
+        let ago = number
+            .checked_mul(unit)
+            .ok_or_else(|| invalid(&"too large"))?;
+        return SystemTime::now()
+            .checked_sub(Duration::from_secs(ago))
+            .ok_or_else(|| invalid(&"too large"));
+    }
+
+    let expected = "expected YYYY-MM-DD, YYYY-MM-DD HH:MM[:SS] or a time ago like 30d";
+    let (date, time) = match value.split_once([' ', 'T']) {
+        Some((date, time)) => (date, Some(time)),
+        None => (value.as_str(), None),
+    };
+    let numbers = |text: &str, sep| -> Result<Vec<u64>> {
+        text.split(sep)
+            .map(|part| part.parse().map_err(|_| invalid(&expected)))
+            .collect()
+    };
+
+    let (year, month, day) = match numbers(date, '-')?[..] {
+        [year, month, day] if year >= 1970 => (year, month, day),
+        _ => return Err(invalid(&expected)),
+    };
+    let month_days = match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return Err(invalid(&"no such month")),
+    };
+    if !(1..=month_days).contains(&day) {
+        return Err(invalid(&"no such day"));
+    }
+    let (hour, minute, second) = match time.map(|time| numbers(time, ':')).transpose()? {
+        None => (0, 0, 0),
+        Some(parts) => match parts[..] {
+            [hour, minute] => (hour, minute, 0),
+            [hour, minute, second] => (hour, minute, second),
+            _ => return Err(invalid(&expected)),
+        },
+    };
+    if hour > 23 || minute > 59 || second > 59 {
+        return Err(invalid(&"no such time"));
+    }
+
+    // days since the epoch, counting years from March so that leap days come last
+    let (y, m) = if month > 2 {
+        (year, month - 3)
+    } else {
+        (year - 1, month + 9)
+    };
+    let days = y * 365 + y / 4 - y / 100 + y / 400 + (153 * m + 2) / 5 + day - 1 - 719_468;
+    let secs = days * 24 * 60 * 60 + hour * 60 * 60 + minute * 60 + second;
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Formats `time` as UTC in the form `--since` and `--until` take.
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / (24 * 60 * 60), secs % (24 * 60 * 60));
+
+    // the reverse of the day count in `parse_time`, with years starting in March
+    let days = days + 719_468;
+    let (era, day_of_era) = (days / 146_097, days % 146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let m = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * m + 2) / 5 + 1;
+    let month = if m < 10 { m + 3 } else { m - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Quotes `text` as a JSON string.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            c if c.is_control() => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_on_conflict(value: String) -> Result<OnConflict> {
+    Ok(match value.as_str() {
+        "overwrite" => OnConflict::Overwrite,
+        "skip" => OnConflict::Skip,
+        "rename" => OnConflict::Rename,
+        "smaller" => OnConflict::Smaller,
+        _ => bail!(
+            "invalid value '{}' for '--on-conflict', expected overwrite, skip, rename or smaller",
+            value
+        ),
+    })
+}
+
+fn parse_ui(value: String) -> Result<Ui> {
+    Ok(match value.as_str() {
+        "tui" => Ui::Tui,
+        "compact" => Ui::Compact,
+        "bars" => Ui::Bars,
+        _ => bail!(
+            "invalid value '{}' for '--ui', expected tui, compact or bars",
+            value
+        ),
+    })
+}
+
+fn parse_extension_case(value: String) -> Result<ExtensionCase> {
+    Ok(match value.as_str() {
+        "preserve" => ExtensionCase::Preserve,
+        "lower" => ExtensionCase::Lower,
+        "upper" => ExtensionCase::Upper,
+        _ => bail!(
+            "invalid value '{}' for '--extension-case', expected preserve, lower or upper",
+            value
+        ),
+    })
+}
+
+fn parse_normalization_form(value: String) -> Result<NormalizationForm> {
+    Ok(match value.as_str() {
+        "nfc" => NormalizationForm::Nfc,
+        "nfd" => NormalizationForm::Nfd,
+        _ => bail!(
+            "invalid value '{}' for '--normalize-names', expected nfc or nfd",
+            value
+        ),
+    })
+}
+
+fn parse_name_template(value: String) -> Result<NameTemplate> {
+    let invalid = |reason: &str| {
+        anyhow::anyhow!(
+            "invalid value '{}' for '--name-template': {}",
+            value,
+            reason
+        )
+    };
+
+    let mut parts = Vec::new();
+    let mut rest = value.as_str();
+    while let Some(start) = rest.find('{') {
+        if rest[..start].contains('}') {
+            return Err(invalid("unmatched '}'"));
+        } else if start > 0 {
+            parts.push(NamePart::Literal(rest[..start].to_string()));
+        }
+        let end = start
+            + rest[start..]
+                .find('}')
+                .ok_or_else(|| invalid("unclosed '{'"))?;
+        let (token, width) = match rest[start + 1..end].split_once(':') {
+            Some((token, width)) => (token, Some(width)),
+            None => (&rest[start + 1..end], None),
+        };
+        parts.push(match (token, width) {
+            ("stem", None) => NamePart::Stem,
+            ("dir", None) => NamePart::Dir,
+            ("index", None) => NamePart::Index(0),
+            ("index", Some(width)) => NamePart::Index(
+                width
+                    .parse()
+                    .map_err(|_| invalid("width must be a number"))?,
+            ),
+            _ => {
+                return Err(invalid(&format!(
+                    "unknown token '{{{}}}', expected {{stem}}, {{dir}} or {{index}}",
+                    &rest[start + 1..end]
+                )))
+            }
+        });
+        rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+        return Err(invalid("unmatched '}'"));
+    }
+    if !rest.is_empty() {
+        parts.push(NamePart::Literal(rest.to_string()));
+    }
+    Ok(NameTemplate(parts))
+}
+
+fn parse_command(flag: &str, value: String) -> Result<Vec<String>> {
+    let words: Vec<_> = value.split_whitespace().map(str::to_string).collect();
+    if words.is_empty() {
+        bail!(
+            "invalid value '{}' for '{}', expected a command",
+            value,
+            flag
+        );
+    }
+    Ok(words)
+}
+
+fn parse_jpeg_encoder(value: String) -> Result<JpegEncoder> {
+    Ok(match value.as_str() {
+        "baseline" => JpegEncoder::Baseline,
+        "mozjpeg" if cfg!(feature = "mozjpeg") => JpegEncoder::Mozjpeg,
+        "mozjpeg" => bail!("this build has no mozjpeg, rebuild with '--features mozjpeg' for it"),
+        _ => bail!(
+            "invalid value '{}' for '--jpeg-encoder', expected baseline or mozjpeg",
+            value
+        ),
+    })
+}
+
+fn parse_format(value: String) -> Result<Format> {
+    Ok(match value.as_str() {
+        "png" => Format::Png,
+        "jpeg" | "jpg" => Format::Jpeg,
+        "webp" => Format::Webp,
+        _ => bail!(
+            "invalid value '{}' for '--format', expected png, jpeg or webp",
+            value
+        ),
+    })
+}
+
+fn parse_verify(value: String) -> Result<Verify> {
+    Ok(match value.as_str() {
+        "size" => Verify::Size,
+        "hash" => Verify::Hash,
+        _ => bail!(
+            "invalid value '{}' for '--verify', expected size or hash",
+            value
+        ),
+    })
+}
+
+/// Parses an encoder quality from `min` to 100.
+fn parse_quality(flag: &str, value: String, min: u8) -> Result<u8> {
+    match value.parse() {
+        Ok(quality) if (min..=100).contains(&quality) => Ok(quality),
+        _ => bail!(
+            "invalid value '{}' for '{}', expected a quality from {} to 100",
+            value,
+            flag,
+            min
+        ),
+    }
+}
+
+fn parse_bit_reduce(value: String) -> Result<u8> {
+    match value.parse() {
+        Ok(bits @ 1..=7) => Ok(bits),
+        _ => bail!(
+            "invalid value '{}' for '--bit-reduce', expected a number of bits from 1 to 7",
+            value
+        ),
+    }
+}
+
+fn parse_quantize(value: String) -> Result<Quantize> {
+    Ok(match value.as_str() {
+        "auto" => Quantize::Auto,
+        "always" => Quantize::Always,
+        _ => bail!(
+            "invalid value '{}' for '--quantize', expected auto or always",
+            value
+        ),
+    })
+}
+
+fn parse_color_chunk(value: String) -> Result<Option<ColorChunk>> {
+    Ok(match value.as_str() {
+        "srgb" => Some(ColorChunk::Srgb),
+        "gamma" => Some(ColorChunk::Gamma),
+        "none" => None,
+        _ => bail!(
+            "invalid value '{}' for '--color-chunk', expected srgb, gamma or none",
+            value
+        ),
+    })
+}
+
+fn parse_png_filter(value: String) -> Result<PngFilter> {
+    Ok(match value.as_str() {
+        "none" => PngFilter::Fixed(png::FilterType::NoFilter),
+        "sub" => PngFilter::Fixed(png::FilterType::Sub),
+        "up" => PngFilter::Fixed(png::FilterType::Up),
+        "avg" => PngFilter::Fixed(png::FilterType::Avg),
+        "paeth" => PngFilter::Fixed(png::FilterType::Paeth),
+        "adaptive" => PngFilter::Adaptive,
+        _ => bail!(
+            "invalid value '{}' for '--png-filter', expected none, sub, up, avg, paeth or adaptive",
+            value
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Options {
+        Options::parse(args.iter().map(|arg| arg.to_string())).unwrap()
+    }
+
+    #[test]
+    fn archive_preset_keeps_everything() {
+        let opts = parse(&["--preset", "archive", "in", "out"]);
+        assert_eq!(opts.formats, [Format::Png]);
+        assert!(opts.preserve_times && opts.preserve_xattrs);
+        assert!(!opts.strip_metadata && !opts.scrub_gps);
+        assert_eq!(opts.verify, Some(Verify::Hash));
+    }
+
+    #[test]
+    fn web_preset_makes_small_jpegs() {
+        let opts = parse(&["--preset", "web", "in", "out"]);
+        assert_eq!(opts.formats, [Format::Jpeg]);
+        assert_eq!(opts.jpeg_quality, 80);
+        assert_eq!(opts.max_dimension, NonZeroU32::new(2048));
+        assert!(opts.strip_metadata);
+
+        // explicit flags still win
+        let opts = parse(&["--preset", "web", "in", "out", "--jpeg-quality", "60"]);
+        assert_eq!(opts.jpeg_quality, 60);
+    }
+
+    #[test]
+    fn help_is_only_a_flag_where_a_flag_can_go() {
+        assert!(parse(&["--help"]).help);
+        assert!(parse(&["in", "out", "-V"]).version);
+
+        let opts = parse(&["in", "out", "--suffix", "-h"]);
+        assert!(!opts.help);
+        assert_eq!(opts.suffix.as_deref(), Some("-h"));
+    }
+
+    /// Writes `text` to a rules file of its own and reads it back.
+    fn rules(name: &str, text: &str) -> Result<BTreeMap<String, Rule>> {
+        let path = std::env::temp_dir().join(format!(
+            "mass-heif-rules-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        let path = Utf8PathBuf::from_path_buf(path).unwrap();
+        std::fs::write(&path, text).unwrap();
+        let rules = read_rules(&path);
+        std::fs::remove_file(&path).unwrap();
+        rules
+    }
+
+    #[test]
+    fn rules_take_a_quality() {
+        let rules = rules(
+            "quality",
+            "[HEIC]\naction = \"convert\"\nformat = \"jpeg\"\nquality = 70\n",
+        )
+        .unwrap();
+        assert_eq!(
+            rules["heic"],
+            Rule::Convert {
+                formats: vec![Format::Jpeg],
+                quality: Some(70),
+            }
+        );
+
+        let opts = Options {
+            rules,
+            ..parse(&["--preset", "web", "in", "out"])
+        };
+        let ruled = opts.for_source("a/b.HEIC".into());
+        assert_eq!((ruled.jpeg_quality, ruled.webp_quality), (70, 70));
+        assert_eq!(opts.for_source("a/b.png".into()).jpeg_quality, 80);
+    }
+
+    #[test]
+    fn rules_only_convert_what_can_be_decoded() {
+        let err = rules("bmp", "[bmp]\naction = \"convert\"\n").unwrap_err();
+        assert!(err.to_string().contains("can't be converted"), "{}", err);
+        // AVIF goes by whether this libheif has an AV1 decoder
+        let avif = rules("avif", "[avif]\naction = \"convert\"\n");
+        assert_eq!(avif.is_ok(), decodable("avif"));
+    }
+
+    #[test]
+    fn rules_refuse_bad_qualities() {
+        for quality in ["0", "101", "\"high\""] {
+            let text = format!("[heic]\naction = \"convert\"\nquality = {}\n", quality);
+            assert!(rules("bad-quality", &text).is_err(), "{}", quality);
+        }
+        let text = "[txt]\naction = \"copy\"\nquality = 50\n";
+        assert!(rules("copy-quality", text).is_err());
+    }
+
+    #[test]
+    fn every_preset_applies() {
+        for (name, _) in PRESETS {
+            Options::default().apply_preset(name).unwrap();
+        }
+        assert!(Options::default().apply_preset("slow").is_err());
+    }
+}
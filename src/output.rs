@@ -0,0 +1,70 @@
+use std::io::Write;
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+
+/// Where converted images are written. Everything else a run writes, like copies, checkpoints and
+/// contact sheets, still goes to the local output directory.
+pub trait Storage: Send + Sync {
+    /// Opens `path` for writing, replacing anything already there.
+    fn create(&self, path: &Utf8Path) -> Result<Box<dyn Write + Send>>;
+}
+
+/// Files under the output directory on the local filesystem.
+pub struct LocalDisk;
+
+impl Storage for LocalDisk {
+    fn create(&self, path: &Utf8Path) -> Result<Box<dyn Write + Send>> {
+        check_writable(path)?;
+        let file =
+            std::fs::File::create(path).with_context(|| format!("failed to create '{}'", path))?;
+        Ok(Box::new(file))
+    }
+}
+
+/// Fails if `path` is already something other than a file, like a named pipe, which opening to
+/// write would hang on until something reads from it, or a device.
+pub fn check_writable(path: &Utf8Path) -> Result<()> {
+    match path
+        .metadata()
+        .ok()
+        .and_then(|meta| special_kind(meta.file_type()))
+    {
+        Some(kind) => bail!(
+            "'{}' is {}, and outputs can only be written as files",
+            path,
+            kind
+        ),
+        None => Ok(()),
+    }
+}
+
+/// Names what `file_type` is if it's a pipe, socket or device.
+pub fn special_kind(file_type: std::fs::FileType) -> Option<&'static str> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return Some("a named pipe");
+        } else if file_type.is_socket() {
+            return Some("a socket");
+        } else if file_type.is_char_device() || file_type.is_block_device() {
+            return Some("a device");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = file_type;
+    None
+}
+
+/// Picks the storage for `output` by its URL scheme, with plain paths being local.
+pub fn storage(output: &Utf8Path) -> Result<Box<dyn Storage>> {
+    match output.as_str().split_once("://") {
+        None => Ok(Box::new(LocalDisk)),
+        Some(("s3", _)) => bail!(
+            "'{}' is an S3 URL, but this build has no S3 storage backend",
+            output
+        ),
+        Some((scheme, _)) => bail!("unknown output URL scheme '{}' in '{}'", scheme, output),
+    }
+}
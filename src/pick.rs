@@ -0,0 +1,147 @@
+use std::io::{self, BufWriter, Write};
+
+use anyhow::{bail, Context, Result};
+use termion::{event::Key, input::TermRead, raw::IntoRawMode};
+
+use crate::{
+    render::{sanitize, shorten_end, terminal_width},
+    Group,
+};
+
+/// Lines of the screen that aren't directories: the keys above them and the count below.
+const CHROME: usize = 2;
+
+/// Shows the directories of `groups` as a checklist on the terminal for `--pick`, and returns
+/// which of them were picked, or `None` if the run was called off. Nothing is picked to begin
+/// with, since picking is for converting less than everything.
+pub fn run(groups: &[Group]) -> Result<Option<Vec<bool>>> {
+    if !termion::is_tty(&io::stdin()) {
+        bail!("--pick needs a terminal to pick directories on");
+    }
+    let lines: Vec<String> = groups
+        .iter()
+        .map(|group| match (group.jobs.len(), &group.listing) {
+            // not listed until the run starts
+            (_, Some(_)) => sanitize(&group.name).into_owned(),
+            (1, None) => format!("{} (1 file)", sanitize(&group.name)),
+            (files, None) => format!("{} ({} files)", sanitize(&group.name), files),
+        })
+        .collect();
+
+    let mut stdout = io::stdout()
+        .into_raw_mode()
+        .context("failed to set up the terminal for --pick")?;
+    let mut picker = Picker {
+        picked: vec![false; groups.len()],
+        cursor: 0,
+        scroll: 0,
+        rows: 0,
+        drawn: 0,
+    };
+    write!(stdout, "{}", termion::cursor::Hide)?;
+    picker.draw(&mut stdout, &lines)?;
+
+    let mut picked = None;
+    for key in io::stdin().keys() {
+        match key? {
+            Key::Up | Key::Char('k') => picker.cursor = picker.cursor.saturating_sub(1),
+            Key::Down | Key::Char('j') => {
+                picker.cursor = (picker.cursor + 1).min(lines.len().saturating_sub(1))
+            }
+            Key::Char(' ') => {
+                if let Some(picked) = picker.picked.get_mut(picker.cursor) {
+                    *picked = !*picked;
+                }
+            }
+            Key::Char('a') => {
+                let all = picker.picked.iter().all(|&picked| picked);
+                picker.picked.fill(!all);
+            }
+            // starting with nothing picked would only make an empty run
+            Key::Char('\n') if picker.picked.contains(&true) => {
+                picked = Some(picker.picked.clone());
+                break;
+            }
+            Key::Char('q') | Key::Esc | Key::Ctrl('c') => break,
+            _ => continue,
+        }
+        picker.draw(&mut stdout, &lines)?;
+    }
+
+    // the run's own output starts below the checklist
+    write!(stdout, "{}\r\n", termion::cursor::Show)?;
+    Ok(picked)
+}
+
+/// The state of the checklist.
+struct Picker {
+    picked: Vec<bool>,
+    cursor: usize,
+    /// Index of the first directory shown, when they don't all fit.
+    scroll: usize,
+    /// Directories shown at once.
+    rows: usize,
+    /// Lines drawn last time, which the next draw goes back over.
+    drawn: usize,
+}
+
+impl Picker {
+    fn draw(&mut self, out: &mut impl Write, lines: &[String]) -> Result<()> {
+        let width = terminal_width().saturating_sub(1);
+        if self.rows == 0 {
+            let height = termion::terminal_size().map_or(usize::MAX, |(_, height)| height as usize);
+            self.rows = lines.len().min(height.saturating_sub(CHROME + 1).max(1));
+        }
+        // keeps the cursor in view
+        if self.cursor < self.scroll {
+            self.scroll = self.cursor;
+        } else if self.cursor >= self.scroll + self.rows {
+            self.scroll = self.cursor + 1 - self.rows;
+        }
+
+        let buf = &mut BufWriter::new(out);
+        if self.drawn > 0 {
+            write!(buf, "{}", termion::cursor::Up(self.drawn as u16))?;
+        }
+        let header = "pick directories: space picks, a picks all, enter starts, q quits";
+        write!(
+            buf,
+            "\r{}{}\r\n",
+            termion::clear::CurrentLine,
+            shorten_end(header, width)
+        )?;
+        for (i, line) in lines.iter().enumerate().skip(self.scroll).take(self.rows) {
+            let line = format!(
+                "{} [{}] {}",
+                if i == self.cursor { '>' } else { ' ' },
+                if self.picked[i] { 'x' } else { ' ' },
+                line
+            );
+            write!(
+                buf,
+                "{}{}\r\n",
+                termion::clear::CurrentLine,
+                shorten_end(&line, width)
+            )?;
+        }
+        let count = self.picked.iter().filter(|&&picked| picked).count();
+        let shown = if self.rows < lines.len() {
+            format!(", {}-{} shown", self.scroll + 1, self.scroll + self.rows)
+        } else {
+            String::new()
+        };
+        write!(
+            buf,
+            "{}{} of {} picked{}",
+            termion::clear::CurrentLine,
+            count,
+            lines.len(),
+            shown
+        )?;
+        // back to the start of the last line, so the totals are drawn over on the next go
+        write!(buf, "\r")?;
+        self.drawn = self.rows + 1;
+        buf.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+use sysinfo::System;
+use tokio::{
+    sync::watch,
+    time::{self, Duration, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+/// How often memory use is measured for `--memory-high-water`, and checked by waiting decodes.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+
+const MB: u64 = 1_000_000;
+
+/// Measures this process's resident memory in the background, and returns a flag that's true
+/// from when it goes over `high` bytes until it's back under `low`, for decodes to wait on
+/// while others are in flight. Children from `--isolate-decodes` aren't counted.
+pub fn watch(high: u64, low: u64) -> watch::Receiver<bool> {
+    let (tx, rx) = watch::channel(false);
+    let Ok(pid) = sysinfo::get_current_pid() else {
+        warn!("can't measure memory use here, so --memory-high-water has no effect");
+        return rx;
+    };
+    tokio::spawn(async move {
+        let mut system = System::new();
+        let mut interval = time::interval(SAMPLE_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // until the run is over and nothing is waiting on it
+        while !tx.is_closed() {
+            interval.tick().await;
+            if !system.refresh_process(pid) {
+                continue;
+            }
+            let Some(used) = system.process(pid).map(|process| process.memory()) else {
+                continue;
+            };
+            let high_water = *tx.borrow();
+            if !high_water && used > high {
+                info!("using {} MB of memory, holding off on decodes", used / MB);
+                tx.send_replace(true);
+            } else if high_water && used < low {
+                info!("down to {} MB of memory, starting decodes again", used / MB);
+                tx.send_replace(false);
+            }
+        }
+    });
+    rx
+}
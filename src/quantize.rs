@@ -0,0 +1,89 @@
+use std::collections::{HashMap, HashSet};
+
+use color_quant::NeuQuant;
+
+use crate::options::Quantize;
+
+/// Most colors a palette can hold.
+const PALETTE_SIZE: usize = 256;
+
+/// With `--quantize auto`, images with more distinct colors than this are taken for photos, whose
+/// gradients would visibly band, and are left in true color.
+const AUTO_MAX_COLORS: usize = 4096;
+
+/// How many pixels NeuQuant skips between the ones it learns from, from 1 for the best palette up
+/// to 30 for the fastest.
+const SAMPLE_FACTOR: i32 = 10;
+
+/// An image as one palette index per pixel, with no padding between rows.
+pub struct Indexed {
+    /// Red, green and blue of each color, as the `PLTE` chunk holds them.
+    pub palette: Vec<u8>,
+    pub indices: Vec<u8>,
+}
+
+/// Reduces the interleaved RGB image in `data`, whose rows are `stride` bytes apart, to a
+/// palette. Images with up to 256 colors get them all exactly, while those with more are
+/// quantized, unless `mode` is `Auto` and there are so many that the image looks like a photo.
+pub fn quantize(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    stride: usize,
+    mode: Quantize,
+) -> Option<Indexed> {
+    let row_size = width as usize * 3;
+    let pixels = || {
+        data.chunks(stride)
+            .take(height as usize)
+            .flat_map(move |row| row[..row_size].chunks_exact(3))
+            .map(|pixel| [pixel[0], pixel[1], pixel[2]])
+    };
+
+    // counted only as far as it makes a difference, which photos get past quickly
+    let limit = match mode {
+        Quantize::Auto => AUTO_MAX_COLORS,
+        Quantize::Always => PALETTE_SIZE,
+    };
+    let mut colors = HashSet::new();
+    for pixel in pixels() {
+        if colors.insert(pixel) && colors.len() > limit {
+            break;
+        }
+    }
+
+    if colors.len() <= PALETTE_SIZE {
+        let mut colors: Vec<_> = colors.into_iter().collect();
+        colors.sort_unstable();
+        let index: HashMap<_, _> = colors
+            .iter()
+            .enumerate()
+            .map(|(i, color)| (*color, i as u8))
+            .collect();
+        return Some(Indexed {
+            palette: colors.concat(),
+            indices: pixels().map(|pixel| index[&pixel]).collect(),
+        });
+    }
+    if mode == Quantize::Auto && colors.len() > AUTO_MAX_COLORS {
+        return None;
+    }
+
+    // NeuQuant takes RGBA
+    let rgba: Vec<u8> = pixels().flat_map(|[r, g, b]| [r, g, b, 255]).collect();
+    let quant = NeuQuant::new(SAMPLE_FACTOR, PALETTE_SIZE, &rgba);
+    drop(rgba);
+    // looking up the closest palette entry is a search, so it's done once per color
+    let mut nearest = HashMap::new();
+    let indices = pixels()
+        .map(|[r, g, b]| {
+            *nearest
+                .entry([r, g, b])
+                .or_insert_with(|| quant.index_of(&[r, g, b, 255]) as u8)
+        })
+        .collect();
+    Some(Indexed {
+        palette: quant.color_map_rgb(),
+        indices,
+    })
+}
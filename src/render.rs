@@ -0,0 +1,694 @@
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{self, BufWriter, Write},
+};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle, TermLike};
+
+use crate::{Entry, Stats};
+
+/// A progress display driven by `event_loop`.
+pub trait Renderer {
+    /// Called once before the first event is received.
+    fn start(&mut self, _entries: &[&Entry]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after every event with the updated state of all entries.
+    fn update(&mut self, entries: &[&Entry], stats: &Stats) -> Result<()>;
+
+    /// Called on every render tick between events, returning whether the display has anything
+    /// animated that needs redrawing.
+    fn tick(&mut self) -> bool {
+        false
+    }
+
+    /// Called when a file fails to process.
+    fn error(&mut self, _file: &Utf8Path, _err: &str) -> Result<()> {
+        Ok(())
+    }
+
+    /// Moves the visible part of the directory list by `delta` lines.
+    fn scroll(&mut self, _delta: isize) {}
+
+    /// Switches between listing every directory and only those with errors.
+    fn toggle_errors_only(&mut self) {}
+
+    /// Called once after the last update, with the final state of all entries.
+    fn finish(&mut self, _entries: &[&Entry]) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// The full-screen display, redrawing one line per directory in place. Directories that don't
+/// fit in the terminal are scrolled through.
+pub struct Tui<W: Write> {
+    out: W,
+    /// Number of directory lines drawn, fixed when the display starts.
+    rows: usize,
+    /// Index of the first directory shown.
+    scroll: usize,
+    /// Number of directories that can be scrolled through, which is only those with errors
+    /// while `errors_only` is set.
+    entries: usize,
+    errors_only: bool,
+    /// Render ticks so far, which the spinners of directories in progress turn with.
+    ticks: usize,
+}
+
+/// Frames of the spinner shown by each directory in progress.
+const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+impl<W: Write> Tui<W> {
+    pub fn new(out: W) -> Self {
+        Self {
+            out,
+            rows: 0,
+            scroll: 0,
+            entries: 0,
+            errors_only: false,
+            ticks: 0,
+        }
+    }
+}
+
+impl<W: Write> Renderer for Tui<W> {
+    fn start(&mut self, entries: &[&Entry]) -> Result<()> {
+        // leave room for the totals and the line the cursor ends up on
+        let height = termion::terminal_size().map_or(usize::MAX, |(_, height)| height as usize);
+        self.entries = entries.len();
+        self.rows = entries.len().min(height.saturating_sub(2).max(1));
+
+        write!(&mut self.out, "{}", vec!["\n\r"; self.rows + 1].join(""))?;
+        Ok(())
+    }
+
+    fn tick(&mut self) -> bool {
+        self.ticks += 1;
+        true
+    }
+
+    fn update(&mut self, entries: &[&Entry], stats: &Stats) -> Result<()> {
+        let buf = &mut BufWriter::new(&mut self.out);
+        write!(buf, "{}", termion::cursor::Up(self.rows as u16 + 1))?;
+        let width = terminal_width().saturating_sub(1);
+        // hidden while paused, since nothing is happening then
+        let spinner = if stats.paused {
+            ' '
+        } else {
+            SPINNER[self.ticks % SPINNER.len()]
+        };
+
+        let shown: Vec<_> = entries
+            .iter()
+            .filter(|entry| !self.errors_only || !entry.errors.is_empty())
+            .collect();
+        self.entries = shown.len();
+        self.scroll = self.scroll.min(shown.len().saturating_sub(self.rows));
+
+        for entry in shown.iter().skip(self.scroll).take(self.rows) {
+            let color = if !entry.errors.is_empty() {
+                termion::color::Red.fg_str()
+            } else if entry.completed == entry.total {
+                termion::color::LightGreen.fg_str()
+            } else {
+                termion::color::LightBlue.fg_str()
+            };
+
+            let line = entry_line(entry, spinner, width);
+            write!(
+                buf,
+                "{}{}{}\r\n",
+                termion::clear::CurrentLine,
+                color,
+                shorten_end(&line, width)
+            )?;
+        }
+        // the same number of lines every time, for the cursor to end up in the same place
+        for _ in shown.len().saturating_sub(self.scroll).min(self.rows)..self.rows {
+            write!(buf, "{}\r\n", termion::clear::CurrentLine)?;
+        }
+
+        let line = format!(
+            "{}{}{}{}",
+            totals_line(entries, stats),
+            if self.rows < shown.len() {
+                format!(
+                    " | {}-{} of {} (j/k to scroll)",
+                    self.scroll + 1,
+                    self.scroll + self.rows,
+                    shown.len()
+                )
+            } else {
+                String::new()
+            },
+            if self.errors_only {
+                " | errors only (e for all)"
+            } else {
+                ""
+            },
+            if stats.paused {
+                " | PAUSED (p to resume)"
+            } else {
+                ""
+            }
+        );
+        write!(
+            buf,
+            "{}{}{}\r\n",
+            termion::clear::CurrentLine,
+            termion::color::Reset.fg_str(),
+            shorten_end(&line, width)
+        )?;
+
+        Ok(())
+    }
+
+    fn scroll(&mut self, delta: isize) {
+        let max = self.entries.saturating_sub(self.rows);
+        self.scroll = self.scroll.saturating_add_signed(delta).min(max);
+    }
+
+    fn toggle_errors_only(&mut self) {
+        self.errors_only = !self.errors_only;
+        self.scroll = 0;
+    }
+
+    fn finish(&mut self, entries: &[&Entry]) -> Result<()> {
+        write_errors(&mut self.out, entries)
+    }
+}
+
+/// A single line of totals redrawn with a carriage return, for terminals where moving the cursor
+/// up doesn't work reliably.
+pub struct Compact<W: Write> {
+    out: W,
+}
+
+impl<W: Write> Compact<W> {
+    pub fn new(out: W) -> Self {
+        Self { out }
+    }
+}
+
+/// Width of the progress bar in the compact display, in characters.
+const BAR_WIDTH: usize = 20;
+
+impl<W: Write> Renderer for Compact<W> {
+    fn update(&mut self, entries: &[&Entry], stats: &Stats) -> Result<()> {
+        let completed: usize = entries.iter().map(|entry| entry.completed).sum();
+        let total: usize = entries.iter().map(|entry| entry.total).sum();
+        let failed: usize = entries.iter().map(|entry| entry.errors.len()).sum();
+        let done = (completed * BAR_WIDTH)
+            .checked_div(total)
+            .unwrap_or(BAR_WIDTH);
+
+        let mut line = format!(
+            "[{}{}] {}/{} ({}%)",
+            "#".repeat(done),
+            "-".repeat(BAR_WIDTH - done),
+            completed,
+            total,
+            (completed * 100).checked_div(total).unwrap_or(100)
+        );
+        if failed > 0 {
+            line += &format!(" {} failed", failed);
+        }
+        let current = entries
+            .iter()
+            .find_map(|entry| entry.decoding.as_ref())
+            .or_else(|| entries.iter().find_map(|entry| entry.last_file.as_ref()));
+        if let Some(file) = current.and_then(|file| file.file_name()) {
+            line += &format!(" current: {}", sanitize(file));
+        }
+        if stats.paused {
+            line += " PAUSED (p to resume)";
+        }
+
+        // a line that wraps can't be redrawn with a carriage return
+        let width = terminal_width();
+        let line: String = line.chars().take(width.saturating_sub(1)).collect();
+        write!(self.out, "\r{:<1$}", line, width.saturating_sub(1))?;
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn error(&mut self, file: &Utf8Path, err: &str) -> Result<()> {
+        let width = terminal_width();
+        write!(
+            self.out,
+            "\r{:<1$}\r\n",
+            format!("{}: {}", sanitize(file.as_str()), sanitize(err)),
+            width.saturating_sub(1)
+        )?;
+        Ok(())
+    }
+
+    fn finish(&mut self, entries: &[&Entry]) -> Result<()> {
+        write!(self.out, "\r\n")?;
+        write_errors(&mut self.out, entries)
+    }
+}
+
+/// A line for each directory as it finishes and for each error as it happens, without any
+/// escape codes, for when stdout isn't a terminal.
+#[derive(Default)]
+pub struct Plain {
+    /// Which entries have had their line printed.
+    reported: Vec<bool>,
+}
+
+impl Renderer for Plain {
+    fn start(&mut self, entries: &[&Entry]) -> Result<()> {
+        self.reported = vec![false; entries.len()];
+        Ok(())
+    }
+
+    fn update(&mut self, entries: &[&Entry], _stats: &Stats) -> Result<()> {
+        let mut out = io::stdout().lock();
+        for (entry, reported) in entries.iter().zip(&mut self.reported) {
+            if *reported || !entry.finished() {
+                continue;
+            }
+            *reported = true;
+
+            let mut line = format!(
+                "{}: {} converted, {} copied",
+                sanitize(&entry.name),
+                entry.converted,
+                entry.copied
+            );
+            if !entry.errors.is_empty() {
+                line += &format!(", {} failed", entry.errors.len());
+            }
+            if entry.skipped > 0 {
+                line += &format!(", {} skipped", entry.skipped);
+            }
+            writeln!(out, "{}", line)?;
+        }
+        Ok(())
+    }
+
+    fn error(&mut self, file: &Utf8Path, err: &str) -> Result<()> {
+        println!("{}: {}", sanitize(file.as_str()), sanitize(err));
+        Ok(())
+    }
+
+    // errors were already printed as they happened
+    fn finish(&mut self, entries: &[&Entry]) -> Result<()> {
+        for entry in entries {
+            for (file, difference) in &entry.differences {
+                println!("{}: {}", sanitize(file.as_str()), sanitize(difference));
+            }
+        }
+        if let Some(skips) = skip_summary(entries) {
+            println!("{}", skips);
+        }
+        Ok(())
+    }
+}
+
+/// A progress bar per directory and one for the totals, drawn with indicatif.
+pub struct Bars {
+    multi: MultiProgress,
+    bars: Vec<ProgressBar>,
+    total: ProgressBar,
+}
+
+impl Default for Bars {
+    fn default() -> Self {
+        Self {
+            multi: MultiProgress::with_draw_target(ProgressDrawTarget::term_like(Box::new(
+                RawTerm,
+            ))),
+            bars: Vec::new(),
+            total: ProgressBar::hidden(),
+        }
+    }
+}
+
+impl Renderer for Bars {
+    fn start(&mut self, entries: &[&Entry]) -> Result<()> {
+        let style = ProgressStyle::with_template("{prefix} [{bar:30}] {pos}/{len} {msg}")?
+            .progress_chars("#>-");
+        // names are padded so the bars line up
+        let width = entries
+            .iter()
+            .map(|entry| sanitize(&entry.name).chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let bar = |name: &str, total: usize| {
+            let bar = self.multi.add(ProgressBar::new(total as u64));
+            bar.set_style(style.clone());
+            bar.set_prefix(format!("{:<1$}", sanitize(name), width));
+            bar
+        };
+        self.bars = entries
+            .iter()
+            .map(|entry| bar(&entry.name, entry.total))
+            .collect();
+        self.total = bar("total", entries.iter().map(|entry| entry.total).sum());
+        Ok(())
+    }
+
+    fn update(&mut self, entries: &[&Entry], stats: &Stats) -> Result<()> {
+        for (bar, entry) in self.bars.iter().zip(entries) {
+            let mut message = format!("{} converted, {} copied", entry.converted, entry.copied);
+            match entry.errors.len() {
+                0 => {}
+                1 => message += ", 1 error",
+                errors => message += &format!(", {} errors", errors),
+            }
+            if entry.skipped > 0 {
+                message += &format!(", {} skipped", entry.skipped);
+            }
+            bar.set_message(message);
+            bar.set_position(entry.completed as u64);
+        }
+
+        let elapsed = stats.started.elapsed().as_secs_f64().max(0.001);
+        self.total.set_message(format!(
+            "{:.1} files/s, {:.1} MB/s{}",
+            stats.completed as f64 / elapsed,
+            stats.bytes as f64 / 1e6 / elapsed,
+            if stats.paused {
+                " | PAUSED (p to resume)"
+            } else {
+                ""
+            }
+        ));
+        self.total
+            .set_position(entries.iter().map(|entry| entry.completed as u64).sum());
+        Ok(())
+    }
+
+    fn error(&mut self, file: &Utf8Path, err: &str) -> Result<()> {
+        self.multi
+            .println(format!("{}: {}", sanitize(file.as_str()), sanitize(err)))?;
+        Ok(())
+    }
+
+    fn finish(&mut self, entries: &[&Entry]) -> Result<()> {
+        write_errors(&mut io::stdout(), entries)
+    }
+}
+
+/// Standard output for indicatif to draw on, ending lines with `\r\n` as the terminal is in raw
+/// mode.
+#[derive(Debug)]
+struct RawTerm;
+
+impl RawTerm {
+    fn write(&self, text: &str) -> io::Result<()> {
+        io::stdout().write_all(text.as_bytes())
+    }
+}
+
+impl TermLike for RawTerm {
+    fn width(&self) -> u16 {
+        terminal_width() as u16
+    }
+
+    // a move of 0 would still move by one
+    fn move_cursor_up(&self, n: usize) -> io::Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&termion::cursor::Up(n as u16).to_string()),
+        }
+    }
+
+    fn move_cursor_down(&self, n: usize) -> io::Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&termion::cursor::Down(n as u16).to_string()),
+        }
+    }
+
+    fn move_cursor_right(&self, n: usize) -> io::Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&termion::cursor::Right(n as u16).to_string()),
+        }
+    }
+
+    fn move_cursor_left(&self, n: usize) -> io::Result<()> {
+        match n {
+            0 => Ok(()),
+            n => self.write(&termion::cursor::Left(n as u16).to_string()),
+        }
+    }
+
+    fn write_line(&self, line: &str) -> io::Result<()> {
+        self.write(&format!("{}\r\n", line))
+    }
+
+    fn write_str(&self, text: &str) -> io::Result<()> {
+        self.write(text)
+    }
+
+    fn clear_line(&self) -> io::Result<()> {
+        self.write(&format!("\r{}", termion::clear::CurrentLine))
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+/// The display's line for `entry`, without its color, where `width` is the room the path of the
+/// file in progress has to fit into.
+fn entry_line(entry: &Entry, spinner: char, width: usize) -> String {
+    let mut line = format!(
+        "{} {} | {:04}/{:04} | {} converted, {} copied{}{} ",
+        if entry.finished() { ' ' } else { spinner },
+        sanitize(&entry.name),
+        entry.completed,
+        entry.total,
+        entry.converted,
+        entry.copied,
+        match entry.errors.len() {
+            0 => String::new(),
+            1 => ", 1 error".to_string(),
+            errors => format!(", {} errors", errors),
+        },
+        if entry.skipped > 0 {
+            format!(", {} skipped", entry.skipped)
+        } else {
+            String::new()
+        },
+    );
+
+    // what's happening now takes precedence over what finished last
+    let (before, file, after) = match (&entry.last_file, entry.copying, &entry.decoding) {
+        (Some(file), Some((copied, size)), _) => (
+            " [",
+            Some(file),
+            format!(" {}%]", copied * 100 / size.max(1)),
+        ),
+        (_, _, Some(file)) => match entry.encoding {
+            Some((encoded, total)) => (
+                " [converting ",
+                Some(file),
+                format!("... {}%]", encoded * 100 / total.max(1)),
+            ),
+            None => (" [converting ", Some(file), "...]".to_string()),
+        },
+        (Some(file), None, None) => (" [", Some(file), "]".to_string()),
+        (None, _, None) => ("", None, String::new()),
+    };
+    // a line that wraps would throw off the cursor movement, so the path gives way
+    // first, keeping its end where the file name is
+    if let Some(file) = file {
+        let room = width.saturating_sub(line.chars().count() + before.len() + after.len());
+        line += before;
+        line += &shorten_start(&sanitize(file.as_str()), room);
+        line += &after;
+    }
+    if entry.finished() {
+        line += "done";
+    }
+    if let Some((_, err)) = entry.errors.last() {
+        line += " ";
+        line += &sanitize(err);
+    }
+    line
+}
+
+/// The start of the display's totals line, before anything about the display itself.
+fn totals_line(entries: &[&Entry], stats: &Stats) -> String {
+    // counts include files resumed from an earlier run, while the rates are for this run only
+    let completed: usize = entries.iter().map(|entry| entry.completed).sum();
+    let total: usize = entries.iter().map(|entry| entry.total).sum();
+    let converted: usize = entries.iter().map(|entry| entry.converted).sum();
+    let copied: usize = entries.iter().map(|entry| entry.copied).sum();
+    let elapsed = stats.started.elapsed().as_secs_f64().max(0.001);
+    format!(
+        "total | {:04}/{:04} | {} converted, {} copied | {:.1} files/s, {:.1} MB/s",
+        completed,
+        total,
+        converted,
+        copied,
+        stats.completed as f64 / elapsed,
+        stats.bytes as f64 / 1e6 / elapsed,
+    )
+}
+
+/// Writes the display as it stands at the end of a run to `path` for `--save-status`, with a
+/// line for every directory rather than those that fit, and no colors.
+pub fn save_status(path: &Utf8Path, entries: &[&Entry], stats: &Stats) -> Result<()> {
+    let mut text = String::new();
+    for entry in entries {
+        text += entry_line(entry, ' ', usize::MAX).trim_end();
+        text += "\n";
+    }
+    text += &totals_line(entries, stats);
+    text += "\n";
+    if let Some(skips) = skip_summary(entries) {
+        text += &skips;
+        text += "\n";
+    }
+    std::fs::write(path, text).with_context(|| format!("failed to write '{}'", path))?;
+    Ok(())
+}
+
+/// Lists every file that failed, grouped by directory, since the display only shows the latest
+/// error for each, followed by those that differ from `--compare` and how many were skipped for
+/// each reason. Lines end in `\r\n` as the terminal is still in raw mode.
+fn write_errors(out: &mut impl Write, entries: &[&Entry]) -> Result<()> {
+    write_files(out, entries, "failed", |entry| &entry.errors)?;
+    write_files(out, entries, "differ from the reference", |entry| {
+        &entry.differences
+    })?;
+    if let Some(skips) = skip_summary(entries) {
+        write!(out, "\r\n{}\r\n", skips)?;
+    }
+    Ok(())
+}
+
+/// Sums up the files left alone across all entries, such as "3 skipped (already exists), 1
+/// skipped (known hash)".
+fn skip_summary(entries: &[&Entry]) -> Option<String> {
+    let mut skips = BTreeMap::new();
+    for (reason, count) in entries.iter().flat_map(|entry| &entry.skips) {
+        *skips.entry(*reason).or_insert(0) += count;
+    }
+    let skips: Vec<_> = skips
+        .into_iter()
+        .map(|(reason, count)| format!("{} skipped ({})", count, reason.reason()))
+        .collect();
+    (!skips.is_empty()).then(|| skips.join(", "))
+}
+
+fn write_files(
+    out: &mut impl Write,
+    entries: &[&Entry],
+    what: &str,
+    files: impl Fn(&Entry) -> &[(Utf8PathBuf, String)],
+) -> Result<()> {
+    let listed: Vec<_> = entries
+        .iter()
+        .filter(|entry| !files(entry).is_empty())
+        .collect();
+    if listed.is_empty() {
+        return Ok(());
+    }
+
+    let total: usize = listed.iter().map(|entry| files(entry).len()).sum();
+    write!(
+        out,
+        "\r\n{}{} files {}:{}\r\n",
+        termion::color::Red.fg_str(),
+        total,
+        what,
+        termion::color::Reset.fg_str()
+    )?;
+    for entry in listed {
+        write!(
+            out,
+            "  {} ({})\r\n",
+            sanitize(&entry.name),
+            files(entry).len()
+        )?;
+        for (file, reason) in files(entry) {
+            let name = file.file_name().unwrap_or(file.as_str());
+            write!(out, "    {}: {}\r\n", sanitize(name), sanitize(reason))?;
+        }
+    }
+    Ok(())
+}
+
+/// Escapes control characters the way Rust's debug formatting does, so that a newline or an
+/// escape sequence in a file name or error can't break the layout or be run by the terminal.
+pub fn sanitize(text: &str) -> Cow<'_, str> {
+    if !text.chars().any(char::is_control) {
+        return Cow::Borrowed(text);
+    }
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c.is_control() {
+            escaped.extend(c.escape_debug());
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+/// Cuts `text` down to `width` characters by replacing its start with `...`.
+fn shorten_start(text: &str, width: usize) -> Cow<'_, str> {
+    let len = text.chars().count();
+    if len <= width {
+        return Cow::Borrowed(text);
+    }
+    let kept = text
+        .chars()
+        .skip(len - width.saturating_sub(ELLIPSIS.len()));
+    Cow::Owned(ELLIPSIS.chars().take(width).chain(kept).collect())
+}
+
+/// Cuts `text` down to `width` characters by replacing its end with `...`.
+pub fn shorten_end(text: &str, width: usize) -> Cow<'_, str> {
+    if text.chars().count() <= width {
+        return Cow::Borrowed(text);
+    }
+    let kept = text.chars().take(width.saturating_sub(ELLIPSIS.len()));
+    Cow::Owned(kept.chain(ELLIPSIS.chars()).take(width).collect())
+}
+
+const ELLIPSIS: &str = "...";
+
+/// Columns in the terminal, guessing where it doesn't say, as some report a size of zero.
+pub fn terminal_width() -> usize {
+    match termion::terminal_size() {
+        Ok((width, _)) if width > 0 => width as usize,
+        _ => 80,
+    }
+}
+
+/// Draws nothing, passing errors through to stderr.
+pub struct Quiet;
+
+impl Renderer for Quiet {
+    fn update(&mut self, _entries: &[&Entry], _stats: &Stats) -> Result<()> {
+        Ok(())
+    }
+
+    fn error(&mut self, file: &Utf8Path, err: &str) -> Result<()> {
+        eprintln!("{}: {}", sanitize(file.as_str()), sanitize(err));
+        Ok(())
+    }
+
+    // errors were already printed as they happened
+    fn finish(&mut self, entries: &[&Entry]) -> Result<()> {
+        for entry in entries {
+            for (file, difference) in &entry.differences {
+                eprintln!("{}: {}", sanitize(file.as_str()), sanitize(difference));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,277 @@
+use libheif_rs::HeifContext;
+use mass_heif_convert::{
+    heif_bytes_to_png, heif_to_png,
+    options::{Options, Quantize},
+};
+use sha2::{Digest, Sha256};
+
+/// A PNG written by the converter, decoded back into its pixels.
+struct Decoded {
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    depth: png::BitDepth,
+    pixels: Vec<u8>,
+}
+
+impl Decoded {
+    fn pixel(&self, x: u32, y: u32) -> &[u8] {
+        let i = (y * self.width + x) as usize * 3;
+        &self.pixels[i..i + 3]
+    }
+}
+
+fn fixture(name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name);
+    std::fs::read(&path).unwrap()
+}
+
+fn encode(fixture_name: &str, opts: &Options) -> Vec<u8> {
+    let mut png = Vec::new();
+    heif_bytes_to_png(&fixture(fixture_name), &mut png, opts).unwrap();
+    png
+}
+
+fn convert(fixture: &str, opts: &Options) -> Decoded {
+    let png = encode(fixture, opts);
+    let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut pixels).unwrap();
+    pixels.truncate(frame.buffer_size());
+    Decoded {
+        width: frame.width,
+        height: frame.height,
+        color: frame.color_type,
+        depth: frame.bit_depth,
+        pixels,
+    }
+}
+
+#[test]
+fn converts_to_8_bit_rgb() {
+    let image = convert("gradient.heic", &Options::default());
+    assert_eq!((image.width, image.height), (64, 48));
+    assert_eq!(image.color, png::ColorType::Rgb);
+    assert_eq!(image.depth, png::BitDepth::Eight);
+    assert_eq!(image.pixels.len(), 64 * 48 * 3);
+}
+
+/// HEVC decoding is bit-exact, but a libheif release that changes how it converts YCbCr to RGB
+/// will change this too.
+#[test]
+fn decodes_known_pixels() {
+    let image = convert("gradient.heic", &Options::default());
+    assert_eq!(
+        format!("{:x}", Sha256::digest(&image.pixels)),
+        "6e4bd21abdc9a9aa5a4807f8f1efa8302f7109de2d69688f96d94f4fd9f06236"
+    );
+}
+
+#[test]
+fn trims_stride_padding_from_odd_widths() {
+    // the fixture is a gradient from black to red left to right, so rows that came out shifted
+    // by leftover padding would start or end on the wrong side of it
+    let image = convert("odd.heic", &Options::default());
+    assert_eq!((image.width, image.height), (33, 17));
+    assert_eq!(image.pixels.len(), 33 * 17 * 3);
+    for y in 0..image.height {
+        assert!(image.pixel(0, y)[0] < 32, "row {} starts too red", y);
+        assert!(image.pixel(32, y)[0] > 208, "row {} ends too dark", y);
+    }
+}
+
+#[test]
+fn keeps_alpha() {
+    let image = convert("alpha.heic", &Options::default());
+    assert_eq!((image.width, image.height), (16, 16));
+    assert_eq!(image.color, png::ColorType::Rgba);
+    assert_eq!(image.pixels.len(), 16 * 16 * 4);
+}
+
+/// The contents of the first chunk of `kind` in `png`.
+fn chunk<'a>(png: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut at = 8;
+    while at + 8 <= png.len() {
+        let len = u32::from_be_bytes(png[at..at + 4].try_into().unwrap()) as usize;
+        if &png[at + 4..at + 8] == kind {
+            return Some(&png[at + 8..at + 8 + len]);
+        }
+        // the length, type and CRC around the data
+        at += len + 12;
+    }
+    None
+}
+
+/// The fixture has an ICC profile and an EXIF block whose Orientation is 6, without any of the
+/// container's own transforms.
+const PROFILED: &str = "profile.heic";
+
+#[test]
+fn keeps_icc_profile() {
+    let data = fixture(PROFILED);
+    let heif = HeifContext::read_from_bytes(&data).unwrap();
+    let expected = heif
+        .primary_image_handle()
+        .unwrap()
+        .color_profile_raw()
+        .unwrap()
+        .data;
+
+    let png = encode(PROFILED, &Options::default());
+    let reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    assert_eq!(reader.info().icc_profile.as_deref(), Some(&expected[..]));
+    // the profile says what the colors are instead
+    assert!(chunk(&png, b"sRGB").is_none());
+}
+
+#[test]
+fn keeps_icc_profile_with_a_palette() {
+    let opts = Options {
+        quantize: Some(Quantize::Always),
+        ..Options::default()
+    };
+    let png = encode(PROFILED, &opts);
+    let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+    assert!(reader.info().icc_profile.is_some());
+    assert!(reader.info().palette.is_some());
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+}
+
+#[test]
+fn converts_files_like_their_bytes() {
+    let path = format!("{}/tests/fixtures/{}", env!("CARGO_MANIFEST_DIR"), PROFILED);
+    let opts = Options {
+        exif_orient: true,
+        ..Options::default()
+    };
+    let mut png = Vec::new();
+    heif_to_png(path.as_str().into(), None, &mut png, &|_, _| {}, &opts).unwrap();
+    assert_eq!(png, encode(PROFILED, &opts));
+}
+
+#[test]
+fn keeps_exif_with_its_orientation() {
+    let png = encode(PROFILED, &Options::default());
+    let exif = chunk(&png, b"eXIf").unwrap();
+    assert!(exif.starts_with(b"MM\0\x2a"));
+    // the value of the single entry of the first IFD
+    assert_eq!(exif[18..20], [0, 6]);
+}
+
+#[test]
+fn resets_exif_orientation_once_applied() {
+    let opts = Options {
+        exif_orient: true,
+        ..Options::default()
+    };
+    let png = encode(PROFILED, &opts);
+    assert_eq!(chunk(&png, b"eXIf").unwrap()[18..20], [0, 1]);
+}
+
+#[test]
+fn strips_metadata() {
+    let opts = Options {
+        strip_metadata: true,
+        ..Options::default()
+    };
+    let png = encode(PROFILED, &opts);
+    assert!(chunk(&png, b"iCCP").is_none());
+    assert!(chunk(&png, b"eXIf").is_none());
+}
+
+#[test]
+fn leaves_out_what_the_source_lacks() {
+    let png = encode("gradient.heic", &Options::default());
+    assert!(chunk(&png, b"iCCP").is_none());
+    assert!(chunk(&png, b"eXIf").is_none());
+}
+
+/// The fixture's EXIF block is big-endian, with an Orientation of 6 and a pointer to a GPS IFD
+/// in its first IFD, and a latitude of 52° 31' 12.34" N in the GPS one.
+const LOCATED: &str = "gps.heic";
+
+/// The latitude's three rationals.
+const LATITUDE: [u8; 24] = [
+    0, 0, 0, 52, 0, 0, 0, 1, 0, 0, 0, 31, 0, 0, 0, 1, 0, 0, 4, 210, 0, 0, 0, 100,
+];
+
+#[test]
+fn keeps_gps_by_default() {
+    let png = encode(LOCATED, &Options::default());
+    let exif = chunk(&png, b"eXIf").unwrap();
+    assert!(exif.windows(24).any(|window| window == LATITUDE));
+}
+
+#[test]
+fn scrubs_gps() {
+    let opts = Options {
+        scrub_gps: true,
+        ..Options::default()
+    };
+    let png = encode(LOCATED, &opts);
+    let exif = chunk(&png, b"eXIf").unwrap();
+    assert!(!exif.windows(24).any(|window| window == LATITUDE));
+    // only the orientation is left in the first IFD, followed by no next IFD
+    assert_eq!(exif[8..10], [0, 1]);
+    assert_eq!(exif[10..12], [0x01, 0x12]);
+    assert_eq!(exif[18..20], [0, 6]);
+    assert_eq!(exif[22..26], [0, 0, 0, 0]);
+    // nor the latitude's reference, the only N in the block
+    assert!(!exif.contains(&b'N'));
+}
+
+#[test]
+fn scrubbing_without_gps_keeps_the_exif() {
+    let opts = Options {
+        scrub_gps: true,
+        ..Options::default()
+    };
+    let scrubbed = encode(PROFILED, &opts);
+    let kept = encode(PROFILED, &Options::default());
+    assert_eq!(chunk(&scrubbed, b"eXIf"), chunk(&kept, b"eXIf"));
+}
+
+#[test]
+fn shrinks_to_max_dimension() {
+    let opts = Options {
+        max_dimension: std::num::NonZeroU32::new(32),
+        ..Options::default()
+    };
+    let image = convert("gradient.heic", &opts);
+    assert_eq!((image.width, image.height), (32, 24));
+
+    // never scaled up
+    let opts = Options {
+        max_dimension: std::num::NonZeroU32::new(100),
+        ..Options::default()
+    };
+    let image = convert("gradient.heic", &opts);
+    assert_eq!((image.width, image.height), (64, 48));
+}
+
+/// Library callers can build options the command line would have refused.
+#[cfg(not(feature = "mozjpeg"))]
+#[test]
+fn fails_jpegs_for_mozjpeg_without_the_feature() {
+    let dir = std::env::temp_dir().join(format!("mass-heif-mozjpeg-{}", std::process::id()));
+    let dir = camino::Utf8PathBuf::from_path_buf(dir).unwrap();
+    std::fs::create_dir_all(&dir).unwrap();
+    let opts = Options {
+        formats: vec![mass_heif_convert::options::Format::Jpeg],
+        jpeg_encoder: mass_heif_convert::options::JpegEncoder::Mozjpeg,
+        ..Options::default()
+    };
+    let storage = mass_heif_convert::output::storage(&dir).unwrap();
+    let data = fixture("gradient.heic");
+    let result = mass_heif_convert::heif_to_formats(
+        "gradient.heic".into(),
+        Some(&data),
+        &dir.join("gradient.jpg"),
+        &*storage,
+        &|_, _| {},
+        &opts,
+    );
+    std::fs::remove_dir_all(&dir).ok();
+    assert!(format!("{:#}", result.unwrap_err()).contains("no mozjpeg"));
+}
@@ -0,0 +1,77 @@
+use std::{
+    fs,
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+/// An empty directory under the system's temporary one, for a run of the command.
+fn scratch(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("mass-heif-convert-{}-{}", name, std::process::id()));
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the command over a directory holding a copy of the same HEIF fixture under each of
+/// `names`, and returns the output directory it was mirrored to along with the fixture.
+fn run(test: &str, names: &[&str]) -> (PathBuf, Vec<u8>) {
+    let dir = scratch(test);
+    let photos = dir.join("in/photos");
+    fs::create_dir_all(&photos).unwrap();
+    let fixture = format!(
+        "{}/tests/fixtures/gradient.heic",
+        env!("CARGO_MANIFEST_DIR")
+    );
+    let fixture = fs::read(fixture).unwrap();
+    for name in names {
+        fs::write(photos.join(name), &fixture).unwrap();
+    }
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mass-heif-convert"))
+        .arg(dir.join("in"))
+        .arg(dir.join("out"))
+        .arg("--log-file")
+        .arg(dir.join("log.txt"))
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    (dir.join("out/photos"), fixture)
+}
+
+fn is_png(path: PathBuf) -> bool {
+    fs::read(path).is_ok_and(|data| data.starts_with(b"\x89PNG\r\n\x1a\n"))
+}
+
+#[test]
+fn converts_heic_in_any_case() {
+    let (out, _) = run("mixed-case", &["upper.HEIC", "lower.heic", "mixed.HeIc"]);
+    // in the case of the source's extension
+    assert!(is_png(out.join("upper.PNG")));
+    assert!(is_png(out.join("lower.png")));
+    assert!(is_png(out.join("mixed.png")));
+    assert!(!out.join("mixed.HeIc").exists());
+}
+
+#[test]
+fn converts_heif() {
+    let (out, _) = run("heif", &["plain.heif", "loud.HEIF"]);
+    assert!(is_png(out.join("plain.png")));
+    assert!(is_png(out.join("loud.PNG")));
+    assert!(!out.join("plain.heif").exists());
+}
+
+#[test]
+fn copies_other_extensions_as_they_are() {
+    // even though it's really a HEIF image, only the name is gone by, while the HEIC is there
+    // so that the directory has something to convert
+    let (out, fixture) = run("jpg", &["photo.jpg", "other.HEIC"]);
+    assert_eq!(fs::read(out.join("photo.jpg")).unwrap(), fixture);
+    assert!(!out.join("photo.png").exists());
+    assert!(is_png(out.join("other.PNG")));
+}